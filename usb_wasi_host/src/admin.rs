@@ -0,0 +1,257 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tide::{Body, Request, Response, StatusCode};
+use tokio::sync::broadcast::error::RecvError;
+use usb_wasi_host::backend::BackendDevice;
+use usb_wasi_host::bindings::component::usb::descriptors::{ConfigurationDescriptor, InterfaceDescriptor};
+use usb_wasi_host::bindings::component::usb::types::TransferType;
+use usb_wasi_host::broker::DeviceBroker;
+use usb_wasi_host::error_name;
+use usb_wasi_host::metrics::Metrics;
+use usb_wasi_host::registry::{Guest, Registry};
+
+#[derive(Clone)]
+struct AdminState {
+    broker: Arc<DeviceBroker>,
+    registry: Arc<Registry>,
+}
+
+/// Starts the HTTP management API in the background.
+///
+/// - `GET /devices`: the attached devices with their descriptors and the guest that has each one open.
+/// - `GET /guests`, `GET /guests/:id`: the guests with their open handles and claimed interfaces.
+/// - `GET /guests/:id/policy`: the policy of a guest.
+/// - `POST /guests/:id/stop`: stops a guest.
+/// - `POST /guests/:id/devices/:bus/:address/revoke`: takes a device away from a guest.
+/// - `GET /events`: a server-sent event stream of hotplug events, transfers and exited guests.
+pub fn serve(address: SocketAddr, broker: Arc<DeviceBroker>, registry: Arc<Registry>) -> Result<()> {
+    let mut app = tide::with_state(AdminState { broker, registry });
+    app.with(driftwood::DevLogger);
+
+    app.at("/devices").get(devices);
+    app.at("/guests").get(guests);
+    app.at("/guests/:id").get(guest);
+    app.at("/guests/:id/policy").get(policy);
+    app.at("/guests/:id/stop").post(stop);
+    app.at("/guests/:id/devices/:bus/:address/revoke").post(revoke);
+    app.at("/events").get(tide::sse::endpoint(stream_events));
+
+    tracing::info!("Admin API listening on http://{}", address);
+    async_std::task::spawn(async move {
+        if let Err(e) = app.listen(address).await {
+            tracing::error!("Admin API stopped: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Serves the transfer metrics and the memory of the guests and the host at `GET /metrics`, in the Prometheus text format.
+pub fn serve_metrics(address: SocketAddr, metrics: Arc<Metrics>, registry: Arc<Registry>) -> Result<()> {
+    let mut app = tide::with_state((metrics, registry));
+
+    app.at("/metrics").get(|req: Request<(Arc<Metrics>, Arc<Registry>)>| async move {
+        let (metrics, registry) = req.state();
+        let mut response = Response::new(StatusCode::Ok);
+        response.set_content_type("text/plain; version=0.0.4");
+        response.set_body(metrics.render(registry));
+        Ok(response)
+    });
+
+    tracing::info!("Metrics listening on http://{}/metrics", address);
+    async_std::task::spawn(async move {
+        if let Err(e) = app.listen(address).await {
+            tracing::error!("Metrics endpoint stopped: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+async fn stream_events(req: Request<AdminState>, sender: tide::sse::Sender) -> tide::Result<()> {
+    let mut events = req.state().registry.subscribe();
+
+    loop {
+        match events.recv().await {
+            Ok(event) => sender.send(event.name(), serde_json::to_string(&event)?, None).await?,
+            // A slow client misses events rather than holding up the host.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn devices(req: Request<AdminState>) -> tide::Result {
+    let broker = &req.state().broker;
+
+    let devices: Vec<Value> = broker
+        .devices()?
+        .iter()
+        .map(|device| {
+            let mut value = device_json(device.as_ref());
+            value["owner"] = json!(broker.owner(device.bus_number(), device.address()));
+            value
+        })
+        .collect();
+
+    json_response(&devices)
+}
+
+async fn guests(req: Request<AdminState>) -> tide::Result {
+    let guests: Vec<_> = req
+        .state()
+        .registry
+        .guests()
+        .iter()
+        .map(|guest| guest.summary())
+        .collect();
+
+    json_response(&guests)
+}
+
+async fn guest(req: Request<AdminState>) -> tide::Result {
+    json_response(&find_guest(&req)?.summary())
+}
+
+async fn policy(req: Request<AdminState>) -> tide::Result {
+    json_response(find_guest(&req)?.policy.as_ref())
+}
+
+async fn stop(req: Request<AdminState>) -> tide::Result {
+    if find_guest(&req)?.stop() {
+        Ok(Response::new(StatusCode::Accepted))
+    } else {
+        Err(tide::Error::from_str(StatusCode::Conflict, "The guest is not running"))
+    }
+}
+
+async fn revoke(req: Request<AdminState>) -> tide::Result {
+    let guest = find_guest(&req)?;
+    let bus_number = param(&req, "bus")?;
+    let address = param(&req, "address")?;
+
+    guest.revoke(bus_number, address, &req.state().broker);
+    tracing::info!(guest = guest.id, bus = bus_number, address, "Revoked the device");
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+fn find_guest(req: &Request<AdminState>) -> tide::Result<Arc<Guest>> {
+    let id = param(req, "id")?;
+
+    req.state()
+        .registry
+        .guest(id)
+        .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, format!("No guest with id {}", id)))
+}
+
+fn param<T: FromStr>(req: &Request<AdminState>, name: &str) -> tide::Result<T> {
+    req.param(name)?
+        .parse()
+        .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, format!("Invalid {}", name)))
+}
+
+fn json_response(value: &impl serde::Serialize) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok).body(Body::from_json(value)?).build())
+}
+
+fn device_json(device: &dyn BackendDevice) -> Value {
+    let descriptor = match device.device_descriptor() {
+        Ok(d) => d,
+        Err(error) => {
+            return json!({
+                "bus_number": device.bus_number(),
+                "address": device.address(),
+                "error": error_name(error),
+            })
+        }
+    };
+
+    let configurations: Vec<Value> = device
+        .configurations()
+        .unwrap_or_default()
+        .iter()
+        .map(configuration_json)
+        .collect();
+
+    json!({
+        "bus_number": device.bus_number(),
+        "address": device.address(),
+        "vendor_id": format!("{:04x}", descriptor.vendor_id),
+        "product_id": format!("{:04x}", descriptor.product_id),
+        "class": descriptor.device_class,
+        "subclass": descriptor.device_subclass,
+        "protocol": descriptor.device_protocol,
+        "usb_version": format!("{}.{}.{}", descriptor.usb_version.major, descriptor.usb_version.minor, descriptor.usb_version.subminor),
+        "device_version": format!("{}.{}.{}", descriptor.device_version.major, descriptor.device_version.minor, descriptor.device_version.subminor),
+        "max_packet_size": descriptor.max_packet_size,
+        "configurations": configurations,
+    })
+}
+
+fn configuration_json(configuration: &ConfigurationDescriptor) -> Value {
+    let interfaces: Vec<Value> = configuration
+        .interfaces
+        .iter()
+        .map(|interface| {
+            let alternate_settings: Vec<Value> = interface.alternate_settings.iter().map(alternate_setting_json).collect();
+            json!({
+                "number": interface.number,
+                "alternate_settings": alternate_settings,
+            })
+        })
+        .collect();
+
+    let interface_associations: Vec<Value> = configuration
+        .interface_associations
+        .iter()
+        .map(|association| {
+            json!({
+                "first_interface": association.first_interface,
+                "interface_count": association.interface_count,
+                "class": association.function_class,
+                "subclass": association.function_subclass,
+                "protocol": association.function_protocol,
+            })
+        })
+        .collect();
+
+    json!({
+        "number": configuration.number,
+        "max_power": configuration.max_power,
+        "interfaces": interfaces,
+        "interface_associations": interface_associations,
+    })
+}
+
+fn alternate_setting_json(setting: &InterfaceDescriptor) -> Value {
+    let endpoints: Vec<Value> = setting
+        .endpoint_descriptors
+        .iter()
+        .map(|endpoint| {
+            json!({
+                "address": endpoint.address,
+                "transfer_type": match endpoint.transfer_type {
+                    TransferType::Control => "control",
+                    TransferType::Isochronous => "isochronous",
+                    TransferType::Bulk => "bulk",
+                    TransferType::Interrupt => "interrupt",
+                },
+                "max_packet_size": endpoint.max_packet_size,
+                "interval": endpoint.interval,
+            })
+        })
+        .collect();
+
+    json!({
+        "alternate_setting": setting.alternate_setting,
+        "class": setting.class_code,
+        "subclass": setting.subclass_code,
+        "protocol": setting.protocol,
+        "endpoints": endpoints,
+    })
+}
@@ -0,0 +1,204 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::conversion::hex;
+use crate::registry::{DeviceUsage, Guest};
+
+/// The `prev` of the first entry of a log.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An append-only log of what guests did with devices, one JSON object per line.
+///
+/// Every entry contains the hash of the entry before it, and its own hash over all of its other fields,
+/// so an entry that is changed, removed or inserted breaks the chain. Removing entries from the end can only be
+/// detected by comparing the last hash with a copy kept elsewhere, which `verify` returns for that purpose.
+pub struct AuditLog {
+    state: Mutex<LogState>,
+}
+
+struct LogState {
+    file: File,
+    seq: u64,
+    last_hash: String,
+}
+
+/// Something a guest did with a device.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AuditEvent {
+    Open {
+        bus_number: u8,
+        address: u8,
+        /// The vendor and product id, as `vendor_id:product_id`.
+        device: Option<String>,
+        serial_number: Option<String>,
+        result: &'static str,
+    },
+    ClaimInterface { bus_number: u8, address: u8, interface: u8, result: &'static str },
+    Control {
+        bus_number: u8,
+        address: u8,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+        result: &'static str,
+    },
+    Reset { bus_number: u8, address: u8, result: &'static str },
+    /// The guest closed a device handle, after moving this many bytes through it.
+    Close { bus_number: u8, address: u8, bytes_in: u64, bytes_out: u64 },
+    /// The guest exited, with the bytes it moved over its whole run.
+    GuestExited { devices: Vec<DeviceUsage> },
+}
+
+/// The result of checking a log.
+#[derive(Debug)]
+pub struct Verification {
+    pub entries: u64,
+    /// The hash of the last entry, to be compared with a copy kept outside the log.
+    pub last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, continuing the chain of the entries already in it.
+    pub fn open(path: &Path) -> Result<Self> {
+        let (seq, last_hash) = match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > 0 => {
+                let last = BufReader::new(File::open(path)?)
+                    .lines()
+                    .last()
+                    .transpose()?
+                    .unwrap_or_default();
+
+                let entry: Value = serde_json::from_str(&last)
+                    .with_context(|| format!("The last entry of the audit log {} is damaged, check it with verify-audit", path.display()))?;
+
+                match (entry["seq"].as_u64(), entry["hash"].as_str()) {
+                    (Some(seq), Some(hash)) => (seq + 1, hash.to_string()),
+                    _ => bail!("The last entry of the audit log {} has no seq or hash", path.display()),
+                }
+            }
+            _ => (0, GENESIS.to_string()),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open the audit log {}", path.display()))?;
+
+        Ok(Self { state: Mutex::new(LogState { file, seq, last_hash }) })
+    }
+
+    /// Appends an entry for the guest, whose component is identified by its hash. The entry is written before the call returns.
+    pub(crate) fn record(&self, guest: &Guest, component_sha256: Option<&str>, event: &AuditEvent) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut entry = serde_json::to_value(event)?;
+        entry["seq"] = state.seq.into();
+        entry["time_ms"] = (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64).into();
+        entry["guest"] = guest.id.into();
+        entry["component"] = guest.component.display().to_string().into();
+        entry["component_sha256"] = component_sha256.into();
+        entry["prev"] = state.last_hash.clone().into();
+
+        let hash = entry_hash(&entry)?;
+        entry["hash"] = hash.clone().into();
+
+        writeln!(state.file, "{}", serde_json::to_string(&entry)?)?;
+        state.file.flush()?;
+
+        state.seq += 1;
+        state.last_hash = hash;
+        Ok(())
+    }
+}
+
+/// Checks that every entry of the log has the hash of its content and links to the entry before it.
+pub fn verify(path: &Path) -> Result<Verification> {
+    let file = File::open(path).with_context(|| format!("Could not open the audit log {}", path.display()))?;
+
+    let mut expected_seq = 0;
+    let mut last_hash = GENESIS.to_string();
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let number = index + 1;
+        let mut entry: Value = serde_json::from_str(&line?).with_context(|| format!("Line {} is not a JSON object", number))?;
+
+        let Some(Value::String(hash)) = entry.as_object_mut().and_then(|entry| entry.remove("hash")) else {
+            bail!("Line {} has no hash", number);
+        };
+
+        if entry["seq"].as_u64() != Some(expected_seq) {
+            bail!("Line {} has seq {}, expected {}: entries were removed or reordered", number, entry["seq"], expected_seq);
+        }
+
+        if entry["prev"].as_str() != Some(last_hash.as_str()) {
+            bail!("Line {} does not link to the entry before it", number);
+        }
+
+        if entry_hash(&entry)? != hash {
+            bail!("Line {} was modified: its hash does not match its content", number);
+        }
+
+        expected_seq += 1;
+        last_hash = hash;
+    }
+
+    Ok(Verification { entries: expected_seq, last_hash })
+}
+
+/// The hex SHA-256 of a file, which identifies a component in the log.
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(hex(&Sha256::digest(bytes)))
+}
+
+/// Hashes the entry without its `hash` field, serialized with sorted keys so the order of the keys in the file does not matter.
+fn entry_hash(entry: &Value) -> Result<String> {
+    let mut json = String::new();
+    canonical(entry, &mut json)?;
+    Ok(hex(&Sha256::digest(json.as_bytes())))
+}
+
+fn canonical(value: &Value, out: &mut String) -> Result<()> {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                canonical(&map[key], out)?;
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out)?;
+            }
+            out.push(']');
+        }
+        other => out.push_str(&serde_json::to_string(other)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::{DeviceHandleError, Speed};
+use crate::events::DeviceConnectionEvent;
+use crate::raw_descriptor::{self, DESCRIPTOR_STRING};
+
+mod cached;
+mod libusb;
+mod replay;
+mod simulated;
+
+pub(crate) use cached::{CachedDevice, DescriptorCache};
+pub use libusb::LibusbBackend;
+pub use replay::ReplayBackend;
+pub use simulated::{SimulatedBackend, SimulatedDevice, SimulatedResponse, Transfer};
+
+/// A source of USB devices. The host only talks to devices through this trait,
+/// so it can run on top of libusb, a simulated bus or a recorded session.
+pub trait UsbBackend: Send + Sync {
+    /// The devices that are currently attached.
+    fn devices(&self) -> Result<Vec<Box<dyn BackendDevice>>>;
+
+    /// Starts sending connection events to `sender`.
+    /// Devices that are already attached are reported as connected.
+    /// No more events are sent once the returned registration is dropped.
+    fn watch(&self, sender: mpsc::UnboundedSender<DeviceConnectionEvent>) -> Result<HotplugRegistration>;
+}
+
+/// A device on the bus, which may or may not be opened.
+pub trait BackendDevice: Send + Sync + Debug {
+    fn bus_number(&self) -> u8;
+
+    fn address(&self) -> u8;
+
+    /// The chain of hub ports between the root hub and the device.
+    fn port_numbers(&self) -> Result<Vec<u8>, DeviceHandleError>;
+
+    /// The address of the hub the device is attached to, if the backend knows it.
+    fn parent_address(&self) -> Option<u8> {
+        None
+    }
+
+    /// The negotiated speed, if the backend knows it.
+    fn speed(&self) -> Speed {
+        Speed::Unknown
+    }
+
+    fn device_descriptor(&self) -> Result<DeviceDescriptor, DeviceHandleError>;
+
+    fn configurations(&self) -> Result<Vec<ConfigurationDescriptor>, DeviceHandleError>;
+
+    /// Reads a descriptor with a standard GET_DESCRIPTOR request, which may require opening the device.
+    /// `language` is the language id of a string descriptor, and 0 for other descriptors.
+    /// Configuration and BOS descriptors are read together with the descriptors that follow them, up to their total length.
+    fn read_descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Result<Vec<u8>, DeviceHandleError>;
+
+    /// The language ids of the string descriptors, such as 0x0409 for US English.
+    fn languages(&self) -> Result<Vec<u16>, DeviceHandleError> {
+        let bytes = self.read_descriptor(DESCRIPTOR_STRING, 0, 0)?;
+        raw_descriptor::parse_languages(&bytes).ok_or(DeviceHandleError::BadDescriptor)
+    }
+
+    /// Reads the string descriptor at `index` in a language.
+    fn read_string(&self, index: u8, language: u16) -> Result<String, DeviceHandleError> {
+        let bytes = self.read_descriptor(DESCRIPTOR_STRING, index, language)?;
+        raw_descriptor::parse_string_descriptor(&bytes).ok_or(DeviceHandleError::BadDescriptor)
+    }
+
+    /// Reads the serial number string in the first language of the device.
+    fn serial_number(&self) -> Result<Option<String>, DeviceHandleError> {
+        self.descriptor_string(self.device_descriptor()?.serial_number_string_index)
+    }
+
+    /// Reads the manufacturer string in the first language of the device.
+    fn manufacturer(&self) -> Result<Option<String>, DeviceHandleError> {
+        self.descriptor_string(self.device_descriptor()?.manufacturer_string_index)
+    }
+
+    /// Reads the product string in the first language of the device.
+    fn product(&self) -> Result<Option<String>, DeviceHandleError> {
+        self.descriptor_string(self.device_descriptor()?.product_string_index)
+    }
+
+    /// Reads the string at an index taken from a descriptor in the first language of the device.
+    /// Returns `None` if the descriptor has no string, or the device has no languages.
+    fn descriptor_string(&self, index: Option<u8>) -> Result<Option<String>, DeviceHandleError> {
+        let Some(index) = index else { return Ok(None) };
+        let Some(&language) = self.languages()?.first() else { return Ok(None) };
+
+        self.read_string(index, language).map(Some)
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendDeviceHandle>, DeviceHandleError>;
+}
+
+/// An opened device. The methods mirror those of `rusb::DeviceHandle`.
+pub trait BackendDeviceHandle: Send + Sync + Debug {
+    fn set_auto_detach_kernel_driver(&mut self, enable: bool) -> Result<(), DeviceHandleError>;
+
+    fn reset(&mut self) -> Result<(), DeviceHandleError>;
+
+    fn active_configuration(&mut self) -> Result<u8, DeviceHandleError>;
+
+    fn set_active_configuration(&mut self, configuration: u8) -> Result<(), DeviceHandleError>;
+
+    fn claim_interface(&mut self, interface: u8) -> Result<(), DeviceHandleError>;
+
+    fn release_interface(&mut self, interface: u8) -> Result<(), DeviceHandleError>;
+
+    fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> Result<(), DeviceHandleError>;
+
+    fn read_interrupt(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceHandleError>;
+
+    fn write_interrupt(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize, DeviceHandleError>;
+
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceHandleError>;
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize, DeviceHandleError>;
+
+    fn read_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceHandleError>;
+
+    fn write_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8], timeout: Duration) -> Result<usize, DeviceHandleError>;
+}
+
+/// Keeps a hotplug listener alive for as long as it exists.
+pub struct HotplugRegistration {
+    _guard: Box<dyn Send>,
+}
+
+impl HotplugRegistration {
+    pub fn new(guard: impl Send + 'static) -> Self {
+        Self { _guard: Box::new(guard) }
+    }
+}
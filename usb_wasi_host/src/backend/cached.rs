@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::{DeviceHandleError, Speed};
+
+use super::{BackendDevice, BackendDeviceHandle};
+
+/// The languages and descriptors read from one device.
+#[derive(Debug, Default)]
+pub struct DescriptorCache {
+    languages: Mutex<Option<Vec<u16>>>,
+    /// By type, index and language.
+    descriptors: Mutex<HashMap<(u8, u8, u16), Vec<u8>>>,
+}
+
+/// A device whose languages, strings and other descriptors are only read once, and then come from a cache shared by all guests.
+/// Reading a descriptor opens the device and sends it a control request, so with the cache, guests and policies
+/// identify a device without talking to it again, even while another guest has it open.
+#[derive(Debug)]
+pub struct CachedDevice {
+    device: Arc<dyn BackendDevice>,
+    cache: Arc<DescriptorCache>,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BackendDevice>, cache: Arc<DescriptorCache>) -> Self {
+        Self { device, cache }
+    }
+}
+
+impl BackendDevice for CachedDevice {
+    fn bus_number(&self) -> u8 {
+        self.device.bus_number()
+    }
+
+    fn address(&self) -> u8 {
+        self.device.address()
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>, DeviceHandleError> {
+        self.device.port_numbers()
+    }
+
+    fn parent_address(&self) -> Option<u8> {
+        self.device.parent_address()
+    }
+
+    fn speed(&self) -> Speed {
+        self.device.speed()
+    }
+
+    fn device_descriptor(&self) -> Result<DeviceDescriptor, DeviceHandleError> {
+        self.device.device_descriptor()
+    }
+
+    fn configurations(&self) -> Result<Vec<ConfigurationDescriptor>, DeviceHandleError> {
+        self.device.configurations()
+    }
+
+    /// Errors are not cached, since they are often caused by another program having the device open.
+    /// The cache is not locked while the device is read, so a slow device does not hold up other guests.
+    fn read_descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Result<Vec<u8>, DeviceHandleError> {
+        let key = (descriptor_type, index, language);
+        if let Some(descriptor) = self.cache.descriptors.lock().unwrap().get(&key) {
+            return Ok(descriptor.clone());
+        }
+
+        let descriptor = self.device.read_descriptor(descriptor_type, index, language)?;
+        self.cache.descriptors.lock().unwrap().insert(key, descriptor.clone());
+        Ok(descriptor)
+    }
+
+    /// Asks the device rather than reading the string descriptor 0 through the cache, for backends that know the languages otherwise.
+    fn languages(&self) -> Result<Vec<u16>, DeviceHandleError> {
+        if let Some(languages) = &*self.cache.languages.lock().unwrap() {
+            return Ok(languages.clone());
+        }
+
+        let read = self.device.languages()?;
+        *self.cache.languages.lock().unwrap() = Some(read.clone());
+        Ok(read)
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendDeviceHandle>, DeviceHandleError> {
+        self.device.open()
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rusb::{Hotplug, UsbContext};
+use tokio::sync::mpsc;
+
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::{DeviceHandleError, Speed};
+use crate::device::usbdevice::USBDevice;
+use crate::events::DeviceConnectionEvent;
+use crate::raw_descriptor;
+
+use super::{BackendDevice, BackendDeviceHandle, HotplugRegistration, UsbBackend};
+
+const DESCRIPTOR_TIMEOUT: Duration = Duration::from_secs(1);
+const GET_DESCRIPTOR: u8 = 0x06;
+/// How long the hotplug thread waits for libusb events before checking whether it should stop.
+const HOTPLUG_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Talks to physical devices through libusb.
+pub struct LibusbBackend {
+    context: rusb::Context,
+}
+
+impl LibusbBackend {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            context: rusb::Context::new()?,
+        })
+    }
+}
+
+impl UsbBackend for LibusbBackend {
+    fn devices(&self) -> Result<Vec<Box<dyn BackendDevice>>> {
+        let devices = self
+            .context
+            .devices()?
+            .iter()
+            .map(|device| Box::new(LibusbDevice { device }) as Box<dyn BackendDevice>)
+            .collect();
+
+        Ok(devices)
+    }
+
+    fn watch(&self, sender: mpsc::UnboundedSender<DeviceConnectionEvent>) -> Result<HotplugRegistration> {
+        let handler = HotplugHandler { sender };
+
+        let context = self.context.clone();
+        let registration: rusb::Registration<rusb::Context> = rusb::HotplugBuilder::new()
+            .enumerate(true)
+            .register(&context, Box::new(handler))?;
+
+        let stop = StopOnDrop(Arc::new(AtomicBool::new(false)));
+        let stopped = stop.0.clone();
+        tokio::task::spawn_blocking(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                if let Err(e) = context.handle_events(Some(HOTPLUG_POLL_INTERVAL)) {
+                    tracing::error!(error = ?e, "Stopped handling hotplug events");
+                    break;
+                }
+            }
+        });
+
+        // The callback is deregistered before the thread is told to stop.
+        Ok(HotplugRegistration::new((registration, stop)))
+    }
+}
+
+/// Tells the thread handling hotplug events to stop once the registration is dropped.
+struct StopOnDrop(Arc<AtomicBool>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+struct HotplugHandler {
+    sender: mpsc::UnboundedSender<DeviceConnectionEvent>,
+}
+
+impl HotplugHandler {
+    /// The channel is unbounded, so events are sent from the libusb thread without blocking and none are lost.
+    fn send(&self, event: DeviceConnectionEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Hotplug<rusb::Context> for HotplugHandler {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        let device = USBDevice { device: Arc::new(LibusbDevice { device }) };
+        self.send(DeviceConnectionEvent::Connected(device));
+    }
+
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+        let device = USBDevice { device: Arc::new(LibusbDevice { device }) };
+        self.send(DeviceConnectionEvent::Disconnected(device));
+    }
+}
+
+#[derive(Debug)]
+struct LibusbDevice {
+    device: rusb::Device<rusb::Context>,
+}
+
+impl BackendDevice for LibusbDevice {
+    fn bus_number(&self) -> u8 {
+        self.device.bus_number()
+    }
+
+    fn address(&self) -> u8 {
+        self.device.address()
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>, DeviceHandleError> {
+        Ok(self.device.port_numbers()?)
+    }
+
+    fn parent_address(&self) -> Option<u8> {
+        self.device.get_parent().map(|parent| parent.address())
+    }
+
+    fn speed(&self) -> Speed {
+        self.device.speed().into()
+    }
+
+    fn device_descriptor(&self) -> Result<DeviceDescriptor, DeviceHandleError> {
+        Ok(self.device.device_descriptor()?.into())
+    }
+
+    fn configurations(&self) -> Result<Vec<ConfigurationDescriptor>, DeviceHandleError> {
+        let config_count = self.device.device_descriptor()?.num_configurations();
+
+        (0..config_count)
+            .map(|i| {
+                self.device
+                    .config_descriptor(i)
+                    .map(ConfigurationDescriptor::from)
+                    .map_err(DeviceHandleError::from)
+            })
+            .collect()
+    }
+
+    fn read_descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Result<Vec<u8>, DeviceHandleError> {
+        let handle = self.device.open()?;
+        let request_type = rusb::request_type(rusb::Direction::In, rusb::RequestType::Standard, rusb::Recipient::Device);
+        let value = u16::from(descriptor_type) << 8 | u16::from(index);
+
+        // Every descriptor fits in 255 bytes, except those with a total length, which are read again once it is known.
+        let mut buf = vec![0; 255];
+        let length = handle.read_control(request_type, GET_DESCRIPTOR, value, language, &mut buf, DESCRIPTOR_TIMEOUT)?;
+        buf.truncate(length);
+
+        if let Some(total_length) = raw_descriptor::total_length(&buf).filter(|&total| total > buf.len()) {
+            buf.resize(total_length, 0);
+            let length = handle.read_control(request_type, GET_DESCRIPTOR, value, language, &mut buf, DESCRIPTOR_TIMEOUT)?;
+            buf.truncate(length);
+        }
+
+        Ok(buf)
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendDeviceHandle>, DeviceHandleError> {
+        let handle = self.device.open()?;
+        Ok(Box::new(LibusbDeviceHandle { handle }))
+    }
+}
+
+#[derive(Debug)]
+struct LibusbDeviceHandle {
+    handle: rusb::DeviceHandle<rusb::Context>,
+}
+
+impl BackendDeviceHandle for LibusbDeviceHandle {
+    fn set_auto_detach_kernel_driver(&mut self, enable: bool) -> Result<(), DeviceHandleError> {
+        Ok(self.handle.set_auto_detach_kernel_driver(enable)?)
+    }
+
+    fn reset(&mut self) -> Result<(), DeviceHandleError> {
+        Ok(self.handle.reset()?)
+    }
+
+    fn active_configuration(&mut self) -> Result<u8, DeviceHandleError> {
+        Ok(self.handle.active_configuration()?)
+    }
+
+    fn set_active_configuration(&mut self, configuration: u8) -> Result<(), DeviceHandleError> {
+        Ok(self.handle.set_active_configuration(configuration)?)
+    }
+
+    fn claim_interface(&mut self, interface: u8) -> Result<(), DeviceHandleError> {
+        Ok(self.handle.claim_interface(interface)?)
+    }
+
+    fn release_interface(&mut self, interface: u8) -> Result<(), DeviceHandleError> {
+        Ok(self.handle.release_interface(interface)?)
+    }
+
+    fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> Result<(), DeviceHandleError> {
+        Ok(self.handle.set_alternate_setting(interface, setting)?)
+    }
+
+    fn read_interrupt(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceHandleError> {
+        Ok(self.handle.read_interrupt(endpoint, buf, timeout)?)
+    }
+
+    fn write_interrupt(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize, DeviceHandleError> {
+        Ok(self.handle.write_interrupt(endpoint, buf, timeout)?)
+    }
+
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceHandleError> {
+        Ok(self.handle.read_bulk(endpoint, buf, timeout)?)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize, DeviceHandleError> {
+        Ok(self.handle.write_bulk(endpoint, buf, timeout)?)
+    }
+
+    fn read_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceHandleError> {
+        Ok(self.handle.read_control(request_type, request, value, index, buf, timeout)?)
+    }
+
+    fn write_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8], timeout: Duration) -> Result<usize, DeviceHandleError> {
+        Ok(self.handle.write_control(request_type, request, value, index, buf, timeout)?)
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::DeviceHandleError;
+use crate::capture::{self, RecordedTransfer, TransferKind};
+use crate::conversion::hex;
+use crate::device::usbdevice::USBDevice;
+use crate::events::DeviceConnectionEvent;
+use crate::firewall::SetupPacket;
+use crate::raw_descriptor::{self, DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE};
+
+use super::{BackendDevice, BackendDeviceHandle, HotplugRegistration, UsbBackend};
+
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+
+/// Serves guests from a capture of an earlier session instead of real devices.
+/// The devices described in the capture are presented as attached, and every transfer of a guest
+/// is answered with the next transfer that was recorded for that device.
+/// Transfers that differ from the recording are answered with `other` and reported as mismatches.
+/// Only captures written with `--capture` can be replayed: other usbmon captures also contain the requests
+/// the kernel makes to enumerate and configure devices, which no guest performs.
+pub struct ReplayBackend {
+    devices: Vec<ReplayDevice>,
+    mismatches: Arc<Mutex<Vec<Mismatch>>>,
+}
+
+/// A difference between what a guest did and what was recorded.
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    pub bus_number: u8,
+    pub device_address: u8,
+    /// The position of the transfer among the recorded transfers of the device.
+    pub index: usize,
+    pub kind: MismatchKind,
+    pub expected: Option<TransferSummary>,
+    pub actual: Option<TransferSummary>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MismatchKind {
+    /// A control transfer was recorded, but the guest performed a bulk or interrupt transfer, or the other way around.
+    TransferType,
+    Endpoint,
+    SetupPacket,
+    OutPayload,
+    /// The guest performed a transfer after all recorded transfers of the device were replayed.
+    Unexpected,
+    /// The guest stopped before performing this recorded transfer.
+    Missing,
+}
+
+/// A transfer as shown in a mismatch report. Data is hex-encoded.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferSummary {
+    pub transfer: TransferKind,
+    pub endpoint: u8,
+    pub data: String,
+}
+
+impl ReplayBackend {
+    pub fn open(path: &Path) -> Result<Self> {
+        let transfers = capture::read_transfers(path)?;
+        if !transfers.iter().any(|transfer| transfer.synthetic) {
+            bail!(
+                "The capture {} was not made with --capture: it does not contain the descriptors the host writes for the devices a guest sees",
+                path.display()
+            );
+        }
+
+        let mut recorded: BTreeMap<(u8, u8), Vec<RecordedTransfer>> = BTreeMap::new();
+        for transfer in transfers {
+            recorded
+                .entry((transfer.bus_number, transfer.device_address))
+                .or_default()
+                .push(transfer);
+        }
+
+        let mismatches = Arc::new(Mutex::new(Vec::new()));
+        let mut devices = Vec::new();
+
+        for ((bus_number, address), transfers) in recorded {
+            let Some(device) = ReplayDevice::new(bus_number, address, transfers, mismatches.clone()) else {
+                tracing::warn!(bus = bus_number, address, "Not replaying the device: the capture does not contain its descriptors");
+                continue;
+            };
+
+            devices.push(device);
+        }
+
+        if devices.is_empty() {
+            bail!("The capture {} does not describe any devices", path.display());
+        }
+
+        Ok(Self { devices, mismatches })
+    }
+
+    /// Returns all mismatches, including the first recorded transfer of each device that the guest did not perform.
+    pub fn finish(&self) -> Vec<Mismatch> {
+        let mut mismatches = self.mismatches.lock().unwrap().clone();
+
+        for device in &self.devices {
+            if let Some((index, expected)) = device.state.lock().unwrap().transfers.front() {
+                mismatches.push(device.mismatch(*index, MismatchKind::Missing, Some(expected.into()), None));
+            }
+        }
+
+        mismatches
+    }
+}
+
+impl UsbBackend for ReplayBackend {
+    fn devices(&self) -> Result<Vec<Box<dyn BackendDevice>>> {
+        let devices = self
+            .devices
+            .iter()
+            .map(|device| Box::new(device.clone()) as Box<dyn BackendDevice>)
+            .collect();
+
+        Ok(devices)
+    }
+
+    fn watch(&self, sender: mpsc::UnboundedSender<DeviceConnectionEvent>) -> Result<HotplugRegistration> {
+        for device in &self.devices {
+            let _ = sender.send(DeviceConnectionEvent::Connected(USBDevice { device: Arc::new(device.clone()) }));
+        }
+
+        // Replayed devices are never detached, so there is nothing to keep alive.
+        Ok(HotplugRegistration::new(()))
+    }
+}
+
+/// A device reconstructed from the GET_DESCRIPTOR requests that the host wrote to a capture to describe it.
+/// Clones share the queue of recorded transfers.
+#[derive(Debug, Clone)]
+struct ReplayDevice {
+    bus_number: u8,
+    address: u8,
+    descriptor: DeviceDescriptor,
+    configurations: Vec<ConfigurationDescriptor>,
+    /// The other descriptors that guests read, such as strings, by type, index and language.
+    descriptors: HashMap<(u8, u8, u16), Vec<u8>>,
+    state: Arc<Mutex<ReplayState>>,
+    mismatches: Arc<Mutex<Vec<Mismatch>>>,
+}
+
+#[derive(Debug)]
+struct ReplayState {
+    active_configuration: u8,
+    /// The number of transfers the guest has performed.
+    performed: usize,
+    /// The transfers the guest has yet to perform, with their position in the recording.
+    transfers: VecDeque<(usize, RecordedTransfer)>,
+}
+
+impl ReplayDevice {
+    fn new(bus_number: u8, address: u8, recorded: Vec<RecordedTransfer>, mismatches: Arc<Mutex<Vec<Mismatch>>>) -> Option<Self> {
+        let mut descriptor = None;
+        let mut configurations = BTreeMap::new();
+        let mut descriptors = HashMap::new();
+
+        for transfer in &recorded {
+            let TransferKind::Control(setup) = transfer.kind else { continue };
+            if !transfer.synthetic || setup.request_type != 0x80 || setup.request != REQUEST_GET_DESCRIPTOR || transfer.result.is_err() {
+                continue;
+            }
+
+            let [descriptor_type, index] = setup.value.to_be_bytes();
+            match descriptor_type {
+                DESCRIPTOR_DEVICE => {
+                    descriptor = raw_descriptor::parse_device_descriptor(&transfer.data).or(descriptor);
+                }
+                DESCRIPTOR_CONFIGURATION => {
+                    if let Some(configuration) = raw_descriptor::parse_configuration_descriptor(&transfer.data) {
+                        configurations.insert(index, configuration);
+                    }
+                }
+                _ => {
+                    descriptors.insert((descriptor_type, index, setup.index), transfer.data.clone());
+                }
+            }
+        }
+
+        let configurations: Vec<ConfigurationDescriptor> = configurations.into_values().collect();
+        let state = ReplayState {
+            active_configuration: configurations.first().map(|c| c.number).unwrap_or(0),
+            performed: 0,
+            transfers: recorded.into_iter().filter(|t| !t.synthetic).enumerate().collect(),
+        };
+
+        Some(Self {
+            bus_number,
+            address,
+            descriptor: descriptor?,
+            configurations,
+            descriptors,
+            state: Arc::new(Mutex::new(state)),
+            mismatches,
+        })
+    }
+
+    fn mismatch(&self, index: usize, kind: MismatchKind, expected: Option<TransferSummary>, actual: Option<TransferSummary>) -> Mismatch {
+        Mismatch {
+            bus_number: self.bus_number,
+            device_address: self.address,
+            index,
+            kind,
+            expected,
+            actual,
+        }
+    }
+
+    /// Takes the next recorded transfer and checks that it is the one the guest performs.
+    /// `data` is the payload of an OUT transfer and is ignored for IN transfers.
+    fn next(&self, kind: TransferKind, endpoint: u8, data: &[u8]) -> Result<RecordedTransfer, DeviceHandleError> {
+        let mut state = self.state.lock().unwrap();
+        let performed = state.performed;
+        state.performed += 1;
+
+        let actual = || TransferSummary {
+            transfer: kind,
+            endpoint,
+            data: if endpoint & 0x80 == 0 { hex(data) } else { String::new() },
+        };
+
+        let Some((index, expected)) = state.transfers.pop_front() else {
+            self.report(self.mismatch(performed, MismatchKind::Unexpected, None, Some(actual())));
+            return Err(DeviceHandleError::Other);
+        };
+
+        let difference = match (kind, expected.kind) {
+            (TransferKind::Control(setup), TransferKind::Control(recorded)) if setup != recorded => Some(MismatchKind::SetupPacket),
+            (TransferKind::Control(_), TransferKind::Control(_)) => None,
+            (TransferKind::Bulk, TransferKind::Bulk) | (TransferKind::Interrupt, TransferKind::Interrupt) => None,
+            _ => Some(MismatchKind::TransferType),
+        }
+        .or_else(|| (endpoint != expected.endpoint).then_some(MismatchKind::Endpoint))
+        .or_else(|| (endpoint & 0x80 == 0 && data != expected.data).then_some(MismatchKind::OutPayload));
+
+        if let Some(difference) = difference {
+            self.report(self.mismatch(index, difference, Some((&expected).into()), Some(actual())));
+            return Err(DeviceHandleError::Other);
+        }
+
+        Ok(expected)
+    }
+
+    fn report(&self, mismatch: Mismatch) {
+        tracing::warn!(
+            bus = mismatch.bus_number, address = mismatch.device_address, transfer = mismatch.index, kind = ?mismatch.kind,
+            "Replay mismatch"
+        );
+        self.mismatches.lock().unwrap().push(mismatch);
+    }
+
+    fn read(&self, kind: TransferKind, endpoint: u8, buf: &mut [u8]) -> Result<usize, DeviceHandleError> {
+        let recorded = self.next(kind, endpoint, &[])?;
+        recorded.result?;
+
+        if recorded.data.len() > buf.len() {
+            return Err(DeviceHandleError::Overflow);
+        }
+        buf[..recorded.data.len()].copy_from_slice(&recorded.data);
+
+        Ok(recorded.data.len())
+    }
+
+    fn write(&self, kind: TransferKind, endpoint: u8, buf: &[u8]) -> Result<usize, DeviceHandleError> {
+        self.next(kind, endpoint, buf)?.result
+    }
+}
+
+impl BackendDevice for ReplayDevice {
+    fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Port numbers are not part of a capture.
+    fn port_numbers(&self) -> Result<Vec<u8>, DeviceHandleError> {
+        Ok(Vec::new())
+    }
+
+    fn device_descriptor(&self) -> Result<DeviceDescriptor, DeviceHandleError> {
+        Ok(self.descriptor.clone())
+    }
+
+    fn configurations(&self) -> Result<Vec<ConfigurationDescriptor>, DeviceHandleError> {
+        Ok(self.configurations.clone())
+    }
+
+    /// A capture contains the device and configuration descriptors, and the other descriptors that guests read.
+    /// Descriptors that no guest read stall, like descriptors a device does not have.
+    fn read_descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Result<Vec<u8>, DeviceHandleError> {
+        match descriptor_type {
+            DESCRIPTOR_DEVICE => Ok(raw_descriptor::encode_device_descriptor(&self.descriptor)),
+            DESCRIPTOR_CONFIGURATION => self.configurations
+                .get(usize::from(index))
+                .map(raw_descriptor::encode_configuration_descriptor)
+                .ok_or(DeviceHandleError::Pipe),
+            _ => self.descriptors
+                .get(&(descriptor_type, index, language))
+                .cloned()
+                .ok_or(DeviceHandleError::Pipe),
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendDeviceHandle>, DeviceHandleError> {
+        Ok(Box::new(ReplayDeviceHandle { device: self.clone() }))
+    }
+}
+
+/// Operations that are not transfers are not part of a capture, so they always succeed.
+#[derive(Debug)]
+struct ReplayDeviceHandle {
+    device: ReplayDevice,
+}
+
+impl BackendDeviceHandle for ReplayDeviceHandle {
+    fn set_auto_detach_kernel_driver(&mut self, _: bool) -> Result<(), DeviceHandleError> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), DeviceHandleError> {
+        Ok(())
+    }
+
+    fn active_configuration(&mut self) -> Result<u8, DeviceHandleError> {
+        Ok(self.device.state.lock().unwrap().active_configuration)
+    }
+
+    fn set_active_configuration(&mut self, configuration: u8) -> Result<(), DeviceHandleError> {
+        self.device.state.lock().unwrap().active_configuration = configuration;
+        Ok(())
+    }
+
+    fn claim_interface(&mut self, _: u8) -> Result<(), DeviceHandleError> {
+        Ok(())
+    }
+
+    fn release_interface(&mut self, _: u8) -> Result<(), DeviceHandleError> {
+        Ok(())
+    }
+
+    fn set_alternate_setting(&mut self, _: u8, _: u8) -> Result<(), DeviceHandleError> {
+        Ok(())
+    }
+
+    fn read_interrupt(&mut self, endpoint: u8, buf: &mut [u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.read(TransferKind::Interrupt, endpoint, buf)
+    }
+
+    fn write_interrupt(&mut self, endpoint: u8, buf: &[u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.write(TransferKind::Interrupt, endpoint, buf)
+    }
+
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.read(TransferKind::Bulk, endpoint, buf)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.write(TransferKind::Bulk, endpoint, buf)
+    }
+
+    fn read_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        let setup = SetupPacket { request_type, request, value, index, length: buf.len() as u16 };
+        self.device.read(TransferKind::Control(setup), 0x80, buf)
+    }
+
+    fn write_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        let setup = SetupPacket { request_type, request, value, index, length: buf.len() as u16 };
+        self.device.write(TransferKind::Control(setup), 0x00, buf)
+    }
+}
+
+impl From<&RecordedTransfer> for TransferSummary {
+    fn from(transfer: &RecordedTransfer) -> Self {
+        Self {
+            transfer: transfer.kind,
+            endpoint: transfer.endpoint,
+            data: hex(&transfer.data),
+        }
+    }
+}
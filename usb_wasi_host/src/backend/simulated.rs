@@ -0,0 +1,791 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::{DeviceHandleError, Speed};
+use crate::device::usbdevice::USBDevice;
+use crate::events::DeviceConnectionEvent;
+use crate::raw_descriptor::{self, DESCRIPTOR_BOS, DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE, DESCRIPTOR_STRING};
+
+use super::{BackendDevice, BackendDeviceHandle, HotplugRegistration, UsbBackend};
+
+const LANGUAGE_EN_US: u16 = 0x0409;
+const MANUFACTURER_INDEX: u8 = 1;
+const PRODUCT_INDEX: u8 = 2;
+const SERIAL_NUMBER_INDEX: u8 = 3;
+
+/// An in-memory USB bus.
+/// Devices are described by their descriptors and answer transfers with scripted responses,
+/// so the host and guests can be exercised without hardware.
+#[derive(Clone, Default)]
+pub struct SimulatedBackend {
+    bus: Arc<Mutex<Bus>>,
+}
+
+#[derive(Default)]
+struct Bus {
+    devices: Vec<SimulatedDevice>,
+    watchers: Vec<mpsc::UnboundedSender<DeviceConnectionEvent>>,
+}
+
+impl Bus {
+    fn notify(&mut self, event: impl Fn() -> DeviceConnectionEvent) {
+        self.watchers.retain(|watcher| !watcher.is_closed());
+        for watcher in &self.watchers {
+            let _ = watcher.send(event());
+        }
+    }
+}
+
+impl SimulatedBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attaches a device to the bus. Watchers receive a connected event.
+    pub fn connect(&self, device: SimulatedDevice) {
+        let mut bus = self.bus.lock().unwrap();
+
+        device.state.lock().unwrap().connected = true;
+        bus.notify(|| DeviceConnectionEvent::Connected(device.to_usb_device()));
+        bus.devices.push(device);
+    }
+
+    /// Detaches the device at the given bus and address. Watchers receive a disconnected event,
+    /// and open handles to the device fail with `no-device` from then on.
+    pub fn disconnect(&self, bus_number: u8, address: u8) -> Option<SimulatedDevice> {
+        let mut bus = self.bus.lock().unwrap();
+
+        let position = bus
+            .devices
+            .iter()
+            .position(|d| d.bus_number == bus_number && d.address == address)?;
+        let device = bus.devices.remove(position);
+
+        device.state.lock().unwrap().connected = false;
+        bus.notify(|| DeviceConnectionEvent::Disconnected(device.to_usb_device()));
+
+        Some(device)
+    }
+}
+
+impl UsbBackend for SimulatedBackend {
+    fn devices(&self) -> Result<Vec<Box<dyn BackendDevice>>> {
+        let devices = self
+            .bus
+            .lock()
+            .unwrap()
+            .devices
+            .iter()
+            .map(|device| Box::new(device.clone()) as Box<dyn BackendDevice>)
+            .collect();
+
+        Ok(devices)
+    }
+
+    fn watch(&self, sender: mpsc::UnboundedSender<DeviceConnectionEvent>) -> Result<HotplugRegistration> {
+        let mut bus = self.bus.lock().unwrap();
+
+        for device in &bus.devices {
+            let _ = sender.send(DeviceConnectionEvent::Connected(device.to_usb_device()));
+        }
+        bus.watchers.push(sender);
+
+        // Closed senders are pruned on the next event, so there is nothing to keep alive.
+        Ok(HotplugRegistration::new(()))
+    }
+}
+
+/// Identifies the transfers a simulated device can be scripted to answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transfer {
+    Control { request_type: u8, request: u8, value: u16, index: u16 },
+    Bulk { endpoint: u8 },
+    Interrupt { endpoint: u8 },
+}
+
+/// The scripted answer to a single transfer.
+#[derive(Debug, Clone)]
+pub enum SimulatedResponse {
+    /// The data returned to an IN transfer.
+    Data(Vec<u8>),
+    /// The number of bytes accepted by an OUT transfer.
+    Written(usize),
+    /// The transfer fails with the given error.
+    Error(DeviceHandleError),
+}
+
+/// A device that can be attached to a `SimulatedBackend`.
+/// Clones share their scripted responses and the record of written data.
+#[derive(Debug, Clone)]
+pub struct SimulatedDevice {
+    bus_number: u8,
+    address: u8,
+    port_numbers: Vec<u8>,
+    parent_address: Option<u8>,
+    speed: Speed,
+    /// The string descriptors by index, all in `LANGUAGE_EN_US`.
+    strings: BTreeMap<u8, String>,
+    bos: Option<Vec<u8>>,
+    descriptor: DeviceDescriptor,
+    configurations: Vec<ConfigurationDescriptor>,
+    state: Arc<Mutex<DeviceState>>,
+}
+
+#[derive(Debug, Default)]
+struct DeviceState {
+    connected: bool,
+    active_configuration: u8,
+    responses: HashMap<Transfer, VecDeque<SimulatedResponse>>,
+    written: Vec<(Transfer, Vec<u8>)>,
+}
+
+impl SimulatedDevice {
+    pub fn new(bus_number: u8, address: u8, descriptor: DeviceDescriptor, configurations: Vec<ConfigurationDescriptor>) -> Self {
+        let active_configuration = configurations.first().map(|c| c.number).unwrap_or(0);
+        let state = DeviceState { active_configuration, ..Default::default() };
+
+        Self {
+            bus_number,
+            address,
+            port_numbers: Vec::new(),
+            parent_address: None,
+            speed: Speed::Full,
+            strings: BTreeMap::new(),
+            bos: None,
+            descriptor,
+            configurations,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn with_port_numbers(mut self, port_numbers: Vec<u8>) -> Self {
+        self.port_numbers = port_numbers;
+        self
+    }
+
+    pub fn with_parent_address(mut self, parent_address: u8) -> Self {
+        self.parent_address = Some(parent_address);
+        self
+    }
+
+    pub fn with_speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn with_serial_number(mut self, serial_number: impl Into<String>) -> Self {
+        self.descriptor.serial_number_string_index = Some(SERIAL_NUMBER_INDEX);
+        self.with_string(SERIAL_NUMBER_INDEX, serial_number)
+    }
+
+    pub fn with_strings(mut self, manufacturer: impl Into<String>, product: impl Into<String>) -> Self {
+        self.descriptor.manufacturer_string_index = Some(MANUFACTURER_INDEX);
+        self.descriptor.product_string_index = Some(PRODUCT_INDEX);
+        self.with_string(MANUFACTURER_INDEX, manufacturer).with_string(PRODUCT_INDEX, product)
+    }
+
+    /// Answers requests for the BOS descriptor with these bytes, which include the capability descriptors.
+    pub fn with_bos(mut self, bos: Vec<u8>) -> Self {
+        self.bos = Some(bos);
+        self
+    }
+
+    /// Adds a string descriptor, such as the string of an interface.
+    pub fn with_string(mut self, index: u8, string: impl Into<String>) -> Self {
+        self.strings.insert(index, string.into());
+        self
+    }
+
+    /// Queues a response for the next matching transfer.
+    /// Responses for the same transfer are used in the order they were queued.
+    pub fn respond(&self, transfer: Transfer, response: SimulatedResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .entry(transfer)
+            .or_default()
+            .push_back(response);
+    }
+
+    /// All data written to the device by OUT transfers, in order.
+    pub fn written(&self) -> Vec<(Transfer, Vec<u8>)> {
+        self.state.lock().unwrap().written.clone()
+    }
+
+    fn to_usb_device(&self) -> USBDevice {
+        USBDevice { device: Arc::new(self.clone()) }
+    }
+
+    fn next_response(&self, transfer: Transfer) -> Result<Option<SimulatedResponse>, DeviceHandleError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(DeviceHandleError::NoDevice);
+        }
+
+        Ok(state.responses.get_mut(&transfer).and_then(|r| r.pop_front()))
+    }
+
+    /// Answers an IN transfer. Without a scripted response, control requests stall and
+    /// other transfers time out, just like an idle device would.
+    fn read(&self, transfer: Transfer, buf: &mut [u8]) -> Result<usize, DeviceHandleError> {
+        match self.next_response(transfer)? {
+            Some(SimulatedResponse::Data(data)) => {
+                if data.len() > buf.len() {
+                    return Err(DeviceHandleError::Overflow);
+                }
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Some(SimulatedResponse::Written(_)) => Err(DeviceHandleError::InvalidParam),
+            Some(SimulatedResponse::Error(error)) => Err(error),
+            None if matches!(transfer, Transfer::Control { .. }) => Err(DeviceHandleError::Pipe),
+            None => Err(DeviceHandleError::Timeout),
+        }
+    }
+
+    /// Answers an OUT transfer. Without a scripted response, all data is accepted.
+    fn write(&self, transfer: Transfer, buf: &[u8]) -> Result<usize, DeviceHandleError> {
+        let written = match self.next_response(transfer)? {
+            Some(SimulatedResponse::Written(count)) => count.min(buf.len()),
+            Some(SimulatedResponse::Data(_)) => return Err(DeviceHandleError::InvalidParam),
+            Some(SimulatedResponse::Error(error)) => return Err(error),
+            None => buf.len(),
+        };
+
+        self.state
+            .lock()
+            .unwrap()
+            .written
+            .push((transfer, buf[..written].to_vec()));
+
+        Ok(written)
+    }
+
+    fn check_connected(&self) -> Result<(), DeviceHandleError> {
+        if self.state.lock().unwrap().connected {
+            Ok(())
+        } else {
+            Err(DeviceHandleError::NoDevice)
+        }
+    }
+}
+
+impl BackendDevice for SimulatedDevice {
+    fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>, DeviceHandleError> {
+        Ok(self.port_numbers.clone())
+    }
+
+    fn parent_address(&self) -> Option<u8> {
+        self.parent_address
+    }
+
+    fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    fn device_descriptor(&self) -> Result<DeviceDescriptor, DeviceHandleError> {
+        Ok(self.descriptor.clone())
+    }
+
+    fn configurations(&self) -> Result<Vec<ConfigurationDescriptor>, DeviceHandleError> {
+        Ok(self.configurations.clone())
+    }
+
+    /// Like a real device, the simulated device stalls for a descriptor it does not have.
+    fn read_descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Result<Vec<u8>, DeviceHandleError> {
+        self.check_connected()?;
+
+        let descriptor = match (descriptor_type, index) {
+            (DESCRIPTOR_DEVICE, _) => Some(raw_descriptor::encode_device_descriptor(&self.descriptor)),
+            (DESCRIPTOR_CONFIGURATION, _) => self.configurations
+                .get(usize::from(index))
+                .map(raw_descriptor::encode_configuration_descriptor),
+            (DESCRIPTOR_STRING, 0) if !self.strings.is_empty() => Some(raw_descriptor::encode_languages(&[LANGUAGE_EN_US])),
+            (DESCRIPTOR_STRING, _) if language == LANGUAGE_EN_US => self.strings
+                .get(&index)
+                .map(|string| raw_descriptor::encode_string_descriptor(string)),
+            (DESCRIPTOR_BOS, _) => self.bos.clone(),
+            _ => None,
+        };
+
+        descriptor.ok_or(DeviceHandleError::Pipe)
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendDeviceHandle>, DeviceHandleError> {
+        self.check_connected()?;
+        Ok(Box::new(SimulatedDeviceHandle { device: self.clone() }))
+    }
+}
+
+#[derive(Debug)]
+struct SimulatedDeviceHandle {
+    device: SimulatedDevice,
+}
+
+impl SimulatedDeviceHandle {
+    fn has_interface(&self, interface: u8) -> bool {
+        self.device
+            .configurations
+            .iter()
+            .flat_map(|c| c.interfaces.iter())
+            .any(|i| i.number == interface)
+    }
+}
+
+impl BackendDeviceHandle for SimulatedDeviceHandle {
+    fn set_auto_detach_kernel_driver(&mut self, _: bool) -> Result<(), DeviceHandleError> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), DeviceHandleError> {
+        self.device.check_connected()
+    }
+
+    fn active_configuration(&mut self) -> Result<u8, DeviceHandleError> {
+        self.device.check_connected()?;
+        Ok(self.device.state.lock().unwrap().active_configuration)
+    }
+
+    fn set_active_configuration(&mut self, configuration: u8) -> Result<(), DeviceHandleError> {
+        self.device.check_connected()?;
+        if !self.device.configurations.iter().any(|c| c.number == configuration) {
+            return Err(DeviceHandleError::NotFound);
+        }
+
+        self.device.state.lock().unwrap().active_configuration = configuration;
+        Ok(())
+    }
+
+    fn claim_interface(&mut self, interface: u8) -> Result<(), DeviceHandleError> {
+        self.device.check_connected()?;
+        if !self.has_interface(interface) {
+            return Err(DeviceHandleError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    fn release_interface(&mut self, interface: u8) -> Result<(), DeviceHandleError> {
+        self.claim_interface(interface)
+    }
+
+    fn set_alternate_setting(&mut self, interface: u8, _: u8) -> Result<(), DeviceHandleError> {
+        self.claim_interface(interface)
+    }
+
+    fn read_interrupt(&mut self, endpoint: u8, buf: &mut [u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.read(Transfer::Interrupt { endpoint }, buf)
+    }
+
+    fn write_interrupt(&mut self, endpoint: u8, buf: &[u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.write(Transfer::Interrupt { endpoint }, buf)
+    }
+
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.read(Transfer::Bulk { endpoint }, buf)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.write(Transfer::Bulk { endpoint }, buf)
+    }
+
+    fn read_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.read(Transfer::Control { request_type, request, value, index }, buf)
+    }
+
+    fn write_control(&mut self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8], _: Duration) -> Result<usize, DeviceHandleError> {
+        self.device.write(Transfer::Control { request_type, request, value, index }, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::component::Resource;
+    use wasmtime_wasi::ResourceTable;
+
+    use crate::bindings::component::usb::descriptors::{EndpointDescriptor, InterfaceDescriptor, UsbInterface};
+    use crate::bindings::component::usb::events::{DeviceConnectionEvent as WasmDeviceConnectionEvent, Host as EventsHost};
+    use crate::bindings::component::usb::types::{Direction, SyncType, TransferType, UsageType, Version};
+    use crate::bindings::component::usb::usb::{DeviceHandle, HostDeviceHandle, HostUsbDevice};
+    use crate::backend::ReplayBackend;
+    use crate::ctx::{UsbCtx, UsbView};
+    use crate::limits::Limits;
+    use crate::metrics::Metrics;
+    use crate::policy::{Action, Grant, Policy, Rule};
+    use crate::UsbHost;
+
+    use super::*;
+
+    const BUS: u8 = 1;
+    const ADDRESS: u8 = 4;
+
+    struct State {
+        table: ResourceTable,
+        usb: UsbCtx,
+    }
+
+    impl UsbView for State {
+        fn table(&mut self) -> &mut ResourceTable {
+            &mut self.table
+        }
+
+        fn usb(&mut self) -> &mut UsbCtx {
+            &mut self.usb
+        }
+    }
+
+    fn endpoint(address: u8) -> EndpointDescriptor {
+        EndpointDescriptor {
+            address,
+            direction: if address & 0x80 != 0 { Direction::In } else { Direction::Out },
+            interval: 0,
+            max_packet_size: 64,
+            number: address & 0x0f,
+            refresh: 0,
+            sync_type: SyncType::NoSync,
+            synch_address: 0,
+            transfer_type: TransferType::Bulk,
+            usage_type: UsageType::Data,
+            extra_descriptors: Vec::new(),
+        }
+    }
+
+    fn device() -> SimulatedDevice {
+        device_at(ADDRESS)
+    }
+
+    /// A vendor-specific device with one interface and a pair of bulk endpoints.
+    fn device_at(address: u8) -> SimulatedDevice {
+        let version = Version { major: 2, minor: 0, subminor: 0 };
+        let descriptor = DeviceDescriptor {
+            device_class: 0,
+            device_protocol: 0,
+            device_subclass: 0,
+            device_version: version,
+            product_id: 0x5678,
+            usb_version: version,
+            vendor_id: 0x1234,
+            max_packet_size: 64,
+            manufacturer_string_index: None,
+            product_string_index: None,
+            serial_number_string_index: None,
+            num_configurations: 1,
+        };
+
+        let interface = InterfaceDescriptor {
+            number: 0,
+            alternate_setting: 0,
+            class_code: 0xff,
+            subclass_code: 0,
+            protocol: 0,
+            interface_string_index: None,
+            endpoint_descriptors: vec![endpoint(0x81), endpoint(0x01)],
+            extra_descriptors: Vec::new(),
+        };
+
+        let configuration = ConfigurationDescriptor {
+            max_power: 100,
+            number: 1,
+            interfaces: vec![UsbInterface { number: 0, alternate_settings: vec![interface] }],
+            interface_associations: Vec::new(),
+        };
+
+        SimulatedDevice::new(BUS, address, descriptor, vec![configuration])
+    }
+
+    fn guest(backend: &SimulatedBackend, default: Action) -> Result<State> {
+        guest_with_policy(backend, Policy { default, grant: Grant::all(), rules: Vec::new() })
+    }
+
+    fn guest_with_policy(backend: &SimulatedBackend, policy: Policy) -> Result<State> {
+        guest_with_limits(backend, policy, Limits::default())
+    }
+
+    fn guest_with_limits(backend: &SimulatedBackend, policy: Policy, limits: Limits) -> Result<State> {
+        let host = UsbHost::builder().backend(Arc::new(backend.clone())).build()?;
+        let usb = host.guest("simulated").policy(policy).limits(limits).build()?;
+
+        Ok(State { table: ResourceTable::new(), usb })
+    }
+
+    /// Polls `events.update` like a guest would, giving the broker a chance to forward events in between.
+    async fn next_event(state: &mut State) -> Result<WasmDeviceConnectionEvent> {
+        for _ in 0..100 {
+            match EventsHost::update(state).await? {
+                WasmDeviceConnectionEvent::Pending => tokio::task::yield_now().await,
+                event => return Ok(event),
+            }
+        }
+
+        Ok(WasmDeviceConnectionEvent::Pending)
+    }
+
+    async fn open(state: &mut State) -> Result<Resource<DeviceHandle>> {
+        let WasmDeviceConnectionEvent::Connected(device) = next_event(state).await? else {
+            panic!("the device was not reported as connected");
+        };
+
+        Ok(HostUsbDevice::open(state, device).await?.unwrap())
+    }
+
+    #[tokio::test]
+    async fn attached_and_detached_devices_are_reported() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        backend.connect(device());
+        let mut state = guest(&backend, Action::Allow)?;
+
+        let WasmDeviceConnectionEvent::Connected(device) = next_event(&mut state).await? else {
+            panic!("the attached device was not reported as connected");
+        };
+        let descriptor = HostUsbDevice::device_descriptor(&mut state, device).await?;
+        assert_eq!((descriptor.vendor_id, descriptor.product_id), (0x1234, 0x5678));
+
+        backend.disconnect(BUS, ADDRESS);
+        assert!(matches!(next_event(&mut state).await?, WasmDeviceConnectionEvent::Disconnected(_)));
+        assert!(matches!(next_event(&mut state).await?, WasmDeviceConnectionEvent::Pending));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_events_are_lost_with_many_devices() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        for address in 1..=20 {
+            backend.connect(device_at(address));
+        }
+        let mut state = guest(&backend, Action::Allow)?;
+
+        for address in 21..=40 {
+            backend.connect(device_at(address));
+        }
+
+        let mut connected = 0;
+        while let WasmDeviceConnectionEvent::Connected(_) = next_event(&mut state).await? {
+            connected += 1;
+        }
+        assert_eq!(connected, 40);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detached_devices_are_reported_when_the_policy_can_no_longer_read_them() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        backend.connect(device().with_serial_number("A1"));
+        let rule = Rule {
+            action: Action::Allow,
+            serial_number: Some("A1".to_string()),
+            grant: Grant::all(),
+            ..Default::default()
+        };
+        let mut state = guest_with_policy(&backend, Policy { rules: vec![rule], ..Default::default() })?;
+
+        assert!(matches!(next_event(&mut state).await?, WasmDeviceConnectionEvent::Connected(_)));
+
+        backend.disconnect(BUS, ADDRESS);
+        assert!(matches!(next_event(&mut state).await?, WasmDeviceConnectionEvent::Disconnected(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn devices_hidden_by_the_policy_are_not_reported() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        backend.connect(device());
+        let mut state = guest(&backend, Action::Deny)?;
+
+        assert!(matches!(next_event(&mut state).await?, WasmDeviceConnectionEvent::Pending));
+
+        backend.disconnect(BUS, ADDRESS);
+        assert!(matches!(next_event(&mut state).await?, WasmDeviceConnectionEvent::Pending));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transfers_are_answered_with_scripted_responses() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        let device = device();
+        backend.connect(device.clone());
+        let mut state = guest(&backend, Action::Allow)?;
+        let handle = open(&mut state).await?;
+
+        device.respond(Transfer::Bulk { endpoint: 0x81 }, SimulatedResponse::Data(vec![1, 2, 3]));
+        let (read, data) = HostDeviceHandle::read_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x81, 64, 0).await?.unwrap();
+        assert_eq!(&data[..read as usize], &[1, 2, 3]);
+
+        device.respond(Transfer::Bulk { endpoint: 0x01 }, SimulatedResponse::Written(2));
+        let written = HostDeviceHandle::write_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x01, vec![4, 5, 6], 0).await?.unwrap();
+        assert_eq!(written, 2);
+
+        let version = Transfer::Control { request_type: 0xc0, request: 0x01, value: 0, index: 0 };
+        device.respond(version, SimulatedResponse::Data(vec![0x10, 0x02]));
+        let (read, data) = HostDeviceHandle::read_control(&mut state, Resource::new_borrow(handle.rep()), 0xc0, 0x01, 0, 0, 2, 0).await?.unwrap();
+        assert_eq!(&data[..read as usize], &[0x10, 0x02]);
+
+        let led = Transfer::Control { request_type: 0x40, request: 0x02, value: 1, index: 0 };
+        HostDeviceHandle::write_control(&mut state, Resource::new_borrow(handle.rep()), 0x40, 0x02, 1, 0, vec![0xaa], 0).await?.unwrap();
+
+        assert_eq!(device.written(), vec![
+            (Transfer::Bulk { endpoint: 0x01 }, vec![4, 5]),
+            (led, vec![0xaa]),
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unscripted_and_failing_transfers() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        let device = device();
+        backend.connect(device.clone());
+        let mut state = guest(&backend, Action::Allow)?;
+        let handle = open(&mut state).await?;
+
+        let result = HostDeviceHandle::read_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x81, 64, 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::Timeout);
+
+        let result = HostDeviceHandle::read_control(&mut state, Resource::new_borrow(handle.rep()), 0xc0, 0x01, 0, 0, 2, 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::Pipe);
+
+        device.respond(Transfer::Bulk { endpoint: 0x01 }, SimulatedResponse::Error(DeviceHandleError::Busy));
+        let result = HostDeviceHandle::write_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x01, vec![1], 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::Busy);
+        assert!(device.written().is_empty());
+
+        backend.disconnect(BUS, ADDRESS);
+        let result = HostDeviceHandle::read_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x81, 64, 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::NoDevice);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transfers_fail_with_busy_once_the_bandwidth_is_used_up() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        let device = device();
+        backend.connect(device.clone());
+        let policy = Policy { default: Action::Allow, grant: Grant::all(), rules: Vec::new() };
+        let limits = Limits { max_bytes_per_second: Some(4), ..Default::default() };
+        let mut state = guest_with_limits(&backend, policy, limits)?;
+        let handle = open(&mut state).await?;
+
+        // Buffers larger than the limit are allowed, and only the bytes read are counted.
+        device.respond(Transfer::Bulk { endpoint: 0x81 }, SimulatedResponse::Data(vec![1, 2]));
+        let (read, _) = HostDeviceHandle::read_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x81, 64, 0).await?.unwrap();
+        assert_eq!(read, 2);
+
+        let written = HostDeviceHandle::write_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x01, vec![3, 4, 5], 0).await?;
+        assert_eq!(written.unwrap(), 3);
+
+        let result = HostDeviceHandle::write_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x01, vec![6], 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::Busy);
+        assert_eq!(device.written(), vec![(Transfer::Bulk { endpoint: 0x01 }, vec![3, 4, 5])]);
+
+        Ok(())
+    }
+
+    /// Transfers that the host refuses never reach the device, but are counted as failed in the metrics.
+    #[tokio::test]
+    async fn refused_transfers_are_counted_in_the_metrics() -> Result<()> {
+        let backend = SimulatedBackend::new();
+        backend.connect(device());
+        let metrics = Arc::new(Metrics::new());
+        let recorder = metrics.clone();
+        let host = UsbHost::builder()
+            .backend(Arc::new(backend.clone()))
+            .on_event(move |event| recorder.record(event))
+            .build()?;
+        let policy = Policy { default: Action::Allow, grant: Grant::all(), rules: Vec::new() };
+        let limits = Limits { max_bytes_per_second: Some(4), ..Default::default() };
+        let mut state = State { table: ResourceTable::new(), usb: host.guest("simulated").policy(policy).limits(limits).build()? };
+        let handle = open(&mut state).await?;
+
+        // SET_ADDRESS is refused by the firewall.
+        let result = HostDeviceHandle::write_control(&mut state, Resource::new_borrow(handle.rep()), 0x00, 0x05, 1, 0, Vec::new(), 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::Access);
+
+        HostDeviceHandle::write_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x01, vec![1, 2, 3, 4], 0).await?.unwrap();
+        let result = HostDeviceHandle::write_bulk(&mut state, Resource::new_borrow(handle.rep()), 0x01, vec![5], 0).await?;
+        assert_eq!(result.unwrap_err(), DeviceHandleError::Busy);
+
+        let rendered = metrics.render(host.registry());
+        assert!(rendered.contains("endpoint=\"0x00\",transfer=\"control\",error=\"denied\"} 1"), "{}", rendered);
+        assert!(rendered.contains("endpoint=\"0x01\",transfer=\"bulk\",error=\"busy\"} 1"), "{}", rendered);
+        // Only the bytes of the transfer that was sent are counted.
+        assert!(rendered.contains("transfer=\"bulk\"} 4"), "{}", rendered);
+
+        Ok(())
+    }
+
+    /// Captures a session in which the guest reads the product string, and replays the capture.
+    #[tokio::test]
+    async fn strings_read_by_the_guest_are_replayed() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("usb-wasi-host-strings-{}.pcapng", std::process::id()));
+        let backend = SimulatedBackend::new();
+        backend.connect(device().with_strings("Acme", "Widget"));
+
+        let host = UsbHost::builder().backend(Arc::new(backend.clone())).capture(&path).build()?;
+        let policy = Policy { default: Action::Allow, grant: Grant::all(), rules: Vec::new() };
+        let mut state = State { table: ResourceTable::new(), usb: host.guest("simulated").policy(policy).build()? };
+
+        let WasmDeviceConnectionEvent::Connected(device) = next_event(&mut state).await? else {
+            panic!("the device was not reported as connected");
+        };
+        assert_eq!(HostUsbDevice::product_name(&mut state, device).await?, Ok(Some("Widget".to_string())));
+
+        let replay = ReplayBackend::open(&path)?;
+        let replayed = replay.devices()?;
+        assert_eq!(replayed[0].product(), Ok(Some("Widget".to_string())));
+        // The guest never read the manufacturer string.
+        assert_eq!(replayed[0].manufacturer(), Err(DeviceHandleError::Pipe));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Captures a session in which the guest reads the BOS descriptor, parsed and raw, and replays the capture.
+    #[tokio::test]
+    async fn bos_descriptor_read_by_the_guest_is_replayed() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("usb-wasi-host-bos-{}.pcapng", std::process::id()));
+        // A BOS descriptor with a USB 2.0 extension capability that supports link power management.
+        let bos = vec![5, DESCRIPTOR_BOS, 12, 0, 1, 7, 0x10, 0x02, 0x02, 0, 0, 0];
+        let mut device = device().with_bos(bos.clone());
+        device.descriptor.usb_version = Version { major: 2, minor: 1, subminor: 0 };
+
+        let backend = SimulatedBackend::new();
+        backend.connect(device);
+        let host = UsbHost::builder().backend(Arc::new(backend.clone())).capture(&path).build()?;
+        let policy = Policy { default: Action::Allow, grant: Grant::all(), rules: Vec::new() };
+        let mut state = State { table: ResourceTable::new(), usb: host.guest("simulated").policy(policy).build()? };
+
+        let WasmDeviceConnectionEvent::Connected(device) = next_event(&mut state).await? else {
+            panic!("the device was not reported as connected");
+        };
+        let parsed = HostUsbDevice::bos_descriptor(&mut state, Resource::new_borrow(device.rep())).await?;
+        assert_eq!(parsed.unwrap().unwrap().capabilities.len(), 1);
+        let raw = HostUsbDevice::get_descriptor(&mut state, device, DESCRIPTOR_BOS, 0, 0).await?;
+        assert_eq!(raw, Ok(bos.clone()));
+
+        let replay = ReplayBackend::open(&path)?;
+        let replayed = replay.devices()?;
+        assert_eq!(replayed[0].read_descriptor(DESCRIPTOR_BOS, 0, 0), Ok(bos));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
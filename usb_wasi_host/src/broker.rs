@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::sync::{mpsc, Notify};
+use tracing::info;
+
+use crate::backend::{BackendDevice, CachedDevice, HotplugRegistration, DescriptorCache, UsbBackend};
+use crate::bindings::component::usb::types::DeviceHandleError;
+use crate::device::usbdevice::USBDevice;
+use crate::events::{self, DeviceConnectionEvent};
+
+/// Identifies a guest component within the host process.
+pub type GuestId = usize;
+
+/// What happens when a guest opens a device that another guest already owns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Contention {
+    /// The first guest to open a device keeps it until that guest exits, even after closing it.
+    FirstCome,
+    /// The guest waits until the other guest closes the device.
+    Queue,
+    /// Opening the device fails with `busy` while another guest has it open.
+    #[default]
+    Deny,
+}
+
+/// Shares the devices of one backend between all guests of the host.
+/// Connection events are sent to every guest, and a device is only opened by one guest at a time.
+pub struct DeviceBroker {
+    backend: Arc<dyn UsbBackend>,
+    contention: Contention,
+    owners: Mutex<HashMap<(u8, u8), Ownership>>,
+    released: Notify,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<DeviceConnectionEvent>>>,
+    /// The descriptors read from each attached device, by bus number and address.
+    descriptors: Mutex<HashMap<(u8, u8), Arc<DescriptorCache>>>,
+    _registration: HotplugRegistration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ownership {
+    guest: GuestId,
+    /// Whether the guest has the device open. With `Contention::FirstCome`, a closed device stays reserved.
+    open: bool,
+}
+
+impl DeviceBroker {
+    pub fn new(backend: Arc<dyn UsbBackend>, contention: Contention) -> Result<Arc<Self>> {
+        let (mut receiver, registration) = events::device_connection_updates(backend.as_ref())?;
+
+        let broker = Arc::new(Self {
+            backend,
+            contention,
+            owners: Mutex::new(HashMap::new()),
+            released: Notify::new(),
+            subscribers: Mutex::new(Vec::new()),
+            descriptors: Mutex::new(HashMap::new()),
+            _registration: registration,
+        });
+
+        let weak = Arc::downgrade(&broker);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let Some(broker) = weak.upgrade() else { break };
+                broker.publish(event);
+            }
+        });
+
+        Ok(broker)
+    }
+
+    /// The devices that are currently attached.
+    pub fn devices(&self) -> Result<Vec<Box<dyn BackendDevice>>> {
+        let devices = self.backend
+            .devices()?
+            .into_iter()
+            .map(|device| Box::new(self.cached(device.into())) as Box<dyn BackendDevice>)
+            .collect();
+
+        Ok(devices)
+    }
+
+    /// Wraps the device so its strings and descriptors are read from the device only once.
+    fn cached(&self, device: Arc<dyn BackendDevice>) -> CachedDevice {
+        let key = (device.bus_number(), device.address());
+        let cache = self.descriptors.lock().unwrap().entry(key).or_default().clone();
+        CachedDevice::new(device, cache)
+    }
+
+    /// Returns a receiver for connection events. Devices that are already attached are reported as connected.
+    /// The channel is unbounded, so no event is lost while a guest is slow to take them.
+    pub fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<DeviceConnectionEvent>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for device in self.devices()? {
+            let _ = sender.send(DeviceConnectionEvent::Connected(USBDevice { device: device.into() }));
+        }
+        subscribers.push(sender);
+
+        Ok(receiver)
+    }
+
+    fn publish(&self, event: DeviceConnectionEvent) {
+        let event = match event {
+            DeviceConnectionEvent::Connected(device) => {
+                DeviceConnectionEvent::Connected(USBDevice { device: Arc::new(self.cached(device.device)) })
+            }
+            DeviceConnectionEvent::Disconnected(device) => {
+                let key = (device.device.bus_number(), device.device.address());
+                if self.owners.lock().unwrap().remove(&key).is_some() {
+                    self.released.notify_waiters();
+                }
+
+                // The address may be given to another device.
+                self.descriptors.lock().unwrap().remove(&key);
+                DeviceConnectionEvent::Disconnected(device)
+            }
+        };
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        for subscriber in subscribers.iter() {
+            let _ = subscriber.send(event.clone());
+        }
+    }
+
+    /// Makes `guest` the owner of the device before it is opened.
+    /// Fails with `access` if the guest already has the device open, and with `busy` if another guest owns it
+    /// and the contention policy does not allow waiting.
+    pub async fn acquire(&self, guest: GuestId, bus_number: u8, address: u8) -> Result<(), DeviceHandleError> {
+        loop {
+            // Registered before checking, so a release between the check and the wait is not missed.
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            {
+                let mut owners = self.owners.lock().unwrap();
+                match owners.get(&(bus_number, address)).copied() {
+                    Some(owner) if owner.guest == guest && owner.open => return Err(DeviceHandleError::Access),
+                    Some(owner) if owner.guest != guest => {
+                        if self.contention != Contention::Queue {
+                            return Err(DeviceHandleError::Busy);
+                        }
+                    }
+                    _ => {
+                        owners.insert((bus_number, address), Ownership { guest, open: true });
+                        return Ok(());
+                    }
+                }
+            }
+
+            info!(guest, bus = bus_number, address, "Waiting for the device, which is open in another guest");
+            released.await;
+        }
+    }
+
+    /// The guest that has the device open, if any.
+    pub fn owner(&self, bus_number: u8, address: u8) -> Option<GuestId> {
+        self.owners
+            .lock()
+            .unwrap()
+            .get(&(bus_number, address))
+            .filter(|owner| owner.open)
+            .map(|owner| owner.guest)
+    }
+
+    /// Called when `guest` closes the device.
+    pub fn release(&self, guest: GuestId, bus_number: u8, address: u8) {
+        let mut owners = self.owners.lock().unwrap();
+        let Some(owner) = owners.get_mut(&(bus_number, address)).filter(|o| o.guest == guest) else { return };
+
+        if self.contention == Contention::FirstCome {
+            owner.open = false;
+        } else {
+            owners.remove(&(bus_number, address));
+        }
+
+        self.released.notify_waiters();
+    }
+
+    /// Called when `guest` exits. All devices it owns become available to other guests.
+    pub fn release_guest(&self, guest: GuestId) {
+        self.owners.lock().unwrap().retain(|_, owner| owner.guest != guest);
+        self.released.notify_waiters();
+    }
+}
@@ -0,0 +1,589 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use crate::backend::{BackendDevice, BackendDeviceHandle};
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::{DeviceHandleError, Speed};
+use crate::firewall::SetupPacket;
+use crate::raw_descriptor::{self, DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE, DESCRIPTOR_STRING};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+/// Linux usbmon packets with the 64-byte header, as read from the binary usbmon interface.
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+
+const URB_SUBMIT: u8 = b'S';
+const URB_COMPLETE: u8 = b'C';
+
+const XFER_INTERRUPT: u8 = 1;
+const XFER_CONTROL: u8 = 2;
+const XFER_BULK: u8 = 3;
+
+/// Marks a setup packet or data as present in a usbmon header.
+const FLAG_PRESENT: u8 = 0;
+const FLAG_NO_SETUP: u8 = b'-';
+const FLAG_DATA_IN: u8 = b'<';
+const FLAG_DATA_OUT: u8 = b'>';
+
+const OPTION_END: u16 = 0;
+const OPTION_COMMENT: u16 = 1;
+
+/// The comment on transfers that the host writes to describe a device, rather than transfers the guest performed.
+const DESCRIPTOR_COMMENT: &str = "Descriptors of a device seen by the guest";
+
+const REQUEST_TYPE_STANDARD_DEVICE_IN: u8 = 0x80;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+
+const EINPROGRESS: i32 = 115;
+
+/// The errno usbmon reports for each error, without the sign.
+const ERRNOS: [(DeviceHandleError, i32); 14] = [
+    (DeviceHandleError::Io, 5),
+    (DeviceHandleError::InvalidParam, 22),
+    (DeviceHandleError::Access, 13),
+    (DeviceHandleError::NoDevice, 19),
+    (DeviceHandleError::NotFound, 2),
+    (DeviceHandleError::Busy, 16),
+    (DeviceHandleError::Timeout, 110),
+    (DeviceHandleError::Overflow, 75),
+    (DeviceHandleError::Pipe, 32),
+    (DeviceHandleError::Interrupted, 4),
+    (DeviceHandleError::NoMem, 12),
+    (DeviceHandleError::NotSupported, 95),
+    (DeviceHandleError::BadDescriptor, 71),
+    (DeviceHandleError::Other, 1),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum TransferKind {
+    Control(SetupPacket),
+    Bulk,
+    Interrupt,
+}
+
+impl TransferKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Control(_) => "control",
+            Self::Bulk => "bulk",
+            Self::Interrupt => "interrupt",
+        }
+    }
+}
+
+/// A transfer performed by a guest, as it is written to the capture.
+pub struct CapturedTransfer<'a> {
+    pub kind: TransferKind,
+    /// The endpoint address, including the direction bit.
+    pub endpoint: u8,
+    /// The data sent by an OUT transfer, or the buffer filled by an IN transfer.
+    pub data: &'a [u8],
+    /// The number of bytes requested.
+    pub length: usize,
+    pub result: Result<usize, DeviceHandleError>,
+    pub submitted: SystemTime,
+}
+
+/// A device given to a guest while traffic is captured. The descriptors the guest reads from it, such as its strings
+/// and its BOS descriptor, are written to the capture, so a replay answers them like the device did.
+pub(crate) struct CapturedDevice {
+    device: Arc<dyn BackendDevice>,
+    capture: Arc<Capture>,
+}
+
+impl CapturedDevice {
+    pub fn new(device: Arc<dyn BackendDevice>, capture: Arc<Capture>) -> Self {
+        Self { device, capture }
+    }
+
+    fn describe(&self, descriptor_type: u8, index: u8, language: u16, data: &[u8]) {
+        if let Err(error) = self.capture.describe_descriptor(self.device.bus_number(), self.device.address(), descriptor_type, index, language, data) {
+            tracing::warn!(?error, "Could not write to capture");
+        }
+    }
+}
+
+impl fmt::Debug for CapturedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.device.fmt(f)
+    }
+}
+
+impl BackendDevice for CapturedDevice {
+    fn bus_number(&self) -> u8 {
+        self.device.bus_number()
+    }
+
+    fn address(&self) -> u8 {
+        self.device.address()
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>, DeviceHandleError> {
+        self.device.port_numbers()
+    }
+
+    fn parent_address(&self) -> Option<u8> {
+        self.device.parent_address()
+    }
+
+    fn speed(&self) -> Speed {
+        self.device.speed()
+    }
+
+    fn device_descriptor(&self) -> Result<DeviceDescriptor, DeviceHandleError> {
+        self.device.device_descriptor()
+    }
+
+    fn configurations(&self) -> Result<Vec<ConfigurationDescriptor>, DeviceHandleError> {
+        self.device.configurations()
+    }
+
+    fn read_descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Result<Vec<u8>, DeviceHandleError> {
+        let descriptor = self.device.read_descriptor(descriptor_type, index, language)?;
+        self.describe(descriptor_type, index, language, &descriptor);
+        Ok(descriptor)
+    }
+
+    /// The languages are written as the string descriptor 0, which is how a replay reads them.
+    fn languages(&self) -> Result<Vec<u16>, DeviceHandleError> {
+        let languages = self.device.languages()?;
+        self.describe(DESCRIPTOR_STRING, 0, 0, &raw_descriptor::encode_languages(&languages));
+        Ok(languages)
+    }
+
+    fn open(&self) -> Result<Box<dyn BackendDeviceHandle>, DeviceHandleError> {
+        self.device.open()
+    }
+}
+
+/// A transfer read back from a capture.
+#[derive(Debug, Clone)]
+pub struct RecordedTransfer {
+    pub bus_number: u8,
+    pub device_address: u8,
+    pub kind: TransferKind,
+    /// The endpoint address, including the direction bit.
+    pub endpoint: u8,
+    /// The data sent by an OUT transfer, or the data returned to an IN transfer.
+    pub data: Vec<u8>,
+    pub result: Result<usize, DeviceHandleError>,
+    /// Whether the host wrote the transfer to describe the device, instead of a guest performing it.
+    pub synthetic: bool,
+}
+
+/// Writes the USB traffic of guests to a pcapng file that Wireshark can decode.
+/// Every transfer is written as a usbmon submission followed by its completion.
+///
+/// The first time a guest sees a device, its device and configuration descriptors are written
+/// as GET_DESCRIPTOR requests, so Wireshark can decode class-specific traffic
+/// and the capture can be replayed without the device. So are the other descriptors the guest reads, such as strings.
+pub struct Capture {
+    writer: Mutex<BufWriter<File>>,
+    next_id: AtomicU64,
+    described: Mutex<HashSet<(u8, u8)>>,
+    /// The descriptors read by guests that were written, by bus number, address, type, index and language.
+    descriptors: Mutex<HashSet<(u8, u8, u8, u8, u16)>>,
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        // Section header block. A section length of -1 means the length is not specified.
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, BLOCK_SECTION_HEADER, &body)?;
+
+        // Interface description block, with a snapshot length of 0 meaning no limit.
+        // The default timestamp resolution of microseconds is used.
+        let mut body = Vec::with_capacity(8);
+        body.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut writer, BLOCK_INTERFACE_DESCRIPTION, &body)?;
+
+        writer.flush()?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            next_id: AtomicU64::new(1),
+            described: Mutex::new(HashSet::new()),
+            descriptors: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn record(&self, bus_number: u8, device_address: u8, transfer: &CapturedTransfer) -> Result<()> {
+        self.write_transfer(bus_number, device_address, transfer, None)
+    }
+
+    /// Writes the descriptors of a device, unless they were already written.
+    pub fn describe_device(&self, bus_number: u8, device_address: u8, descriptor: &DeviceDescriptor, configurations: &[ConfigurationDescriptor]) -> Result<()> {
+        if !self.described.lock().unwrap().insert((bus_number, device_address)) {
+            return Ok(());
+        }
+
+        let descriptors = std::iter::once((DESCRIPTOR_DEVICE, 0, raw_descriptor::encode_device_descriptor(descriptor)))
+            .chain(configurations.iter().enumerate().map(|(index, configuration)| {
+                (DESCRIPTOR_CONFIGURATION, index as u8, raw_descriptor::encode_configuration_descriptor(configuration))
+            }));
+
+        for (descriptor_type, index, data) in descriptors {
+            self.write_descriptor(bus_number, device_address, descriptor_type, index, 0, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a descriptor that a guest read from a device, unless it was already written.
+    /// `language` is the language id of a string descriptor, and 0 for other descriptors.
+    pub fn describe_descriptor(&self, bus_number: u8, device_address: u8, descriptor_type: u8, index: u8, language: u16, data: &[u8]) -> Result<()> {
+        if !self.descriptors.lock().unwrap().insert((bus_number, device_address, descriptor_type, index, language)) {
+            return Ok(());
+        }
+
+        self.write_descriptor(bus_number, device_address, descriptor_type, index, language, data)
+    }
+
+    /// Writes a descriptor as a GET_DESCRIPTOR request, marked as written by the host.
+    fn write_descriptor(&self, bus_number: u8, device_address: u8, descriptor_type: u8, index: u8, language: u16, data: &[u8]) -> Result<()> {
+        let setup = SetupPacket {
+            request_type: REQUEST_TYPE_STANDARD_DEVICE_IN,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (u16::from(descriptor_type) << 8) | u16::from(index),
+            index: language,
+            length: data.len() as u16,
+        };
+
+        let transfer = CapturedTransfer {
+            kind: TransferKind::Control(setup),
+            endpoint: 0x80,
+            data,
+            length: data.len(),
+            result: Ok(data.len()),
+            submitted: SystemTime::now(),
+        };
+
+        self.write_transfer(bus_number, device_address, &transfer, Some(DESCRIPTOR_COMMENT))
+    }
+
+    fn write_transfer(&self, bus_number: u8, device_address: u8, transfer: &CapturedTransfer, comment: Option<&str>) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let is_in = transfer.endpoint & 0x80 != 0;
+
+        let submit_data = if is_in { &[][..] } else { transfer.data };
+        let submit = UsbmonHeader {
+            id,
+            kind: URB_SUBMIT,
+            bus_number,
+            device_address,
+            transfer,
+            timestamp: transfer.submitted,
+            status: -EINPROGRESS,
+            length: transfer.length,
+            flag_data: if is_in { FLAG_DATA_IN } else { FLAG_PRESENT },
+            setup: match transfer.kind {
+                TransferKind::Control(setup) => Some(setup),
+                _ => None,
+            },
+        };
+
+        let complete_data = match (is_in, transfer.result) {
+            (true, Ok(count)) => &transfer.data[..count.min(transfer.data.len())],
+            _ => &[][..],
+        };
+        let complete = UsbmonHeader {
+            id,
+            kind: URB_COMPLETE,
+            bus_number,
+            device_address,
+            transfer,
+            timestamp: SystemTime::now(),
+            status: transfer.result.err().map_or(0, errno),
+            length: transfer.result.unwrap_or(0),
+            flag_data: if is_in { FLAG_PRESENT } else { FLAG_DATA_OUT },
+            setup: None,
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        write_packet(&mut *writer, &submit, submit_data, comment)?;
+        write_packet(&mut *writer, &complete, complete_data, comment)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+struct UsbmonHeader<'a> {
+    id: u64,
+    kind: u8,
+    bus_number: u8,
+    device_address: u8,
+    transfer: &'a CapturedTransfer<'a>,
+    timestamp: SystemTime,
+    status: i32,
+    length: usize,
+    flag_data: u8,
+    setup: Option<SetupPacket>,
+}
+
+impl UsbmonHeader<'_> {
+    fn to_bytes(&self, captured: usize) -> Vec<u8> {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let transfer_type = match self.transfer.kind {
+            TransferKind::Control(_) => XFER_CONTROL,
+            TransferKind::Bulk => XFER_BULK,
+            TransferKind::Interrupt => XFER_INTERRUPT,
+        };
+
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        bytes.push(self.kind);
+        bytes.push(transfer_type);
+        bytes.push(self.transfer.endpoint);
+        bytes.push(self.device_address);
+        bytes.extend_from_slice(&u16::from(self.bus_number).to_le_bytes());
+        bytes.push(if self.setup.is_some() { FLAG_PRESENT } else { FLAG_NO_SETUP });
+        bytes.push(if captured > 0 { FLAG_PRESENT } else { self.flag_data });
+        bytes.extend_from_slice(&(since_epoch.as_secs() as i64).to_le_bytes());
+        bytes.extend_from_slice(&(since_epoch.subsec_micros() as i32).to_le_bytes());
+        bytes.extend_from_slice(&self.status.to_le_bytes());
+        bytes.extend_from_slice(&(self.length as u32).to_le_bytes());
+        bytes.extend_from_slice(&(captured as u32).to_le_bytes());
+
+        match self.setup {
+            Some(setup) => {
+                bytes.push(setup.request_type);
+                bytes.push(setup.request);
+                bytes.extend_from_slice(&setup.value.to_le_bytes());
+                bytes.extend_from_slice(&setup.index.to_le_bytes());
+                bytes.extend_from_slice(&setup.length.to_le_bytes());
+            }
+            None => bytes.extend_from_slice(&[0; 8]),
+        }
+
+        // Interval, start frame, transfer flags and number of isochronous descriptors.
+        bytes.extend_from_slice(&[0; 16]);
+
+        bytes
+    }
+}
+
+/// Maps an error to the negative errno that usbmon reports as URB status.
+fn errno(error: DeviceHandleError) -> i32 {
+    let errno = ERRNOS.iter().find(|(e, _)| *e == error).map_or(1, |(_, errno)| *errno);
+    -errno
+}
+
+/// Maps a URB status back to an error. Statuses that the host never writes become `other`.
+fn error_from_status(status: i32) -> DeviceHandleError {
+    ERRNOS
+        .iter()
+        .find(|(_, errno)| -errno == status)
+        .map_or(DeviceHandleError::Other, |(error, _)| *error)
+}
+
+fn write_packet(writer: &mut impl Write, header: &UsbmonHeader, data: &[u8], comment: Option<&str>) -> Result<()> {
+    let mut packet = header.to_bytes(data.len());
+    packet.extend_from_slice(data);
+
+    let micros = header.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+    let mut body = Vec::with_capacity(20 + packet.len() + 3);
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&packet);
+    body.resize(body.len().next_multiple_of(4), 0);
+
+    if let Some(comment) = comment {
+        body.extend_from_slice(&OPTION_COMMENT.to_le_bytes());
+        body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+        body.resize(body.len().next_multiple_of(4), 0);
+        body.extend_from_slice(&OPTION_END.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+    }
+
+    write_block(writer, BLOCK_ENHANCED_PACKET, &body)
+}
+
+/// Writes a pcapng block. The body must already be padded to a multiple of 4 bytes.
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> Result<()> {
+    let total_length = (body.len() + 12) as u32;
+
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_length.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_length.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Reads the transfers from a capture written by `Capture`, or from any little-endian pcapng file
+/// with usbmon packets. Submissions are matched with their completions, and isochronous transfers are skipped.
+pub fn read_transfers(path: &Path) -> Result<Vec<RecordedTransfer>> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read capture {}", path.display()))?;
+
+    let mut link_types = Vec::new();
+    let mut submissions = HashMap::new();
+    let mut transfers = Vec::new();
+
+    let mut offset = 0;
+    while offset + 12 <= bytes.len() {
+        let block_type = read_u32(&bytes, offset);
+        let total_length = read_u32(&bytes, offset + 4) as usize;
+        if total_length < 12 || offset + total_length > bytes.len() {
+            bail!("Truncated pcapng block at offset {}", offset);
+        }
+
+        let body = &bytes[offset + 8..offset + total_length - 4];
+        offset += total_length;
+
+        match block_type {
+            BLOCK_SECTION_HEADER => {
+                if body.len() < 4 || read_u32(body, 0) != BYTE_ORDER_MAGIC {
+                    bail!("Only little-endian pcapng captures are supported");
+                }
+                link_types.clear();
+            }
+            BLOCK_INTERFACE_DESCRIPTION if body.len() >= 2 => {
+                link_types.push(u16::from_le_bytes([body[0], body[1]]));
+            }
+            BLOCK_ENHANCED_PACKET if body.len() >= 20 => {
+                let interface = read_u32(body, 0) as usize;
+                if link_types.get(interface) != Some(&LINKTYPE_USB_LINUX_MMAPPED) {
+                    continue;
+                }
+
+                let captured = (read_u32(body, 12) as usize).min(body.len() - 20);
+                let packet = &body[20..20 + captured];
+                let comment = packet_comment(&body[(20 + captured).next_multiple_of(4).min(body.len())..]);
+
+                let Some(packet) = UsbmonPacket::parse(packet) else { continue };
+                match packet.kind {
+                    URB_SUBMIT => {
+                        submissions.insert(packet.id, (packet, comment));
+                    }
+                    URB_COMPLETE => {
+                        let Some((submit, comment)) = submissions.remove(&packet.id) else { continue };
+                        if let Some(transfer) = RecordedTransfer::new(submit, packet, comment.as_deref() == Some(DESCRIPTOR_COMMENT)) {
+                            transfers.push(transfer);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(transfers)
+}
+
+/// The fields of a usbmon packet that are needed to replay it.
+struct UsbmonPacket<'a> {
+    id: u64,
+    kind: u8,
+    transfer_type: u8,
+    endpoint: u8,
+    device_address: u8,
+    bus_number: u8,
+    status: i32,
+    length: usize,
+    setup: Option<SetupPacket>,
+    data: &'a [u8],
+}
+
+impl<'a> UsbmonPacket<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < 64 {
+            return None;
+        }
+
+        let setup = (bytes[14] == FLAG_PRESENT).then(|| SetupPacket {
+            request_type: bytes[40],
+            request: bytes[41],
+            value: u16::from_le_bytes([bytes[42], bytes[43]]),
+            index: u16::from_le_bytes([bytes[44], bytes[45]]),
+            length: u16::from_le_bytes([bytes[46], bytes[47]]),
+        });
+
+        Some(Self {
+            id: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+            kind: bytes[8],
+            transfer_type: bytes[9],
+            endpoint: bytes[10],
+            device_address: bytes[11],
+            bus_number: bytes[12],
+            status: read_u32(bytes, 28) as i32,
+            length: read_u32(bytes, 32) as usize,
+            setup,
+            data: &bytes[64..],
+        })
+    }
+}
+
+impl RecordedTransfer {
+    fn new(submit: UsbmonPacket, complete: UsbmonPacket, synthetic: bool) -> Option<Self> {
+        let kind = match submit.transfer_type {
+            XFER_CONTROL => TransferKind::Control(submit.setup?),
+            XFER_BULK => TransferKind::Bulk,
+            XFER_INTERRUPT => TransferKind::Interrupt,
+            _ => return None,
+        };
+
+        let is_in = submit.endpoint & 0x80 != 0;
+
+        Some(Self {
+            bus_number: submit.bus_number,
+            device_address: submit.device_address,
+            kind,
+            endpoint: submit.endpoint,
+            data: if is_in { complete.data } else { submit.data }.to_vec(),
+            result: match complete.status {
+                0 => Ok(complete.length),
+                status => Err(error_from_status(status)),
+            },
+            synthetic,
+        })
+    }
+}
+
+/// Returns the first comment in the options of an enhanced packet block.
+fn packet_comment(mut options: &[u8]) -> Option<String> {
+    while options.len() >= 4 {
+        let code = u16::from_le_bytes([options[0], options[1]]);
+        let length = usize::from(u16::from_le_bytes([options[2], options[3]]));
+        let value = options.get(4..4 + length)?;
+
+        match code {
+            OPTION_END => return None,
+            OPTION_COMMENT => return Some(String::from_utf8_lossy(value).into_owned()),
+            _ => options = options.get((4 + length).next_multiple_of(4)..)?,
+        }
+    }
+
+    None
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
@@ -0,0 +1,401 @@
+//! Serves components built against `component:usb@0.2.0`, such as the examples, next to the current version.
+//!
+//! 0.3.0 groups the alternate settings of an interface, lists the interface associations of a configuration and
+//! adds the extra descriptors of interfaces and endpoints. Every 0.2.0 function forwards to its 0.3.0 implementation,
+//! and the records are converted to their 0.2.0 shape: alternate settings are flattened into one list of interfaces
+//! and the other additions are left out.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use wasmtime::component::Resource;
+
+use crate::bindings::component::usb as current;
+use crate::ctx::UsbView;
+use crate::device::devicehandle::DeviceHandle;
+use crate::device::usbdevice::USBDevice;
+
+pub mod bindings {
+    wasmtime::component::bindgen!({
+        world: "component:usb/driver",
+        async: true,
+        with: {
+            "component:usb/usb/usb-device": crate::device::usbdevice::USBDevice,
+            "component:usb/usb/device-handle": crate::device::devicehandle::DeviceHandle,
+        },
+        path: "wit-0.2.0"
+    });
+}
+
+use bindings::component::usb::{descriptors, events, types, usb};
+
+/// The interface that a 0.2.0 driver exports.
+pub const HOTPLUG_INTERFACE: &str = "component:usb/hotplug@0.2.0";
+
+type DeviceResult<T> = Result<Result<T, types::DeviceHandleError>>;
+
+/// Converts the result of a 0.3.0 function into the result of its 0.2.0 counterpart.
+fn convert<T, U: From<T>>(result: Result<Result<T, current::types::DeviceHandleError>>) -> DeviceResult<U> {
+    Ok(result?.map(U::from).map_err(types::DeviceHandleError::from))
+}
+
+impl<T: UsbView> usb::Host for T {}
+impl<T: UsbView> descriptors::Host for T {}
+impl<T: UsbView> types::Host for T {}
+
+#[async_trait]
+impl<T: UsbView> events::Host for T {
+    async fn update(&mut self) -> Result<events::DeviceConnectionEvent> {
+        Ok(current::events::Host::update(self).await?.into())
+    }
+}
+
+#[async_trait]
+impl<T: UsbView> usb::HostUsbDevice for T {
+    fn drop(&mut self, rep: Resource<USBDevice>) -> Result<()> {
+        current::usb::HostUsbDevice::drop(self, rep)
+    }
+
+    async fn configurations(&mut self, device: Resource<USBDevice>) -> DeviceResult<Vec<descriptors::ConfigurationDescriptor>> {
+        let configurations = current::usb::HostUsbDevice::configurations(self, device).await?;
+        Ok(configurations
+            .map(|configurations| configurations.into_iter().map(Into::into).collect())
+            .map_err(Into::into))
+    }
+
+    async fn device_descriptor(&mut self, device: Resource<USBDevice>) -> Result<descriptors::DeviceDescriptor> {
+        Ok(current::usb::HostUsbDevice::device_descriptor(self, device).await?.into())
+    }
+
+    async fn get_descriptor(&mut self, device: Resource<USBDevice>, descriptor_type: u8, index: u8, language: u16) -> DeviceResult<Vec<u8>> {
+        convert(current::usb::HostUsbDevice::get_descriptor(self, device, descriptor_type, index, language).await)
+    }
+
+    async fn raw_configuration_descriptor(&mut self, device: Resource<USBDevice>, index: u8) -> DeviceResult<Vec<u8>> {
+        convert(current::usb::HostUsbDevice::raw_configuration_descriptor(self, device, index).await)
+    }
+
+    async fn bos_descriptor(&mut self, device: Resource<USBDevice>) -> DeviceResult<Option<descriptors::BosDescriptor>> {
+        let bos = current::usb::HostUsbDevice::bos_descriptor(self, device).await?;
+        Ok(bos.map(|bos| bos.map(Into::into)).map_err(Into::into))
+    }
+
+    async fn languages(&mut self, device: Resource<USBDevice>) -> DeviceResult<Vec<u16>> {
+        convert(current::usb::HostUsbDevice::languages(self, device).await)
+    }
+
+    async fn read_string(&mut self, device: Resource<USBDevice>, index: u8, language: Option<u16>) -> DeviceResult<String> {
+        convert(current::usb::HostUsbDevice::read_string(self, device, index, language).await)
+    }
+
+    async fn manufacturer_name(&mut self, device: Resource<USBDevice>) -> DeviceResult<Option<String>> {
+        convert(current::usb::HostUsbDevice::manufacturer_name(self, device).await)
+    }
+
+    async fn product_name(&mut self, device: Resource<USBDevice>) -> DeviceResult<Option<String>> {
+        convert(current::usb::HostUsbDevice::product_name(self, device).await)
+    }
+
+    async fn serial_number(&mut self, device: Resource<USBDevice>) -> DeviceResult<Option<String>> {
+        convert(current::usb::HostUsbDevice::serial_number(self, device).await)
+    }
+
+    async fn open(&mut self, device: Resource<USBDevice>) -> DeviceResult<Resource<DeviceHandle>> {
+        convert(current::usb::HostUsbDevice::open(self, device).await)
+    }
+
+    async fn enumerate(&mut self) -> Result<Vec<Resource<USBDevice>>> {
+        current::usb::HostUsbDevice::enumerate(self).await
+    }
+
+    async fn request_device(&mut self, filter: usb::DeviceFilter) -> Result<Vec<Resource<USBDevice>>> {
+        current::usb::HostUsbDevice::request_device(self, filter.into()).await
+    }
+
+    async fn inherited(&mut self) -> Result<Vec<usb::InheritedHandle>> {
+        let inherited = current::usb::HostUsbDevice::inherited(self).await?;
+        Ok(inherited.into_iter().map(Into::into).collect())
+    }
+}
+
+#[async_trait]
+impl<T: UsbView> usb::HostDeviceHandle for T {
+    fn drop(&mut self, rep: Resource<DeviceHandle>) -> Result<()> {
+        current::usb::HostDeviceHandle::drop(self, rep)
+    }
+
+    async fn reset(&mut self, handle: Resource<DeviceHandle>) -> DeviceResult<()> {
+        convert(current::usb::HostDeviceHandle::reset(self, handle).await)
+    }
+
+    async fn active_configuration(&mut self, handle: Resource<DeviceHandle>) -> DeviceResult<u8> {
+        convert(current::usb::HostDeviceHandle::active_configuration(self, handle).await)
+    }
+
+    async fn select_configuration(&mut self, handle: Resource<DeviceHandle>, configuration: u8) -> DeviceResult<()> {
+        convert(current::usb::HostDeviceHandle::select_configuration(self, handle, configuration).await)
+    }
+
+    async fn claim_interface(&mut self, handle: Resource<DeviceHandle>, interface: u8) -> DeviceResult<()> {
+        convert(current::usb::HostDeviceHandle::claim_interface(self, handle, interface).await)
+    }
+
+    async fn release_interface(&mut self, handle: Resource<DeviceHandle>, interface: u8) -> Result<()> {
+        current::usb::HostDeviceHandle::release_interface(self, handle, interface).await
+    }
+
+    async fn select_alternate_interface(&mut self, handle: Resource<DeviceHandle>, interface: u8, setting: u8) -> DeviceResult<()> {
+        convert(current::usb::HostDeviceHandle::select_alternate_interface(self, handle, interface, setting).await)
+    }
+
+    async fn read_interrupt(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, timeout: u64) -> DeviceResult<(u64, Vec<u8>)> {
+        convert(current::usb::HostDeviceHandle::read_interrupt(self, handle, endpoint, timeout).await)
+    }
+
+    async fn write_interrupt(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, data: Vec<u8>, timeout: u64) -> DeviceResult<u64> {
+        convert(current::usb::HostDeviceHandle::write_interrupt(self, handle, endpoint, data, timeout).await)
+    }
+
+    async fn read_bulk(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, max_size: u64, timeout: u64) -> DeviceResult<(u64, Vec<u8>)> {
+        convert(current::usb::HostDeviceHandle::read_bulk(self, handle, endpoint, max_size, timeout).await)
+    }
+
+    async fn write_bulk(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, data: Vec<u8>, timeout: u64) -> DeviceResult<u64> {
+        convert(current::usb::HostDeviceHandle::write_bulk(self, handle, endpoint, data, timeout).await)
+    }
+
+    async fn read_isochronous(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, timeout: u64) -> DeviceResult<(u64, Vec<u8>)> {
+        convert(current::usb::HostDeviceHandle::read_isochronous(self, handle, endpoint, timeout).await)
+    }
+
+    async fn write_isochronous(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, data: Vec<u8>, timeout: u64) -> DeviceResult<u64> {
+        convert(current::usb::HostDeviceHandle::write_isochronous(self, handle, endpoint, data, timeout).await)
+    }
+
+    async fn read_control(&mut self, handle: Resource<DeviceHandle>, request_type: u8, request: u8, value: u16, index: u16, max_size: u16, timeout: u64) -> DeviceResult<(u64, Vec<u8>)> {
+        convert(current::usb::HostDeviceHandle::read_control(self, handle, request_type, request, value, index, max_size, timeout).await)
+    }
+
+    async fn write_control(&mut self, handle: Resource<DeviceHandle>, request_type: u8, request: u8, value: u16, index: u16, buf: Vec<u8>, timeout: u64) -> DeviceResult<u64> {
+        convert(current::usb::HostDeviceHandle::write_control(self, handle, request_type, request, value, index, buf, timeout).await)
+    }
+}
+
+impl From<current::events::DeviceConnectionEvent> for events::DeviceConnectionEvent {
+    fn from(event: current::events::DeviceConnectionEvent) -> Self {
+        match event {
+            current::events::DeviceConnectionEvent::Pending => Self::Pending,
+            current::events::DeviceConnectionEvent::Connected(device) => Self::Connected(device),
+            current::events::DeviceConnectionEvent::Disconnected(device) => Self::Disconnected(device),
+        }
+    }
+}
+
+impl From<usb::DeviceFilter> for current::usb::DeviceFilter {
+    fn from(filter: usb::DeviceFilter) -> Self {
+        Self {
+            vendor_id: filter.vendor_id,
+            product_id: filter.product_id,
+            class_code: filter.class_code,
+            subclass_code: filter.subclass_code,
+            protocol_code: filter.protocol_code,
+            interface_class: filter.interface_class,
+            interface_subclass: filter.interface_subclass,
+            interface_protocol: filter.interface_protocol,
+            serial_number: filter.serial_number,
+        }
+    }
+}
+
+impl From<current::usb::InheritedHandle> for usb::InheritedHandle {
+    fn from(inherited: current::usb::InheritedHandle) -> Self {
+        Self {
+            device: inherited.device,
+            handle: inherited.handle,
+            claimed_interfaces: inherited.claimed_interfaces,
+        }
+    }
+}
+
+impl From<current::descriptors::ConfigurationDescriptor> for descriptors::ConfigurationDescriptor {
+    fn from(configuration: current::descriptors::ConfigurationDescriptor) -> Self {
+        Self {
+            max_power: configuration.max_power,
+            number: configuration.number,
+            interfaces: configuration
+                .interfaces
+                .into_iter()
+                .flat_map(|interface| interface.alternate_settings)
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+impl From<current::descriptors::InterfaceDescriptor> for descriptors::InterfaceDescriptor {
+    fn from(interface: current::descriptors::InterfaceDescriptor) -> Self {
+        Self {
+            number: interface.number,
+            alternate_setting: interface.alternate_setting,
+            class_code: interface.class_code,
+            subclass_code: interface.subclass_code,
+            protocol: interface.protocol,
+            interface_string_index: interface.interface_string_index,
+            endpoint_descriptors: interface.endpoint_descriptors.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<current::descriptors::EndpointDescriptor> for descriptors::EndpointDescriptor {
+    fn from(endpoint: current::descriptors::EndpointDescriptor) -> Self {
+        Self {
+            address: endpoint.address,
+            direction: endpoint.direction.into(),
+            interval: endpoint.interval,
+            max_packet_size: endpoint.max_packet_size,
+            number: endpoint.number,
+            refresh: endpoint.refresh,
+            sync_type: endpoint.sync_type.into(),
+            synch_address: endpoint.synch_address,
+            transfer_type: endpoint.transfer_type.into(),
+            usage_type: endpoint.usage_type.into(),
+        }
+    }
+}
+
+impl From<current::descriptors::DeviceDescriptor> for descriptors::DeviceDescriptor {
+    fn from(descriptor: current::descriptors::DeviceDescriptor) -> Self {
+        Self {
+            device_class: descriptor.device_class,
+            device_protocol: descriptor.device_protocol,
+            device_subclass: descriptor.device_subclass,
+            device_version: descriptor.device_version.into(),
+            product_id: descriptor.product_id,
+            usb_version: descriptor.usb_version.into(),
+            vendor_id: descriptor.vendor_id,
+            max_packet_size: descriptor.max_packet_size,
+            manufacturer_string_index: descriptor.manufacturer_string_index,
+            product_string_index: descriptor.product_string_index,
+            serial_number_string_index: descriptor.serial_number_string_index,
+            num_configurations: descriptor.num_configurations,
+        }
+    }
+}
+
+impl From<current::descriptors::BosDescriptor> for descriptors::BosDescriptor {
+    fn from(bos: current::descriptors::BosDescriptor) -> Self {
+        Self { capabilities: bos.capabilities.into_iter().map(Into::into).collect() }
+    }
+}
+
+impl From<current::descriptors::DeviceCapability> for descriptors::DeviceCapability {
+    fn from(capability: current::descriptors::DeviceCapability) -> Self {
+        use current::descriptors::DeviceCapability as Current;
+
+        match capability {
+            Current::Usb2Extension(c) => Self::Usb2Extension(descriptors::Usb2ExtensionCapability {
+                attributes: c.attributes,
+                lpm_supported: c.lpm_supported,
+            }),
+            Current::Superspeed(c) => Self::Superspeed(descriptors::SuperspeedCapability {
+                attributes: c.attributes,
+                speeds_supported: c.speeds_supported,
+                functionality_support: c.functionality_support,
+                u1_exit_latency: c.u1_exit_latency,
+                u2_exit_latency: c.u2_exit_latency,
+            }),
+            Current::ContainerId(uuid) => Self::ContainerId(uuid),
+            Current::Webusb(c) => Self::Webusb(descriptors::WebusbCapability {
+                version: c.version.into(),
+                vendor_code: c.vendor_code,
+                landing_page: c.landing_page,
+            }),
+            Current::MicrosoftOs20(sets) => Self::MicrosoftOs20(
+                sets.into_iter()
+                    .map(|set| descriptors::MicrosoftOs20DescriptorSet {
+                        windows_version: set.windows_version,
+                        total_length: set.total_length,
+                        vendor_code: set.vendor_code,
+                        alt_enum_code: set.alt_enum_code,
+                    })
+                    .collect(),
+            ),
+            Current::Platform(c) => Self::Platform(descriptors::PlatformCapability { uuid: c.uuid, data: c.data }),
+            Current::Other(c) => Self::Other(descriptors::UnknownCapability { capability_type: c.capability_type, data: c.data }),
+        }
+    }
+}
+
+impl From<current::types::Version> for types::Version {
+    fn from(version: current::types::Version) -> Self {
+        Self {
+            major: version.major,
+            minor: version.minor,
+            subminor: version.subminor,
+        }
+    }
+}
+
+impl From<current::types::Direction> for types::Direction {
+    fn from(direction: current::types::Direction) -> Self {
+        match direction {
+            current::types::Direction::In => Self::In,
+            current::types::Direction::Out => Self::Out,
+        }
+    }
+}
+
+impl From<current::types::SyncType> for types::SyncType {
+    fn from(sync_type: current::types::SyncType) -> Self {
+        match sync_type {
+            current::types::SyncType::NoSync => Self::NoSync,
+            current::types::SyncType::Asynchronous => Self::Asynchronous,
+            current::types::SyncType::Adaptive => Self::Adaptive,
+            current::types::SyncType::Synchronous => Self::Synchronous,
+        }
+    }
+}
+
+impl From<current::types::UsageType> for types::UsageType {
+    fn from(usage_type: current::types::UsageType) -> Self {
+        match usage_type {
+            current::types::UsageType::Data => Self::Data,
+            current::types::UsageType::Feedback => Self::Feedback,
+            current::types::UsageType::FeedbackData => Self::FeedbackData,
+            current::types::UsageType::Reserved => Self::Reserved,
+        }
+    }
+}
+
+impl From<current::types::TransferType> for types::TransferType {
+    fn from(transfer_type: current::types::TransferType) -> Self {
+        match transfer_type {
+            current::types::TransferType::Control => Self::Control,
+            current::types::TransferType::Isochronous => Self::Isochronous,
+            current::types::TransferType::Bulk => Self::Bulk,
+            current::types::TransferType::Interrupt => Self::Interrupt,
+        }
+    }
+}
+
+impl From<current::types::DeviceHandleError> for types::DeviceHandleError {
+    fn from(error: current::types::DeviceHandleError) -> Self {
+        use current::types::DeviceHandleError as Current;
+
+        match error {
+            Current::Io => Self::Io,
+            Current::InvalidParam => Self::InvalidParam,
+            Current::Access => Self::Access,
+            Current::NoDevice => Self::NoDevice,
+            Current::NotFound => Self::NotFound,
+            Current::Busy => Self::Busy,
+            Current::Timeout => Self::Timeout,
+            Current::Overflow => Self::Overflow,
+            Current::Pipe => Self::Pipe,
+            Current::Interrupted => Self::Interrupted,
+            Current::NoMem => Self::NoMem,
+            Current::NotSupported => Self::NotSupported,
+            Current::BadDescriptor => Self::BadDescriptor,
+            Current::Other => Self::Other,
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use wasmtime::component::Component;
+use wasmtime::{Engine, Precompiled};
+use usb_wasi_host::hex;
+
+/// Compiles a component ahead of time into a `.cwasm` file that the host loads without compiling it again.
+pub fn compile(engine: &Engine, component: &Path, output: &Path) -> Result<()> {
+    let wasm = fs::read(component)
+        .with_context(|| format!("Could not read component {}", component.display()))?;
+
+    let compiled = engine.precompile_component(&wasm)?;
+    fs::write(output, compiled)
+        .with_context(|| format!("Could not write {}", output.display()))?;
+
+    println!("Compiled {} to {}", component.display(), output.display());
+    Ok(())
+}
+
+/// Loads a component, a `.cwasm` file made by `compile`, or a component from the cache.
+/// Components that are not in the cache yet are compiled and added to it.
+pub fn load(engine: &Engine, path: &Path, cache_dir: Option<&Path>) -> Result<Component> {
+    match Engine::detect_precompiled_file(path)? {
+        // Deserializing runs the code in the file, so only files made by `compile` should be passed to the host.
+        // Wasmtime refuses files compiled by another version or with another configuration.
+        Some(Precompiled::Component) => {
+            let component = unsafe { Component::deserialize_file(engine, path) };
+            return component.with_context(|| format!("Could not load precompiled component {}, compile it again", path.display()));
+        }
+        Some(Precompiled::Module) => bail!("{} is a precompiled core module, not a component", path.display()),
+        None => {}
+    }
+
+    let wasm = fs::read(path)
+        .with_context(|| format!("Could not read component {}", path.display()))?;
+
+    let Some(cache_dir) = cache_dir else {
+        return Component::new(engine, &wasm);
+    };
+
+    let cached = cache_dir.join(cache_key(engine, &wasm)).with_extension("cwasm");
+    if cached.exists() {
+        // The cache only contains files written below, for this engine configuration.
+        match unsafe { Component::deserialize_file(engine, &cached) } {
+            Ok(component) => return Ok(component),
+            Err(e) => tracing::warn!("Ignoring cached component {}: {:?}", cached.display(), e),
+        }
+    }
+
+    let compiled = engine.precompile_component(&wasm)?;
+    if let Err(e) = write_cache(&cached, &compiled) {
+        tracing::warn!("Could not cache compiled component {}: {:?}", path.display(), e);
+    }
+
+    unsafe { Component::deserialize(engine, &compiled) }
+}
+
+/// `$XDG_CACHE_HOME/usb-wasi-host`, or `~/.cache/usb-wasi-host`.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+
+    Some(cache.join("usb-wasi-host"))
+}
+
+/// The SHA-256 of the component, followed by the SHA-256 of everything that makes compiled code incompatible:
+/// the Wasmtime version, the target and the engine configuration. SHA-256 keeps the key stable across Rust releases,
+/// unlike the hashers of the standard library.
+fn cache_key(engine: &Engine, wasm: &[u8]) -> String {
+    let mut compatibility = Sha256::new();
+    engine.precompile_compatibility_hash().hash(&mut Sha256Hasher(&mut compatibility));
+
+    format!("{}-{}", hex(&Sha256::digest(wasm)), hex(&compatibility.finalize()))
+}
+
+/// Feeds the data `Hash` implementations write into a SHA-256 digest.
+struct Sha256Hasher<'a>(&'a mut Sha256);
+
+impl Hasher for Sha256Hasher<'_> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        // Only the digest is used.
+        0
+    }
+}
+
+/// Writes to a temporary file first, so a host that runs at the same time never loads a partial file.
+fn write_cache(path: &Path, compiled: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let partial = path.with_extension(format!("{}.partial", std::process::id()));
+    fs::write(&partial, compiled)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
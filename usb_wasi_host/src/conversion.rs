@@ -1,7 +1,11 @@
-use crate::bindings::component::usb::{descriptors::{EndpointDescriptor, DeviceDescriptor}, types::{
-    DeviceHandleError, Direction, SyncType, TransferType, UsageType, Version
+use crate::bindings::component::usb::{descriptors::{
+    ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, InterfaceAssociation, InterfaceDescriptor, RawDescriptor, UsbInterface
+}, types::{
+    DeviceHandleError, Direction, Speed, SyncType, TransferType, UsageType, Version
 }};
 
+use crate::raw_descriptor;
+
 impl From<rusb::Version> for Version {
     fn from(a: rusb::Version) -> Self {
         Version {
@@ -12,6 +16,20 @@ impl From<rusb::Version> for Version {
     }
 }
 
+impl From<rusb::Speed> for Speed {
+    fn from(speed: rusb::Speed) -> Self {
+        match speed {
+            rusb::Speed::Low => Self::Low,
+            rusb::Speed::Full => Self::Full,
+            rusb::Speed::High => Self::High,
+            rusb::Speed::Super => Self::Super,
+            rusb::Speed::SuperPlus => Self::SuperPlus,
+            // Also covers speeds that newer versions of libusb add.
+            _ => Self::Unknown,
+        }
+    }
+}
+
 impl From<rusb::Direction> for Direction {
     fn from(b: rusb::Direction) -> Self {
         match b {
@@ -56,6 +74,7 @@ impl From<rusb::TransferType> for TransferType {
 
 impl From<rusb::EndpointDescriptor<'_>> for EndpointDescriptor {
     fn from(ed: rusb::EndpointDescriptor) -> Self {
+        let transfer_type = ed.transfer_type().into();
         Self {
             address: ed.address(),
             direction: ed.direction().into(),
@@ -65,12 +84,73 @@ impl From<rusb::EndpointDescriptor<'_>> for EndpointDescriptor {
             refresh: ed.refresh(),
             sync_type: ed.sync_type().into(),
             synch_address: ed.synch_address(),
-            transfer_type: ed.transfer_type().into(),
+            transfer_type,
             usage_type: ed.usage_type().into(),
+            extra_descriptors: ed
+                .extra()
+                .map(|extra| raw_descriptor::descriptors(extra).map(|d| raw_descriptor::parse_endpoint_extra(d, transfer_type)).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<rusb::ConfigDescriptor> for ConfigurationDescriptor {
+    fn from(config: rusb::ConfigDescriptor) -> Self {
+        let mut interfaces: Vec<UsbInterface> = config
+            .interfaces()
+            .map(|interface| UsbInterface {
+                number: interface.number(),
+                alternate_settings: interface
+                    .descriptors()
+                    .map(|descriptor| InterfaceDescriptor {
+                        number: descriptor.interface_number(),
+                        alternate_setting: descriptor.setting_number(),
+                        class_code: descriptor.class_code(),
+                        subclass_code: descriptor.sub_class_code(),
+                        protocol: descriptor.protocol_code(),
+                        interface_string_index: descriptor.description_string_index(),
+                        endpoint_descriptors: descriptor.endpoint_descriptors().map(|e| e.into()).collect(),
+                        extra_descriptors: raw_descriptor::descriptors(descriptor.extra())
+                            .map(|d| raw_descriptor::parse_interface_extra(d, descriptor.class_code(), descriptor.sub_class_code()))
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        // libusb keeps the descriptors it does not know, interface associations among them, as the extra bytes of the
+        // configuration, interface or endpoint they follow.
+        let mut interface_associations: Vec<InterfaceAssociation> = raw_descriptor::descriptors(config.extra())
+            .filter_map(raw_descriptor::parse_interface_association)
+            .collect();
+
+        for setting in interfaces.iter_mut().flat_map(|i| i.alternate_settings.iter_mut()) {
+            take_associations(&mut setting.extra_descriptors, &mut interface_associations);
+            for endpoint in &mut setting.endpoint_descriptors {
+                take_associations(&mut endpoint.extra_descriptors, &mut interface_associations);
+            }
+        }
+
+        Self {
+            max_power: config.max_power(),
+            number: config.number(),
+            interfaces,
+            interface_associations,
         }
     }
 }
 
+/// Moves the interface associations out of the extra descriptors of an interface or endpoint.
+fn take_associations(extra_descriptors: &mut Vec<RawDescriptor>, associations: &mut Vec<InterfaceAssociation>) {
+    extra_descriptors.retain(|extra| match raw_descriptor::parse_interface_association(&extra.data) {
+        Some(association) => {
+            associations.push(association);
+            false
+        }
+        None => true,
+    });
+}
+
 impl From<rusb::DeviceDescriptor> for DeviceDescriptor {
     fn from(descriptor: rusb::DeviceDescriptor) -> Self {
         Self {
@@ -110,3 +190,28 @@ impl From<rusb::Error> for DeviceHandleError {
         }
     }
 }
+
+/// Formats bytes as lowercase hex, as hashes and transfer data are shown in files and reports.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The name of the error in the WIT interface, as shown in logs and reports.
+pub fn error_name(error: DeviceHandleError) -> &'static str {
+    match error {
+        DeviceHandleError::Io => "io",
+        DeviceHandleError::InvalidParam => "invalid-param",
+        DeviceHandleError::Access => "access",
+        DeviceHandleError::NoDevice => "no-device",
+        DeviceHandleError::NotFound => "not-found",
+        DeviceHandleError::Busy => "busy",
+        DeviceHandleError::Timeout => "timeout",
+        DeviceHandleError::Overflow => "overflow",
+        DeviceHandleError::Pipe => "pipe",
+        DeviceHandleError::Interrupted => "interrupted",
+        DeviceHandleError::NoMem => "no-mem",
+        DeviceHandleError::NotSupported => "not-supported",
+        DeviceHandleError::BadDescriptor => "bad-descriptor",
+        DeviceHandleError::Other => "other",
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use anyhow::Result;
+use async_trait::async_trait;
+use tracing::{debug, instrument, warn, Span};
+use wasmtime_wasi::ResourceTable;
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::backend::BackendDevice;
+use crate::broker::DeviceBroker;
+use crate::capture::{Capture, CapturedDevice};
+use crate::device::devicehandle::SharedHandle;
+use crate::device::usbdevice::USBDevice;
+use crate::events;
+use crate::limits::{Limits, Usage};
+use crate::policy::{Grant, Policy, Rejection};
+use crate::prompt::{Chooser, Decision};
+use crate::registry::{Guest, HandleSummary};
+use crate::UsbHost;
+use crate::bindings::component::usb;
+use crate::bindings::component::usb::events::{Host as EventsHost, DeviceConnectionEvent as WasmDeviceConnectionEvent};
+
+/// The USB state of one guest. Embed it in the data of a store and implement `UsbView` to give the guest USB access.
+pub struct UsbCtx {
+    updates: tokio::sync::mpsc::UnboundedReceiver<events::DeviceConnectionEvent>,
+    pub(crate) guest: Arc<Guest>,
+    pub(crate) broker: Arc<DeviceBroker>,
+    pub(crate) policy: Arc<Policy>,
+    pub(crate) capture: Option<Arc<Capture>>,
+    pub(crate) usage: Usage,
+    /// The handles the previous version of the guest had open, until the guest takes them with `usb-device.inherited`.
+    pub(crate) inherited: Vec<(HandleSummary, SharedHandle)>,
+    pub(crate) audit: Option<Arc<AuditLog>>,
+    /// The hex SHA-256 of the guest's component, which identifies it in the audit log.
+    component_sha256: Option<String>,
+    pub(crate) chooser: Option<Arc<Chooser>>,
+    /// Devices the operator granted once, by bus number and address, until the guest opens them.
+    pub(crate) once: HashSet<(u8, u8)>,
+    /// Devices the operator denied, which the guest does not ask for again.
+    declined: HashSet<(u8, u8)>,
+    /// Devices the guest has been given, by bus number and address, whose disconnection is reported to the guest.
+    pub(crate) reported: HashSet<(u8, u8)>,
+}
+
+/// Implemented by the data of a store to give the USB host functions access to the resource table and the `UsbCtx`.
+pub trait UsbView: Send {
+    fn table(&mut self) -> &mut ResourceTable;
+
+    fn usb(&mut self) -> &mut UsbCtx;
+}
+
+impl UsbCtx {
+    pub(crate) fn new(guest: Arc<Guest>, policy: Arc<Policy>, host: &UsbHost, limits: Limits, component_sha256: Option<String>) -> Result<Self> {
+        let receiver = host.broker.subscribe()?;
+        Ok(Self {
+            updates: receiver,
+            guest,
+            broker: host.broker.clone(),
+            policy,
+            capture: host.capture.clone(),
+            usage: Usage::new(limits),
+            inherited: Vec::new(),
+            audit: host.audit.clone(),
+            component_sha256,
+            chooser: host.chooser.clone(),
+            once: HashSet::new(),
+            declined: HashSet::new(),
+            reported: HashSet::new(),
+        })
+    }
+
+    /// Prepares the context for a new version of the guest's component, which runs in a new store.
+    /// The devices the guest has open stay open and owned by the guest, and the new version takes them with `usb-device.inherited`.
+    /// Handles that the previous version did not take are closed. The attached devices are reported as connected again.
+    pub fn reload(&mut self) -> Result<()> {
+        self.updates = self.broker.subscribe()?;
+        self.usage.reset();
+        self.reported.clear();
+        self.release_inherited();
+        self.inherited = self.guest.open_handles();
+        self.guest.reloaded();
+        Ok(())
+    }
+
+    /// Changes the hash that identifies the guest's component in the audit log, when the component is replaced.
+    pub fn set_component_sha256(&mut self, component_sha256: String) {
+        self.component_sha256 = Some(component_sha256);
+    }
+
+    /// Closes the inherited handles that the guest did not take, so other guests can open their devices.
+    pub(crate) fn release_inherited(&mut self) {
+        for (open, handle) in std::mem::take(&mut self.inherited) {
+            self.release_handle(&open, &handle);
+        }
+    }
+
+    /// Closes an inherited handle that is not handed over to the guest, and gives its device back to the broker.
+    pub(crate) fn release_handle(&self, open: &HandleSummary, handle: &SharedHandle) {
+        handle.lock().unwrap().take();
+        self.broker.release(self.guest.id, open.bus_number, open.address);
+        self.guest.closed(open.bus_number, open.address);
+    }
+
+    /// Appends an entry to the audit log, if there is one. Fails, trapping the guest, when the entry cannot be written,
+    /// so a guest never uses a device without a record of it.
+    pub(crate) fn audit(&self, event: &AuditEvent) -> Result<()> {
+        match &self.audit {
+            Some(audit) => audit.record(&self.guest, self.component_sha256.as_deref(), event),
+            None => Ok(()),
+        }
+    }
+
+    /// Evaluates the policy of the guest. A device that no rule covers is allowed, with `Grant::chosen`,
+    /// if the operator granted it in the chooser.
+    pub(crate) fn evaluate(&self, device: &dyn BackendDevice) -> Result<Grant, Rejection> {
+        match self.policy.evaluate(device) {
+            Err(Rejection::NoMatchingRule) if self.is_granted(device) => Ok(Grant::chosen()),
+            result => result,
+        }
+    }
+
+    fn is_granted(&self, device: &dyn BackendDevice) -> bool {
+        let Some(chooser) = &self.chooser else { return false };
+        self.once.contains(&(device.bus_number(), device.address())) || chooser.allows(&self.guest, device)
+    }
+
+    /// Asks the operator about devices that no rule covers, except those the operator already denied to the guest,
+    /// and returns the devices that were granted. Returns no devices without a chooser, and while the operator answers,
+    /// the guest waits.
+    pub(crate) async fn ask(&mut self, devices: Vec<USBDevice>) -> Result<Vec<USBDevice>> {
+        let Some(chooser) = self.chooser.clone() else { return Ok(Vec::new()) };
+
+        let devices: Vec<USBDevice> = devices
+            .into_iter()
+            .filter(|device| !self.declined.contains(&(device.device.bus_number(), device.device.address())))
+            .collect();
+        if devices.is_empty() {
+            return Ok(devices);
+        }
+
+        let guest = self.guest.clone();
+        let backend_devices = devices.iter().map(|device| device.device.clone()).collect::<Vec<_>>();
+        let decisions = tokio::task::spawn_blocking(move || chooser.ask(&guest, &backend_devices)).await?;
+
+        let decisions = decisions.unwrap_or_else(|error| {
+            warn!(?error, "Could not ask the operator, denying the devices");
+            Vec::new()
+        });
+
+        let mut granted = Vec::new();
+        for (i, device) in devices.into_iter().enumerate() {
+            let key = (device.device.bus_number(), device.device.address());
+            match decisions.get(i).copied().unwrap_or(Decision::Deny) {
+                Decision::Deny => {
+                    self.declined.insert(key);
+                }
+                Decision::Once => {
+                    self.once.insert(key);
+                    granted.push(device);
+                }
+                Decision::Session | Decision::Always => granted.push(device),
+            }
+        }
+
+        Ok(granted)
+    }
+
+    /// The guest this context belongs to.
+    pub fn guest(&self) -> &Arc<Guest> {
+        &self.guest
+    }
+
+    /// Writes the descriptors of a device the guest is given to the capture, if there is one, and wraps the device
+    /// so the descriptors the guest reads from it later, such as its strings, are written too.
+    pub(crate) fn captured(&self, device: USBDevice) -> USBDevice {
+        let Some(capture) = &self.capture else { return device };
+
+        let descriptors = device.device.device_descriptor().and_then(|descriptor| {
+            Ok((descriptor, device.device.configurations()?))
+        });
+
+        let (descriptor, configurations) = match descriptors {
+            Ok(descriptors) => descriptors,
+            Err(error) => {
+                warn!(%device, ?error, "Could not read the descriptors for the capture");
+                return device;
+            }
+        };
+
+        if let Err(error) = capture.describe_device(device.device.bus_number(), device.device.address(), &descriptor, &configurations) {
+            warn!(?error, "Could not write to capture");
+        }
+
+        USBDevice { device: Arc::new(CapturedDevice::new(device.device, capture.clone())) }
+    }
+
+    /// Waits for the next connection event. Used by the host to drive reactor guests.
+    pub(crate) async fn next_event(&mut self) -> Option<events::DeviceConnectionEvent> {
+        self.updates.recv().await
+    }
+}
+
+/// Gives the device of a connection event to the guest, unless the policy hides it from the guest.
+/// A disconnected device can no longer be read, so its disconnection is reported if the guest was given the device,
+/// without evaluating the policy again.
+pub(crate) fn accept(view: &mut impl UsbView, event: events::DeviceConnectionEvent) -> Result<Option<WasmDeviceConnectionEvent>> {
+    let (device, connected) = match event {
+        events::DeviceConnectionEvent::Connected(device) => (device, true),
+        events::DeviceConnectionEvent::Disconnected(device) => (device, false),
+    };
+
+    let ctx = view.usb();
+    let key = (device.device.bus_number(), device.device.address());
+    let device = if connected {
+        if let Err(rejection) = ctx.evaluate(device.device.as_ref()) {
+            debug!(%device, %rejection, "Not reporting the device");
+            return Ok(None);
+        }
+
+        ctx.reported.insert(key);
+        ctx.captured(device)
+    } else {
+        if !ctx.reported.remove(&key) {
+            debug!(%device, "Not reporting the disconnection of a device the guest was not given");
+            return Ok(None);
+        }
+
+        device
+    };
+
+    ctx.usage.add_devices(1)?;
+    let d = view.table().push(device)?;
+    let mapped = if connected {
+        WasmDeviceConnectionEvent::Connected(d)
+    } else {
+        WasmDeviceConnectionEvent::Disconnected(d)
+    };
+
+    Ok(Some(mapped))
+}
+
+impl Drop for UsbCtx {
+    fn drop(&mut self) {
+        if let Err(error) = self.audit(&AuditEvent::GuestExited { devices: self.guest.device_usage() }) {
+            warn!(?error, "Could not write to the audit log");
+        }
+
+        self.release_inherited();
+        self.broker.release_guest(self.guest.id);
+        self.guest.exited();
+    }
+}
+
+impl<T: UsbView> usb::usb::Host for T {}
+impl<T: UsbView> usb::descriptors::Host for T {}
+impl<T: UsbView> usb::types::Host for T {}
+
+#[async_trait]
+impl<T: UsbView> EventsHost for T {
+    // Guests poll for events, so their spans are only recorded at the trace level.
+    #[instrument(level = "trace", name = "events.update", skip(self), fields(event))]
+    async fn update(&mut self) -> Result<WasmDeviceConnectionEvent> {
+        let event = match self.usb().updates.try_recv() {
+            Ok(event) => accept(self, event)?,
+            Err(_) => None
+        };
+
+        let event = event.unwrap_or(WasmDeviceConnectionEvent::Pending);
+        Span::current().record("event", match event {
+            WasmDeviceConnectionEvent::Pending => "pending",
+            WasmDeviceConnectionEvent::Connected(_) => "connected",
+            WasmDeviceConnectionEvent::Disconnected(_) => "disconnected",
+        });
+
+        Ok(event)
+    }
+}
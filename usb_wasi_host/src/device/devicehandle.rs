@@ -1,166 +1,447 @@
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use anyhow::Result;
+use tracing::{instrument, warn, Span};
 use wasmtime::component::Resource;
-use wasmtime_wasi::WasiView;
 
-use crate::{bindings::component::usb::{types::DeviceHandleError, usb::HostDeviceHandle}, usb_host_wasi_view::USBHostWasiView};
+use crate::{USBDeviceIdentifier, trace, audit::AuditEvent, backend::{BackendDevice, BackendDeviceHandle}, capture::{CapturedTransfer, TransferKind}, bindings::component::usb::{types::DeviceHandleError, usb::HostDeviceHandle}, firewall::{ControlFirewall, Recipient, SetupPacket}, policy::{Grant, Interfaces}, ctx::UsbView};
+
+/// The backend handle of an open device. It is shared with the guest registry,
+/// which closes the handle by taking it out when the device is revoked.
+pub type SharedHandle = Arc<Mutex<Option<Box<dyn BackendDeviceHandle>>>>;
 
 #[derive(Debug)]
 pub struct DeviceHandle {
+    pub bus_number: u8,
     pub device_address: u8,
-    pub handle: rusb::DeviceHandle<rusb::Context>
+    pub identifier: Option<USBDeviceIdentifier>,
+    pub handle: SharedHandle,
+    pub grant: Grant,
+    /// The endpoints of the interfaces the guest may claim.
+    pub endpoints: HashSet<u8>,
+    /// The class and subclass of each interface, by interface number.
+    pub interface_classes: HashMap<u8, (u8, u8)>,
+    pub firewall: ControlFirewall,
+    /// The bytes transferred through this handle, for the audit log.
+    pub bytes_in: u64,
+    pub bytes_out: u64
+}
+
+/// An operation on a device handle that needs a capability from the device's grant.
+#[derive(Debug, Clone, Copy)]
+pub enum Operation {
+    Reset,
+    Configure,
+    Interface(u8),
+    Endpoint(u8),
+    Control(SetupPacket),
+}
+
+/// Access to the backend handle of a device that has not been revoked.
+pub struct HandleGuard<'a>(MutexGuard<'a, Option<Box<dyn BackendDeviceHandle>>>);
+
+impl DeviceHandle {
+    pub fn new(device: &dyn BackendDevice, handle: Box<dyn BackendDeviceHandle>, grant: Grant) -> Self {
+        Self::shared(device, Arc::new(Mutex::new(Some(handle))), grant)
+    }
+
+    /// Wraps a backend handle that is already open, such as one inherited from an earlier version of the guest.
+    pub fn shared(device: &dyn BackendDevice, handle: SharedHandle, grant: Grant) -> Self {
+        let configurations = device.configurations().unwrap_or_default();
+        let interfaces = configurations
+            .iter()
+            .flat_map(|c| c.interfaces.iter())
+            .flat_map(|i| i.alternate_settings.iter());
+
+        let endpoints = interfaces
+            .clone()
+            .filter(|i| grant.allows_interface(i.number))
+            .flat_map(|i| i.endpoint_descriptors.iter().map(|e| e.address))
+            .collect();
+
+        let interface_classes = interfaces
+            .map(|i| (i.number, (i.class_code, i.subclass_code)))
+            .collect();
+
+        let identifier = device.device_descriptor().ok().map(|descriptor| USBDeviceIdentifier {
+            vendor_id: descriptor.vendor_id,
+            product_id: descriptor.product_id
+        });
+
+        Self {
+            bus_number: device.bus_number(),
+            device_address: device.address(),
+            identifier,
+            handle,
+            firewall: ControlFirewall::new(grant.control.clone()),
+            grant,
+            endpoints,
+            interface_classes,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    fn allows(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Reset => self.grant.reset,
+            Operation::Configure => self.grant.configure,
+            Operation::Interface(interface) => self.grant.allows_interface(interface),
+            Operation::Endpoint(endpoint) => self.grant.interfaces == Interfaces::All || self.endpoints.contains(&endpoint),
+            // Requests to an interface or endpoint carry its number in the low byte of the index.
+            Operation::Control(setup) => match setup.recipient() {
+                Recipient::Interface => self.allows(Operation::Interface(setup.index as u8)),
+                Recipient::Endpoint => self.allows(Operation::Endpoint(setup.index as u8)),
+                _ => true,
+            },
+        }
+    }
+
+    /// Gives access to the backend handle, which fails with `no-device` once the device is revoked.
+    /// Records the device in the span of the host call.
+    pub fn backend(&self) -> Result<HandleGuard<'_>, DeviceHandleError> {
+        trace::record_device(self.bus_number, self.device_address, self.identifier.as_ref());
+
+        let handle = self.handle.lock().unwrap();
+        if handle.is_none() {
+            return Err(DeviceHandleError::NoDevice);
+        }
+
+        Ok(HandleGuard(handle))
+    }
+
+    /// Gives access to the backend handle if the grant, and for control requests the firewall, permit the operation.
+    pub fn checked(&mut self, operation: Operation) -> Result<HandleGuard<'_>, DeviceHandleError> {
+        trace::record_device(self.bus_number, self.device_address, self.identifier.as_ref());
+
+        if !self.allows(operation) {
+            return Err(DeviceHandleError::Access);
+        }
+
+        if let Operation::Control(setup) = operation {
+            if let Err(rule) = self.firewall.check(&setup, &self.interface_classes) {
+                warn!(%setup, %rule, "Blocked control request");
+                return Err(DeviceHandleError::Access);
+            }
+        }
+
+        self.backend()
+    }
+}
+
+impl Deref for HandleGuard<'_> {
+    type Target = dyn BackendDeviceHandle;
+
+    fn deref(&self) -> &Self::Target {
+        // Guards are only created for handles that are present.
+        self.0.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for HandleGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_deref_mut().unwrap()
+    }
+}
+
+/// Reports a transfer to the guest's event hooks, and writes it to the capture file if traffic is being captured
+/// and to the audit log if it is a control request.
+/// A transfer that the host refused, because the guest used up its bandwidth or the grant or the firewall does not
+/// allow it, was not `sent` to the device. It is reported to the event hooks as failed, so the metrics count it,
+/// and written to the audit log, as `denied` if the grant or the firewall refused it, but not to the capture file.
+/// Fails, trapping the guest, when the transfer cannot be audited.
+fn record_transfer(view: &mut impl UsbView, handle: &Resource<DeviceHandle>, transfer: CapturedTransfer, sent: bool) -> Result<()> {
+    let guest = view.usb().guest.clone();
+    let capture = view.usb().capture.clone();
+
+    let device_handle = view.table().get_mut(handle)?;
+    let (bus_number, device_address) = (device_handle.bus_number, device_handle.device_address);
+    if !sent {
+        guest.transferred(bus_number, device_address, &transfer, false);
+        let result = match transfer.result {
+            Err(DeviceHandleError::Access) => "denied",
+            result => trace::result_name(&result),
+        };
+        return audit_control(view, bus_number, device_address, &transfer, result);
+    }
+
+    if let Ok(bytes) = transfer.result {
+        if transfer.endpoint & 0x80 != 0 {
+            device_handle.bytes_in += bytes as u64;
+        } else {
+            device_handle.bytes_out += bytes as u64;
+        }
+    }
+
+    guest.transferred(bus_number, device_address, &transfer, true);
+    if let Ok(bytes) = transfer.result {
+        Span::current().record("transferred", bytes);
+    }
+
+    if let Some(capture) = capture {
+        if let Err(e) = capture.record(bus_number, device_address, &transfer) {
+            warn!(error = ?e, "Could not write to capture");
+        }
+    }
+
+    audit_control(view, bus_number, device_address, &transfer, trace::result_name(&transfer.result))?;
+    view.usb().usage.transferred(transfer.result.unwrap_or(0));
+    Ok(())
+}
+
+/// Writes a control request to the audit log. Other transfers are not audited.
+fn audit_control(view: &mut impl UsbView, bus_number: u8, address: u8, transfer: &CapturedTransfer, result: &'static str) -> Result<()> {
+    let TransferKind::Control(setup) = transfer.kind else { return Ok(()) };
+
+    view.usb().audit(&AuditEvent::Control {
+        bus_number,
+        address,
+        request_type: setup.request_type,
+        request: setup.request,
+        value: setup.value,
+        index: setup.index,
+        length: setup.length,
+        result,
+    })
 }
 
 #[async_trait]
-impl HostDeviceHandle for USBHostWasiView {
+impl<T: UsbView> HostDeviceHandle for T {
+    #[instrument(level = "debug", name = "device-handle.drop", skip(self, rep), fields(bus, address, device))]
     fn drop(&mut self, rep: Resource<DeviceHandle>) -> Result<()>  {
         let handle = self.table().delete(rep)?;
-
-        let was_present = self.active_device_handles.remove(&handle.device_address);
-        assert!(was_present);
-        Ok(())
+        trace::record_device(handle.bus_number, handle.device_address, handle.identifier.as_ref());
+        let ctx = self.usb();
+        ctx.usage.remove_handle();
+
+        ctx.broker.release(ctx.guest.id, handle.bus_number, handle.device_address);
+        ctx.guest.closed(handle.bus_number, handle.device_address);
+
+        ctx.audit(&AuditEvent::Close {
+            bus_number: handle.bus_number,
+            address: handle.device_address,
+            bytes_in: handle.bytes_in,
+            bytes_out: handle.bytes_out,
+        })
     }
 
+    #[instrument(level = "debug", name = "device-handle.reset", skip(self, handle), fields(bus, address, device, result))]
     async fn reset(&mut self, handle: Resource<DeviceHandle>) -> Result<Result<(), DeviceHandleError>> {
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .reset()
-            .map_err(|e| e.into());
-
-        Ok(result)
+        let device_handle = self.table().get_mut(&handle)?;
+        let (bus_number, address) = (device_handle.bus_number, device_handle.device_address);
+        let result = device_handle
+            .checked(Operation::Reset)
+            .and_then(|mut h| h.reset());
+
+        self.usb().audit(&AuditEvent::Reset { bus_number, address, result: trace::result_name(&result) })?;
+        Ok(trace::record_result(result))
     }
 
+    #[instrument(level = "debug", name = "device-handle.active-configuration", skip(self, handle), fields(bus, address, device, result))]
     async fn active_configuration(&mut self, handle: Resource<DeviceHandle>) -> Result<Result<u8, DeviceHandleError>> {
         let result = self.table()
             .get_mut(&handle)?
-            .handle
-            .active_configuration()
-            .map_err(|e| e.into());
+            .backend()
+            .and_then(|mut h| h.active_configuration());
 
-        Ok(result)
+        Ok(trace::record_result(result))
     }
 
 
+    #[instrument(level = "debug", name = "device-handle.select-configuration", skip(self, handle), fields(bus, address, device, result))]
     async fn select_configuration(&mut self, handle: Resource<DeviceHandle>, configuration: u8) -> Result<Result<(), DeviceHandleError>> {
         let result = self.table()
             .get_mut(&handle)?
-            .handle
-            .set_active_configuration(configuration)
-            .map_err(|e| e.into());
+            .checked(Operation::Configure)
+            .and_then(|mut h| h.set_active_configuration(configuration));
 
-        Ok(result)
+        Ok(trace::record_result(result))
     }
 
+    #[instrument(level = "debug", name = "device-handle.claim-interface", skip(self, handle), fields(bus, address, device, result))]
     async fn claim_interface(&mut self, handle: Resource<DeviceHandle>, interface: u8) -> Result<Result<(), DeviceHandleError>> {
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .claim_interface(interface)
-            .map_err(|e| e.into());
-
-        Ok(result)
+        let guest = self.usb().guest.clone();
+        let device_handle = self.table().get_mut(&handle)?;
+        let result = device_handle
+            .checked(Operation::Interface(interface))
+            .and_then(|mut h| h.claim_interface(interface));
+
+        let (bus_number, address) = (device_handle.bus_number, device_handle.device_address);
+        if result.is_ok() {
+            guest.claimed(bus_number, address, interface, true);
+        }
+
+        self.usb().audit(&AuditEvent::ClaimInterface { bus_number, address, interface, result: trace::result_name(&result) })?;
+        Ok(trace::record_result(result))
     }
 
+    #[instrument(level = "debug", name = "device-handle.release-interface", skip(self, handle), fields(bus, address, device, result))]
     async fn release_interface(&mut self, handle: Resource<DeviceHandle>, interface: u8) -> Result<()> {
-        let _ = self.table()
-            .get_mut(&handle)?
-            .handle
-            .release_interface(interface)
-            .map_err(|e| println!("{:?}", e));
+        let guest = self.usb().guest.clone();
+        let device_handle = self.table().get_mut(&handle)?;
+        let result = device_handle
+            .checked(Operation::Interface(interface))
+            .and_then(|mut h| h.release_interface(interface));
+
+        match trace::record_result(result) {
+            Ok(()) => guest.claimed(device_handle.bus_number, device_handle.device_address, interface, false),
+            Err(error) => warn!(interface, ?error, "Could not release the interface"),
+        }
 
         Ok(())
     }
 
+    #[instrument(level = "debug", name = "device-handle.write-interrupt", skip(self, handle, data), fields(bus, address, device, length = data.len(), transferred, result))]
     async fn write_interrupt(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, data: Vec<u8>, timeout: u64) -> Result<Result<u64, DeviceHandleError>> {
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .write_interrupt(endpoint, &data, Duration::from_nanos(timeout))
-            .map_err(|e| e.into())
-            .map(|a| a as u64);
-
-        Ok(result)
+        self.usb().usage.check_transfer_size(data.len() as u64)?;
+        let submitted = SystemTime::now();
+        let mut sent = false;
+        let result = match self.usb().usage.check_bandwidth() {
+            Ok(()) => self.table()
+                .get_mut(&handle)?
+                .checked(Operation::Endpoint(endpoint))
+                .and_then(|mut h| {
+                    sent = true;
+                    h.write_interrupt(endpoint, &data, Duration::from_nanos(timeout))
+                }),
+            Err(error) => Err(error),
+        };
+
+        record_transfer(self, &handle, CapturedTransfer { kind: TransferKind::Interrupt, endpoint, data: &data, length: data.len(), result, submitted }, sent)?;
+
+        Ok(trace::record_result(result).map(|a| a as u64))
     }
 
+    #[instrument(level = "debug", name = "device-handle.write-bulk", skip(self, handle, data), fields(bus, address, device, length = data.len(), transferred, result))]
     async fn write_bulk(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, data: Vec<u8>, timeout: u64) -> Result<Result<u64, DeviceHandleError>> {
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .write_bulk(endpoint, &data, Duration::from_nanos(timeout))
-            .map_err(|e| e.into())
-            .map(|a| a as u64);
-
-        Ok(result)
+        self.usb().usage.check_transfer_size(data.len() as u64)?;
+        let submitted = SystemTime::now();
+        let mut sent = false;
+        let result = match self.usb().usage.check_bandwidth() {
+            Ok(()) => self.table()
+                .get_mut(&handle)?
+                .checked(Operation::Endpoint(endpoint))
+                .and_then(|mut h| {
+                    sent = true;
+                    h.write_bulk(endpoint, &data, Duration::from_nanos(timeout))
+                }),
+            Err(error) => Err(error),
+        };
+
+        record_transfer(self, &handle, CapturedTransfer { kind: TransferKind::Bulk, endpoint, data: &data, length: data.len(), result, submitted }, sent)?;
+
+        Ok(trace::record_result(result).map(|a| a as u64))
     }
 
+    #[instrument(level = "debug", name = "device-handle.write-control", skip(self, handle, buf), fields(bus, address, device, length = buf.len(), transferred, result))]
     async fn write_control(&mut self, handle: Resource<DeviceHandle>, request_type: u8, request: u8, value: u16, index: u16, buf: Vec<u8>, timeout: u64) -> Result<Result<u64, DeviceHandleError>> {
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .write_control(request_type, request, value, index, &buf, Duration::from_nanos(timeout))
-            .map_err(|e| e.into())
-            .map(|a| a as u64);
-
-        Ok(result)
+        self.usb().usage.check_transfer_size(buf.len() as u64)?;
+        let setup = SetupPacket { request_type, request, value, index, length: buf.len() as u16 };
+        let submitted = SystemTime::now();
+        let mut sent = false;
+        let result = match self.usb().usage.check_bandwidth() {
+            Ok(()) => self.table()
+                .get_mut(&handle)?
+                .checked(Operation::Control(setup))
+                .and_then(|mut h| {
+                    sent = true;
+                    h.write_control(request_type, request, value, index, &buf, Duration::from_nanos(timeout))
+                }),
+            Err(error) => Err(error),
+        };
+
+        record_transfer(self, &handle, CapturedTransfer { kind: TransferKind::Control(setup), endpoint: request_type & 0x80, data: &buf, length: buf.len(), result, submitted }, sent)?;
+
+        Ok(trace::record_result(result).map(|a| a as u64))
     }
 
+    #[instrument(level = "debug", name = "device-handle.read-control", skip(self, handle), fields(bus, address, device, transferred, result))]
     async fn read_control(&mut self, handle: Resource<DeviceHandle>, request_type: u8, request: u8, value: u16, index: u16, max_size: u16, timeout: u64) -> Result<Result<(u64, Vec<u8>), DeviceHandleError>> {
-        let mut buf: Vec<u8> = vec![0; max_size as usize];
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .read_control(request_type, request, value, index, &mut buf, Duration::from_nanos(timeout))
-            .map_err(|e| e.into())
-            .map(|a| a as u64);
-
-        Ok(result.map(|bytes_read| (bytes_read, buf)))
+        let setup = SetupPacket { request_type, request, value, index, length: max_size };
+        let mut buf: Vec<u8> = vec![0; self.usb().usage.check_transfer_size(max_size.into())?];
+        let submitted = SystemTime::now();
+        let mut sent = false;
+        let result = match self.usb().usage.check_bandwidth() {
+            Ok(()) => self.table()
+                .get_mut(&handle)?
+                .checked(Operation::Control(setup))
+                .and_then(|mut h| {
+                    sent = true;
+                    h.read_control(request_type, request, value, index, &mut buf, Duration::from_nanos(timeout))
+                }),
+            Err(error) => Err(error),
+        };
+
+        record_transfer(self, &handle, CapturedTransfer { kind: TransferKind::Control(setup), endpoint: request_type & 0x80, data: &buf, length: buf.len(), result, submitted }, sent)?;
+
+        Ok(trace::record_result(result).map(|bytes_read| (bytes_read as u64, buf)))
     }
 
+    /// Isochronous transfers are not supported by the backends.
     async fn write_isochronous(&mut self, _: Resource<DeviceHandle>, _: u8, _: Vec<u8>, _: u64) -> Result<Result<u64, DeviceHandleError>> {
-        todo!()
+        Ok(Err(DeviceHandleError::NotSupported))
     }
 
     async fn read_isochronous(&mut self, _: Resource<DeviceHandle>, _: u8, _: u64) -> Result<Result<(u64, Vec<u8>), DeviceHandleError>> {
-        todo!()
+        Ok(Err(DeviceHandleError::NotSupported))
     }
 
 
+    #[instrument(level = "debug", name = "device-handle.read-bulk", skip(self, handle), fields(bus, address, device, transferred, result))]
     async fn read_bulk(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, max_size: u64, timeout: u64) -> Result<Result<(u64, Vec<u8>), DeviceHandleError>> {
-        let mut buffer: Vec<u8> = vec![0; max_size as usize];
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .read_bulk(endpoint, &mut buffer, Duration::from_nanos(timeout))
-            .map_err(|e| e.into())
-            .map(|a| a as u64);
-
-        Ok(result.map(|a| (a, buffer)))
+        let mut buffer: Vec<u8> = vec![0; self.usb().usage.check_transfer_size(max_size)?];
+        let submitted = SystemTime::now();
+        let mut sent = false;
+        let result = match self.usb().usage.check_bandwidth() {
+            Ok(()) => self.table()
+                .get_mut(&handle)?
+                .checked(Operation::Endpoint(endpoint))
+                .and_then(|mut h| {
+                    sent = true;
+                    h.read_bulk(endpoint, &mut buffer, Duration::from_nanos(timeout))
+                }),
+            Err(error) => Err(error),
+        };
+
+        record_transfer(self, &handle, CapturedTransfer { kind: TransferKind::Bulk, endpoint, data: &buffer, length: buffer.len(), result, submitted }, sent)?;
+
+        Ok(trace::record_result(result).map(|a| (a as u64, buffer)))
     }
 
+    #[instrument(level = "debug", name = "device-handle.read-interrupt", skip(self, handle), fields(bus, address, device, transferred, result))]
     async fn read_interrupt(&mut self, handle: Resource<DeviceHandle>, endpoint: u8, timeout: u64) -> Result<Result<(u64, Vec<u8>), DeviceHandleError>> {
         let mut buf = [0; 256];
-        let result = self.table()
-            .get_mut(&handle)?
-            .handle
-            .read_interrupt(endpoint, &mut buf, Duration::from_nanos(timeout))
-            .map_err(|e| e.into())
-            .map(|a| a as u64);
-
-        Ok(result.map(|a| (a, buf.to_vec())))
+        let submitted = SystemTime::now();
+        let mut sent = false;
+        let result = match self.usb().usage.check_bandwidth() {
+            Ok(()) => self.table()
+                .get_mut(&handle)?
+                .checked(Operation::Endpoint(endpoint))
+                .and_then(|mut h| {
+                    sent = true;
+                    h.read_interrupt(endpoint, &mut buf, Duration::from_nanos(timeout))
+                }),
+            Err(error) => Err(error),
+        };
+
+        record_transfer(self, &handle, CapturedTransfer { kind: TransferKind::Interrupt, endpoint, data: &buf, length: buf.len(), result, submitted }, sent)?;
+
+        Ok(trace::record_result(result).map(|a| (a as u64, buf.to_vec())))
     }
 
+    #[instrument(level = "debug", name = "device-handle.select-alternate-interface", skip(self, handle), fields(bus, address, device, result))]
     async fn select_alternate_interface(&mut self, handle: Resource<DeviceHandle>, interface: u8, setting: u8) -> Result<Result<(), DeviceHandleError>> {
 
         let result = self.table()
             .get_mut(&handle)?
-            .handle
-            .set_alternate_setting(interface, setting)
-            .map_err(|e| e.into());
+            .checked(Operation::Interface(interface))
+            .and_then(|mut h| h.set_alternate_setting(interface, setting));
 
-        Ok(result)
+        Ok(trace::record_result(result))
     }
 
     // async fn detach_kernel_driver(&mut self, handle: Resource<MyDeviceHandle>, interface: u8) -> Result<Result<(), DeviceHandleError>> {
@@ -1,280 +1,418 @@
+use crate::backend::BackendDevice;
 use crate::bindings::component::usb as world;
-use crate::usb_host_wasi_view::USBHostWasiView;
-use crate::{AllowedUSBDevices, USBDeviceIdentifier};
+use crate::ctx::UsbView;
+use crate::audit::AuditEvent;
+use crate::policy::{Grant, Rejection};
+use crate::raw_descriptor::{self, DESCRIPTOR_BOS, DESCRIPTOR_CONFIGURATION};
+use crate::{trace, USBDeviceIdentifier};
 use anyhow::{Error, Result};
 use async_trait::async_trait;
-use rusb::{ConfigDescriptor, DeviceHandle as RusbDeviceHandle, Language, UsbContext};
-use std::time::Duration;
+use tracing::{debug, instrument, Span};
+use std::fmt;
+use std::sync::Arc;
 use wasmtime::component::Resource;
-use wasmtime_wasi::WasiView;
 
-use world::usb::HostUsbDevice;
-use world::descriptors::{ConfigurationDescriptor, InterfaceDescriptor, DeviceDescriptor};
-use world::types::DeviceHandleError;
+use world::usb::{DeviceFilter, DeviceLocation, HostUsbDevice, InheritedHandle};
+use world::descriptors::{BosDescriptor, ConfigurationDescriptor, DeviceDescriptor};
+use world::types::{DeviceHandleError, Speed};
 
 use super::devicehandle::DeviceHandle;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct USBDevice {
-    pub device: rusb::Device<rusb::Context>,
+    pub device: Arc<dyn BackendDevice>,
 }
 
 impl USBDevice {
-    #[allow(dead_code)]
-    fn get_language(handle: &rusb::DeviceHandle<rusb::Context>, timeout: Duration) -> Result<rusb::Language> {
-        let languages = handle.read_languages(timeout)?;
-        let language = languages
-            .first()
-            .ok_or(Error::msg("No language to read configuration"))?;
-
-        Ok(*language)
-    }
-
-    pub fn identifier(&self) -> Result<USBDeviceIdentifier> {
+    pub fn identifier(&self) -> Result<USBDeviceIdentifier, DeviceHandleError> {
         let descriptor = self.device.device_descriptor()?;
         Ok(USBDeviceIdentifier {
-            vendor_id: descriptor.vendor_id(),
-            product_id: descriptor.product_id()
+            vendor_id: descriptor.vendor_id,
+            product_id: descriptor.product_id
         })
     }
-}
 
-impl USBDevice {
-    #[allow(dead_code)]
-    fn read_property<S>(&self, timeout: Duration, perform: impl FnOnce(&rusb::Device<rusb::Context>, RusbDeviceHandle<rusb::Context>, &Language) -> Result<S, rusb::Error>) -> Result<S, DeviceHandleError> {
-        let device = &self.device;
-        let handle = device.open().map_err(DeviceHandleError::from)?;
-        let languages = handle.read_languages(timeout).map_err(DeviceHandleError::from)?;
-        let language = languages
-            .first()
-            .ok_or(DeviceHandleError::Other)?;
-
-        perform(device, handle, language)
-            .map_err(DeviceHandleError::from)
+    /// Records the device in the span of the host call.
+    fn record(&self) {
+        trace::record_device(self.device.bus_number(), self.device.address(), self.identifier().ok().as_ref());
     }
+}
 
-    fn get_configuration(&self, config: ConfigDescriptor) -> ConfigurationDescriptor {
-
-        let interfaces = config
-            .interfaces()
-            .flat_map(|interface| interface.descriptors())
-            .map(|descriptor| {
-                InterfaceDescriptor {
-                    number: descriptor.interface_number(),
-                    alternate_setting: descriptor.setting_number(),
-                    class_code: descriptor.class_code(),
-                    subclass_code: descriptor.sub_class_code(),
-                    protocol: descriptor.protocol_code(),
-                    interface_string_index: descriptor.description_string_index(),
-                    endpoint_descriptors: descriptor.endpoint_descriptors().map(|e| e.into()).collect(),
-                }
-            })
-            .collect();
-
-        let configuration = ConfigurationDescriptor {
-            max_power: config.max_power(),
-            number: config.number(),
-            interfaces,
-        };
-
-        configuration
+impl fmt::Display for USBDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "device {:03}:{:03}", self.device.bus_number(), self.device.address())?;
+        if let Ok(identifier) = self.identifier() {
+            write!(f, " ({})", identifier)?;
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
-impl HostUsbDevice for USBHostWasiView {
+impl<T: UsbView> HostUsbDevice for T {
+    #[instrument(level = "debug", name = "usb-device.drop", skip(self, rep), fields(bus, address, device))]
     fn drop(&mut self, rep: Resource<USBDevice>) -> Result<()> {
-        Ok(self.table().delete(rep).map(|_| ())?)
+        self.table().delete(rep)?.record();
+        self.usb().usage.remove_device();
+        Ok(())
     }
 
+    #[instrument(level = "debug", name = "usb-device.device-descriptor", skip(self, device), fields(bus, address, device))]
     async fn device_descriptor(&mut self, device: Resource<USBDevice>) -> Result<DeviceDescriptor> {
-        let descriptor = self
-            .table()
-            .get(&device)?
-            .device.device_descriptor()?
-            .into();
+        let resource = self.table().get(&device)?;
+        resource.record();
+        let descriptor = resource.device.device_descriptor()?;
 
         Ok(descriptor)
     }
 
+    #[instrument(level = "debug", name = "usb-device.location", skip(self, device), fields(bus, address, device, result))]
+    async fn location(&mut self, device: Resource<USBDevice>) -> Result<Result<DeviceLocation, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
+
+        let location = resource.device.port_numbers().map(|port_numbers| DeviceLocation {
+            bus_number: resource.device.bus_number(),
+            address: resource.device.address(),
+            port_numbers,
+            parent_address: resource.device.parent_address(),
+        });
+
+        Ok(trace::record_result(location))
+    }
+
+    #[instrument(level = "debug", name = "usb-device.speed", skip(self, device), fields(bus, address, device))]
+    async fn speed(&mut self, device: Resource<USBDevice>) -> Result<Speed> {
+        let resource = self.table().get(&device)?;
+        resource.record();
+
+        Ok(resource.device.speed())
+    }
+
+    #[instrument(level = "debug", name = "usb-device.configurations", skip(self, device), fields(bus, address, device, result))]
     async fn configurations(&mut self, device: Resource<USBDevice>) -> Result<Result<Vec<ConfigurationDescriptor>, DeviceHandleError>> {
-        let resource = self
-            .table()
-            .get(&device)?;
+        let resource = self.table().get(&device)?;
+        resource.record();
+        let configurations = resource.device.configurations();
 
-        let device = &resource.device;
+        Ok(trace::record_result(configurations))
+    }
 
-        let config_count = device.device_descriptor()?.num_configurations();
-        let mut configurations: Vec<ConfigurationDescriptor> = Vec::with_capacity(config_count.into());
+    #[instrument(level = "debug", name = "usb-device.get-descriptor", skip(self, device), fields(bus, address, device, result))]
+    async fn get_descriptor(&mut self, device: Resource<USBDevice>, descriptor_type: u8, index: u8, language: u16) -> Result<Result<Vec<u8>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-        for i in 0..config_count {
-            match device.config_descriptor(i) {
-                Ok(config) => {
-                    let resource = resource.get_configuration(config);
-                    configurations.push(resource)
-                }
-                Err(error) => return Ok(Err(error.into()))
-            }
-        }
+        Ok(trace::record_result(resource.device.read_descriptor(descriptor_type, index, language)))
+    }
 
-        Ok(Ok(configurations))
+    #[instrument(level = "debug", name = "usb-device.raw-configuration-descriptor", skip(self, device), fields(bus, address, device, result))]
+    async fn raw_configuration_descriptor(&mut self, device: Resource<USBDevice>, index: u8) -> Result<Result<Vec<u8>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
+
+        Ok(trace::record_result(resource.device.read_descriptor(DESCRIPTOR_CONFIGURATION, index, 0)))
     }
 
-    // async fn configurations(&mut self, device: Resource<MyDevice<rusb::Context>>) -> Result<Result<ConfigurationDescriptor, DeviceHandleError>> {
-    //     let resource = self
-    //         .table()
-    //         .get(&device)?;
+    #[instrument(level = "debug", name = "usb-device.bos-descriptor", skip(self, device), fields(bus, address, device, result))]
+    async fn bos_descriptor(&mut self, device: Resource<USBDevice>) -> Result<Result<Option<BosDescriptor>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-    //     let config = resource.read_property(|device, handle, language| {
-    //         let config = device.active_config_descriptor()?;
-    //         Ok(resource.get_configuration(&handle, config, language))
-    //     });
+        Ok(trace::record_result(read_bos_descriptor(resource.device.as_ref())))
+    }
 
-    //     Ok(config)
-    // }
+    // Strings are read once per device and then come from the broker's cache, so they can be read without opening the device.
+    #[instrument(level = "debug", name = "usb-device.languages", skip(self, device), fields(bus, address, device, result))]
+    async fn languages(&mut self, device: Resource<USBDevice>) -> Result<Result<Vec<u16>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-    // async fn product_name(&mut self, device: Resource<MyDevice<rusb::Context>>) -> Result<Result<String, DeviceHandleError>> {
-    //     let device = self
-    //         .table()
-    //         .get(&device)?;
+        Ok(trace::record_result(resource.device.languages()))
+    }
 
-    //     let name = device.read_property(|device, handle, language| {
-    //         let descriptor = device.device_descriptor()?;
-    //         handle.read_product_string(*language, &descriptor, DEFAULT_TIMEOUT)
-    //     });
+    #[instrument(level = "debug", name = "usb-device.read-string", skip(self, device), fields(bus, address, device, result))]
+    async fn read_string(&mut self, device: Resource<USBDevice>, index: u8, language: Option<u16>) -> Result<Result<String, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-    //     Ok(name)
-    // }
+        let language = match language {
+            Some(language) => Ok(language),
+            None => resource.device.languages().and_then(|languages| languages.first().copied().ok_or(DeviceHandleError::NotFound)),
+        };
 
-    // async fn manufacturer_name(&mut self, device: Resource<MyDevice<rusb::Context>>) -> Result<Result<String, DeviceHandleError>> {
-    //     let device = self
-    //         .table()
-    //         .get(&device)?;
+        Ok(trace::record_result(language.and_then(|language| resource.device.read_string(index, language))))
+    }
 
-    //     let name = device.read_property(|device, handle, language| {
-    //         let descriptor = device.device_descriptor()?;
-    //         handle.read_manufacturer_string(*language, &descriptor, DEFAULT_TIMEOUT)
-    //     });
+    #[instrument(level = "debug", name = "usb-device.manufacturer-name", skip(self, device), fields(bus, address, device, result))]
+    async fn manufacturer_name(&mut self, device: Resource<USBDevice>) -> Result<Result<Option<String>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-    //     Ok(name)
-    // }
+        Ok(trace::record_result(resource.device.manufacturer()))
+    }
 
-    // async fn serial_number(&mut self, device: Resource<MyDevice<rusb::Context>>) -> Result<Result<String, DeviceHandleError>> {
-    //     let device = self
-    //         .table()
-    //         .get(&device)?;
+    #[instrument(level = "debug", name = "usb-device.product-name", skip(self, device), fields(bus, address, device, result))]
+    async fn product_name(&mut self, device: Resource<USBDevice>) -> Result<Result<Option<String>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-    //     let name = device.read_property(|device, handle, language| {
-    //         let descriptor = device.device_descriptor()?;
-    //         handle.read_serial_number_string(*language, &descriptor, DEFAULT_TIMEOUT)
-    //     });
+        Ok(trace::record_result(resource.device.product()))
+    }
+
+    #[instrument(level = "debug", name = "usb-device.serial-number", skip(self, device), fields(bus, address, device, result))]
+    async fn serial_number(&mut self, device: Resource<USBDevice>) -> Result<Result<Option<String>, DeviceHandleError>> {
+        let resource = self.table().get(&device)?;
+        resource.record();
 
-    //     Ok(name)
-    // }
+        Ok(trace::record_result(resource.device.serial_number()))
+    }
 
+    #[instrument(level = "debug", name = "usb-device.open", skip(self, device), fields(bus, address, device, result))]
     async fn open(&mut self, device: Resource<USBDevice>) -> Result<Result<Resource<DeviceHandle>, DeviceHandleError>> {
-        let device_address = self
-            .table()
-            .get(&device)?
-            .device
-            .address();
-
-        let already_present = !self.active_device_handles.insert(device_address);
-        if already_present {
-            return Ok(Err(DeviceHandleError::Access));
+        let result = open_device(self, &device).await?;
+
+        if self.usb().audit.is_some() {
+            let resource = self.table().get(&device)?;
+            let event = AuditEvent::Open {
+                bus_number: resource.device.bus_number(),
+                address: resource.device.address(),
+                device: resource.identifier().ok().map(|identifier| identifier.to_string()),
+                // Reading the serial number may open the device, so it is only read from devices the guest could open.
+                serial_number: result.as_ref().ok().and_then(|_| resource.device.serial_number().ok().flatten()),
+                result: trace::result_name(&result),
+            };
+            self.usb().audit(&event)?;
         }
 
-        let mut handle = self
-            .table()
-            .get(&device)?
-            .device
-            .open()?;
+        Ok(trace::record_result(result))
+    }
+
+    #[instrument(level = "debug", name = "usb-device.enumerate", skip(self), fields(count))]
+    async fn enumerate(&mut self) -> Result<Vec<Resource<USBDevice>>> {
+        allowed_devices(self, None).await
+    }
 
-        _ = handle.set_auto_detach_kernel_driver(true);
+    #[instrument(level = "debug", name = "usb-device.request-device", skip(self), fields(count))]
+    async fn request_device(&mut self, filter: DeviceFilter) -> Result<Vec<Resource<USBDevice>>> {
+        allowed_devices(self, Some(&filter)).await
+    }
 
-        let resource = self
-            .table()
-            .push(DeviceHandle {device_address, handle})?;
+    #[instrument(level = "debug", name = "usb-device.inherited", skip(self), fields(count))]
+    async fn inherited(&mut self) -> Result<Vec<InheritedHandle>> {
+        let inherited = std::mem::take(&mut self.usb().inherited);
+        if inherited.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        Ok(Ok(resource))
+        let mut devices = self.usb().broker.devices()?;
+        let mut handles = Vec::new();
+
+        for (open, handle) in inherited {
+            // The device may have been disconnected while the component was reloaded.
+            let Some(i) = devices.iter().position(|d| d.bus_number() == open.bus_number && d.address() == open.address) else {
+                self.usb().release_handle(&open, &handle);
+                continue;
+            };
+            let device = USBDevice { device: devices.swap_remove(i).into() };
+
+            let grant = match self.usb().evaluate(device.device.as_ref()) {
+                Ok(grant) => grant,
+                Err(rejection) => {
+                    debug!(%device, %rejection, "Not handing over the device");
+                    self.usb().release_handle(&open, &handle);
+                    continue;
+                }
+            };
+
+            self.usb().usage.add_devices(1)?;
+            self.usb().usage.add_handle()?;
+            let device = self.usb().captured(device);
+
+            let handle = DeviceHandle::shared(device.device.as_ref(), handle, grant);
+            handles.push(InheritedHandle {
+                device: self.table().push(device)?,
+                handle: self.table().push(handle)?,
+                claimed_interfaces: open.claimed_interfaces,
+            });
+        }
+
+        Span::current().record("count", handles.len());
+        Ok(handles)
     }
+}
 
-    async fn enumerate(&mut self) -> Result<Vec<Resource<USBDevice>>> {
-        let context = rusb::Context::new();
+/// Adds the attached devices that match the filter, and that the policy or the operator allows, to the table of the guest.
+/// Devices that no policy rule covers are offered to the operator if there is a chooser.
+async fn allowed_devices(view: &mut impl UsbView, filter: Option<&DeviceFilter>) -> Result<Vec<Resource<USBDevice>>> {
+    let mut devices = Vec::new();
+    let mut uncovered = Vec::new();
+
+    for device in view.usb().broker.devices()? {
+        let device = USBDevice { device: device.into() };
+
+        if let Some(filter) = filter {
+            match matches_filter(filter, device.device.as_ref()) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(error) => {
+                    debug!(%device, ?error, "Could not read the device to match it against the filter");
+                    continue;
+                }
+            }
+        }
+
+        match view.usb().evaluate(device.device.as_ref()) {
+            Ok(_) => devices.push(device),
+            Err(Rejection::NoMatchingRule) if view.usb().chooser.is_some() => uncovered.push(device),
+            Err(rejection) => debug!(%device, %rejection, "Not enumerating the device"),
+        }
+    }
+
+    if !uncovered.is_empty() {
+        devices.extend(view.usb().ask(uncovered).await?);
+    }
+
+    Span::current().record("count", devices.len());
+    view.usb().usage.add_devices(devices.len())?;
+
+    devices
+        .into_iter()
+        .map(|device| {
+            view.usb().reported.insert((device.device.bus_number(), device.device.address()));
+            let device = view.usb().captured(device);
+            view.table()
+                .push(device)
+                .map_err(Error::from)
+        })
+        .collect()
+}
 
-        let allowed_devices: AllowedUSBDevices = self.allowed_devices.to_owned();
+/// The BOS descriptor exists from USB 2.1 on. Older devices, and newer devices that stall the request, have none.
+fn read_bos_descriptor(device: &dyn BackendDevice) -> Result<Option<BosDescriptor>, DeviceHandleError> {
+    let version = device.device_descriptor()?.usb_version;
+    if (version.major, version.minor, version.subminor) < (2, 0, 1) {
+        return Ok(None);
+    }
+
+    match device.read_descriptor(DESCRIPTOR_BOS, 0, 0) {
+        Ok(bytes) => raw_descriptor::parse_bos_descriptor(&bytes).map(Some).ok_or(DeviceHandleError::BadDescriptor),
+        Err(DeviceHandleError::Pipe) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Whether the device matches every field of the filter that is set.
+/// The configurations and the serial number are only read when the descriptor matches and the filter needs them.
+fn matches_filter(filter: &DeviceFilter, device: &dyn BackendDevice) -> Result<bool, DeviceHandleError> {
+    let descriptor = device.device_descriptor()?;
+
+    let matches_descriptor = filter.vendor_id.map_or(true, |v| descriptor.vendor_id == v)
+        && filter.product_id.map_or(true, |v| descriptor.product_id == v)
+        && filter.class_code.map_or(true, |v| descriptor.device_class == v)
+        && filter.subclass_code.map_or(true, |v| descriptor.device_subclass == v)
+        && filter.protocol_code.map_or(true, |v| descriptor.device_protocol == v);
 
-        context?
-            .devices()?
+    if !matches_descriptor {
+        return Ok(false);
+    }
+
+    if filter.interface_class.is_some() || filter.interface_subclass.is_some() || filter.interface_protocol.is_some() {
+        let has_interface = device
+            .configurations()?
             .iter()
-            .map(|device| USBDevice { device })
-            .filter(|device| {
-                device.identifier()
-                    .map(|device| allowed_devices.is_allowed(&device))
-                    .unwrap_or(false)
-            })
-            .map(|device| {
-                self.table()
-                    .push(device)
-                    .map_err(Error::from)
-            })
-            .collect()
+            .flat_map(|c| c.interfaces.iter())
+            .flat_map(|i| i.alternate_settings.iter())
+            .any(|i| {
+                filter.interface_class.map_or(true, |v| i.class_code == v)
+                    && filter.interface_subclass.map_or(true, |v| i.subclass_code == v)
+                    && filter.interface_protocol.map_or(true, |v| i.protocol == v)
+            });
+
+        if !has_interface {
+            return Ok(false);
+        }
+    }
+
+    if let Some(serial_number) = &filter.serial_number {
+        return Ok(device.serial_number()?.as_ref() == Some(serial_number));
     }
+
+    Ok(true)
 }
 
-// #[async_trait]
-// impl<T> world::device::Host for T
-// where
-//     T: WasiView
-// {
-    // async fn get_devices(&mut self) -> Result<Vec<Resource<MyDevice<rusb::Context>>>> {
-    //     let context = rusb::Context::new();
-
-    //     context?
-    //         .devices()?
-    //         .iter()
-    //         .map(|device| {
-    //             self
-    //                 .table()
-    //                 .push(MyDevice { device })
-    //                 .map_err(Error::from)
-    //         })
-    //         .collect()
-    // }
-
-    // async fn request_device(&mut self, filter: DeviceFilter) -> Result<Option<Resource<MyDevice<rusb::Context>>>> {
-    //     let context = rusb::Context::new();
-
-    //     let device = context?
-    //         .devices()?
-    //         .iter()
-    //         .find(|device| {
-    //             let Ok(descriptor) = device.device_descriptor() else { return false };
-
-    //             filter.class_code.map_or(true, |v| descriptor.class_code() == v)
-    //             && filter.subclass_code.map_or(true, |v| descriptor.sub_class_code() == v)
-    //             && filter.product_id.map_or(true, |v| descriptor.product_id() == v)
-    //             && filter.protocol_code.map_or(true, |v| descriptor.protocol_code() == v)
-    //             && filter.vendor_id.map_or(true, |v| descriptor.vendor_id() == v)
-    //             && filter.serial_number.as_ref().map_or(true, |v| {
-    //                 let Ok(device) = device.open() else { return false };
-
-    //                 let Ok(languages) = device.read_languages(DEFAULT_TIMEOUT) else { return false };
-    //                 let Some(language) = languages.first() else { return false };
-
-    //                 let Ok(serial_number) = device.read_serial_number_string(*language, &descriptor, DEFAULT_TIMEOUT) else { return false };
-
-    //                 serial_number == *v
-    //             })
-    //         });
-
-    //     let Some(device) = device else { return Ok(None) };
-
-    //     let resource = self
-    //         .table()
-    //         .push(MyDevice { device })
-    //         .map_err(Error::from)?;
-
-    //     Ok(Some(resource))
-    // }
-// }
+/// Opens the device if the policy or the operator allows it and no other guest has it open.
+async fn open_device(view: &mut impl UsbView, device: &Resource<USBDevice>) -> Result<Result<Resource<DeviceHandle>, DeviceHandleError>> {
+    let ctx = view.usb();
+    let guest = ctx.guest.clone();
+    let broker = ctx.broker.clone();
+
+    let resource = view
+        .table()
+        .get(device)?
+        .clone();
+    resource.record();
+
+    let grant = match view.usb().evaluate(resource.device.as_ref()) {
+        Ok(grant) => grant,
+        Err(Rejection::NoMatchingRule) if view.usb().chooser.is_some() => {
+            if view.usb().ask(vec![resource.clone()]).await?.is_empty() {
+                debug!("Refusing to open the device: the operator did not grant it");
+                return Ok(Err(DeviceHandleError::Access));
+            }
+            Grant::chosen()
+        }
+        Err(rejection) => {
+            debug!(%rejection, "Refusing to open the device");
+            return Ok(Err(DeviceHandleError::Access));
+        }
+    };
+
+    let bus_number = resource.device.bus_number();
+    let device_address = resource.device.address();
+
+    if guest.is_revoked(bus_number, device_address) {
+        debug!("Refusing to open the device: it was revoked");
+        return Ok(Err(DeviceHandleError::Access));
+    }
+
+    view.usb().usage.add_handle()?;
+
+    if let Err(error) = broker.acquire(guest.id, bus_number, device_address).await {
+        view.usb().usage.remove_handle();
+        return Ok(Err(error));
+    }
+
+    let opened = view
+        .table()
+        .get(device)?
+        .device
+        .open();
+
+    let mut handle = match opened {
+        Ok(handle) => handle,
+        Err(error) => {
+            broker.release(guest.id, bus_number, device_address);
+            view.usb().usage.remove_handle();
+            return Ok(Err(error));
+        }
+    };
+
+    // A device granted once is asked for again the next time the guest opens it.
+    view.usb().once.remove(&(bus_number, device_address));
+
+    if grant.detach_kernel_driver {
+        _ = handle.set_auto_detach_kernel_driver(true);
+    }
+
+    let resource = view.table().get(device)?;
+    let identifier = resource.identifier().ok().map(|identifier| identifier.to_string());
+    let handle = DeviceHandle::new(resource.device.as_ref(), handle, grant);
+    guest.opened(bus_number, device_address, identifier, handle.handle.clone());
+
+    let resource = view
+        .table()
+        .push(handle)?;
+
+    Ok(Ok(resource))
+}
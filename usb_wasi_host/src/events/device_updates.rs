@@ -1,73 +1,17 @@
 use anyhow::Result;
-use tokio::{sync::mpsc, task::JoinHandle};
-use rusb::{UsbContext, Hotplug};
+use tokio::sync::mpsc;
+use crate::backend::{HotplugRegistration, UsbBackend};
 use crate::device::usbdevice::USBDevice;
 
-struct DeviceUpdateHandler {
-	sender: mpsc::Sender<DeviceConnectionEvent>
-}
-
-impl DeviceUpdateHandler {
-	fn new(buffer_size: usize) -> Result<(mpsc::Receiver<DeviceConnectionEvent>, rusb::Registration<rusb::Context>, tokio::task::JoinHandle<()>)> {
-		let (sender, receiver) = mpsc::channel::<DeviceConnectionEvent>(buffer_size);
-
-		let handler = DeviceUpdateHandler {
-			sender
-		};
-
-		let (registration, task) = handler.start_listener()?;
-		Ok((receiver, registration, task))
-	}
-}
-
-impl DeviceUpdateHandler where {
-	fn start_listener(self) -> Result<(rusb::Registration<rusb::Context>, tokio::task::JoinHandle<()>)> {
-		let context = rusb::Context::new().unwrap();
-		let reg: Result<rusb::Registration<rusb::Context>, rusb::Error> = rusb::HotplugBuilder::new()
-			.enumerate(true)
-			.register(&context, Box::new(self));
-
-		let task = tokio::task::spawn_blocking(move || {
-			loop {
-				if let Err(e) = context.handle_events(None) {
-					println!("Got Error! {:?}", e);
-					break;
-				}
-			}
-		});
-
-		Ok((reg?, task))
-	}
-}
-
+#[derive(Clone)]
 pub enum DeviceConnectionEvent {
 	Connected(USBDevice),
 	Disconnected(USBDevice)
 }
 
-impl Hotplug<rusb::Context> for DeviceUpdateHandler {
-	fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
-		let sender = self.sender.clone();
-
-		let mydevice = USBDevice { device };
-		// sender.blocking_send cannot be used here, so a new task is created.
-		// Blocking send will cause the main thread to panic.
-		tokio::spawn(async move {
-			let _ = sender.send(DeviceConnectionEvent::Connected(mydevice)).await;
-		});
-	}
-
-	fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
-		let sender = self.sender.clone();
-
-		let mydevice = USBDevice { device };
-
-		tokio::spawn(async move {
-			let _ = sender.send(DeviceConnectionEvent::Disconnected(mydevice)).await;
-		});
-	}
-}
+pub fn device_connection_updates(backend: &dyn UsbBackend) -> Result<(mpsc::UnboundedReceiver<DeviceConnectionEvent>, HotplugRegistration)> {
+	let (sender, receiver) = mpsc::unbounded_channel::<DeviceConnectionEvent>();
 
-pub fn device_connection_updates() -> Result<(mpsc::Receiver<DeviceConnectionEvent>, rusb::Registration<rusb::Context>, JoinHandle<()>)> {
-	DeviceUpdateHandler::new(10)
+	let registration = backend.watch(sender)?;
+	Ok((receiver, registration))
 }
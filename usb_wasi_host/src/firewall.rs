@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::policy::IdPattern;
+
+const REQUEST_SET_ADDRESS: u8 = 0x05;
+const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+const REQUEST_DFU_DETACH: u8 = 0x00;
+const REQUEST_DFU_DNLOAD: u8 = 0x01;
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xfe;
+const SUBCLASS_DFU: u8 = 0x01;
+
+/// The fields of a control transfer's setup packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlAction {
+    Allow,
+    Deny,
+    /// Allows the request and logs it.
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestKind {
+    Standard,
+    Class,
+    Vendor,
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Recipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferDirection {
+    In,
+    Out,
+}
+
+/// A rule matching control requests. Every field that is set must match for the rule to apply.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlRule {
+    pub name: Option<String>,
+    pub action: ControlAction,
+    #[serde(rename = "type")]
+    pub kind: Option<RequestKind>,
+    pub recipient: Option<Recipient>,
+    pub direction: Option<TransferDirection>,
+    pub request: Option<u8>,
+    pub value: Option<IdPattern>,
+    pub index: Option<IdPattern>,
+    /// For requests to an interface, the class of the interface named by the index.
+    pub interface_class: Option<u8>,
+    pub interface_subclass: Option<u8>,
+}
+
+/// Decides which control requests a guest may send to a device.
+/// Rules granted for the device are evaluated first, followed by the built-in rules
+/// that block address changes, configuration changes and firmware updates.
+/// Requests that no rule matches are allowed.
+#[derive(Debug, Clone)]
+pub struct ControlFirewall {
+    rules: Vec<ControlRule>,
+}
+
+impl SetupPacket {
+    pub fn kind(&self) -> RequestKind {
+        match (self.request_type >> 5) & 0x03 {
+            0 => RequestKind::Standard,
+            1 => RequestKind::Class,
+            2 => RequestKind::Vendor,
+            _ => RequestKind::Reserved,
+        }
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        match self.request_type & 0x1f {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            _ => Recipient::Other,
+        }
+    }
+
+    pub fn direction(&self) -> TransferDirection {
+        if self.request_type & 0x80 != 0 {
+            TransferDirection::In
+        } else {
+            TransferDirection::Out
+        }
+    }
+}
+
+impl fmt::Display for SetupPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request type {:#04x}, request {:#04x}, value {:#06x}, index {:#06x}, length {}",
+            self.request_type, self.request, self.value, self.index, self.length
+        )
+    }
+}
+
+impl ControlRule {
+    fn new(name: &str, kind: RequestKind, recipient: Recipient, request: u8) -> Self {
+        Self {
+            name: Some(name.to_owned()),
+            action: ControlAction::Deny,
+            kind: Some(kind),
+            recipient: Some(recipient),
+            direction: Some(TransferDirection::Out),
+            request: Some(request),
+            value: None,
+            index: None,
+            interface_class: None,
+            interface_subclass: None,
+        }
+    }
+
+    fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("\"{}\"", name),
+            None => format!("#{}", index + 1),
+        }
+    }
+
+    fn matches(&self, setup: &SetupPacket, interface_classes: &HashMap<u8, (u8, u8)>) -> bool {
+        let matches_setup = self.kind.map_or(true, |v| setup.kind() == v)
+            && self.recipient.map_or(true, |v| setup.recipient() == v)
+            && self.direction.map_or(true, |v| setup.direction() == v)
+            && self.request.map_or(true, |v| setup.request == v)
+            && self.value.map_or(true, |p| p.matches(setup.value))
+            && self.index.map_or(true, |p| p.matches(setup.index));
+
+        if !matches_setup {
+            return false;
+        }
+
+        if self.interface_class.is_none() && self.interface_subclass.is_none() {
+            return true;
+        }
+
+        if setup.recipient() != Recipient::Interface {
+            return false;
+        }
+
+        let Some(&(class, subclass)) = interface_classes.get(&(setup.index as u8)) else { return false };
+
+        self.interface_class.map_or(true, |v| class == v)
+            && self.interface_subclass.map_or(true, |v| subclass == v)
+    }
+}
+
+impl ControlFirewall {
+    pub fn new(granted: Vec<ControlRule>) -> Self {
+        let mut rules = granted;
+        rules.extend(Self::default_rules());
+
+        Self { rules }
+    }
+
+    fn default_rules() -> Vec<ControlRule> {
+        let dfu = |name: &str, request: u8| ControlRule {
+            interface_class: Some(CLASS_APPLICATION_SPECIFIC),
+            interface_subclass: Some(SUBCLASS_DFU),
+            ..ControlRule::new(name, RequestKind::Class, Recipient::Interface, request)
+        };
+
+        vec![
+            ControlRule::new("SET_ADDRESS", RequestKind::Standard, Recipient::Device, REQUEST_SET_ADDRESS),
+            ControlRule::new("SET_CONFIGURATION", RequestKind::Standard, Recipient::Device, REQUEST_SET_CONFIGURATION),
+            dfu("DFU_DETACH", REQUEST_DFU_DETACH),
+            dfu("DFU_DNLOAD", REQUEST_DFU_DNLOAD),
+        ]
+    }
+
+    /// Returns the label of the rule that denies the request, if any.
+    /// `interface_classes` maps interface numbers to their class and subclass.
+    pub fn check(&self, setup: &SetupPacket, interface_classes: &HashMap<u8, (u8, u8)>) -> Result<(), String> {
+        let Some((index, rule)) = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(setup, interface_classes))
+        else {
+            return Ok(());
+        };
+
+        match rule.action {
+            ControlAction::Allow => Ok(()),
+            ControlAction::Deny => Err(rule.label(index)),
+            ControlAction::Log => {
+                tracing::info!(%setup, rule = %rule.label(index), "Control request matched a logging rule");
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Gives WebAssembly components access to USB devices through the `component:usb` interfaces.
+//!
+//! Create a `UsbHost` once, add the host functions to a linker with `add_to_linker`, and give
+//! every store a `UsbCtx` from `UsbHost::guest`, exposed through the `UsbView` trait:
+//!
+//! ```ignore
+//! let host = UsbHost::builder().contention(Contention::Queue).build()?;
+//!
+//! let mut linker = Linker::<MyState>::new(&engine);
+//! wasmtime_wasi::add_to_linker_async(&mut linker)?;
+//! usb_wasi_host::add_to_linker(&mut linker)?;
+//!
+//! let usb = host.guest("driver.wasm").policy(Policy::from_file(path)?).build()?;
+//! let store = Store::new(&engine, MyState { table, wasi, usb });
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use wasmtime::component::Linker;
+
+pub mod audit;
+pub mod backend;
+pub mod broker;
+pub mod capture;
+pub mod compat;
+mod conversion;
+mod ctx;
+pub mod device;
+pub mod events;
+pub mod firewall;
+pub mod limits;
+pub mod metrics;
+pub mod outcome;
+pub mod policy;
+pub mod prompt;
+mod raw_descriptor;
+pub mod reactor;
+pub mod registry;
+mod trace;
+
+pub use conversion::{error_name, hex};
+pub use ctx::{UsbCtx, UsbView};
+
+use audit::AuditLog;
+use backend::{LibusbBackend, UsbBackend};
+use broker::{Contention, DeviceBroker};
+use capture::Capture;
+use events::DeviceConnectionEvent;
+use limits::Limits;
+use policy::Policy;
+use prompt::{Chooser, Prompt};
+use registry::{EventHook, HostEvent, Registry};
+
+pub mod bindings {
+    wasmtime::component::bindgen!({
+        world: "component:usb/driver",
+        async: true,
+        with: {
+            "component:usb/usb/usb-device": crate::device::usbdevice::USBDevice,
+            "component:usb/usb/device-handle": crate::device::devicehandle::DeviceHandle,
+        },
+        path: "wit"
+    });
+}
+
+/// Adds the `component:usb` host functions to the linker, in version 0.3.0 and in version 0.2.0 for older components.
+pub fn add_to_linker<T: UsbView>(linker: &mut Linker<T>) -> Result<()> {
+    bindings::Driver::add_to_linker(linker, |view| view)?;
+    compat::bindings::Driver::add_to_linker(linker, |view| view)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct USBDeviceIdentifier {
+    pub vendor_id: u16,
+    pub product_id: u16
+}
+
+impl FromStr for USBDeviceIdentifier {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 2 {
+            return Err("Invalid format. Expected vendor_id:product_id");
+        }
+
+        let vendor_id = u16::from_str_radix(parts[0], 16).map_err(|_| "Invalid vendor_id")?;
+        let product_id = u16::from_str_radix(parts[1], 16).map_err(|_| "Invalid product_id")?;
+
+        Ok(Self { vendor_id, product_id })
+    }
+}
+
+impl fmt::Display for USBDeviceIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)
+    }
+}
+
+/// The devices and guests of a host. All guests created from one `UsbHost` share its devices.
+pub struct UsbHost {
+    broker: Arc<DeviceBroker>,
+    registry: Arc<Registry>,
+    capture: Option<Arc<Capture>>,
+    audit: Option<Arc<AuditLog>>,
+    chooser: Option<Arc<Chooser>>,
+}
+
+/// Configures a `UsbHost`. By default, the host uses libusb, denies a device to a guest while another guest has it open,
+/// and does not capture traffic.
+#[derive(Default)]
+pub struct UsbHostBuilder {
+    backend: Option<Arc<dyn UsbBackend>>,
+    contention: Contention,
+    capture: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    prompt: Option<Box<dyn Prompt>>,
+    permissions_file: Option<PathBuf>,
+    hooks: Vec<EventHook>,
+}
+
+/// Configures the `UsbCtx` of a guest. By default, the guest may not see any device and has no limits
+/// besides the default maximum transfer size.
+pub struct GuestBuilder<'a> {
+    host: &'a UsbHost,
+    name: PathBuf,
+    policy: Arc<Policy>,
+    limits: Limits,
+    component_sha256: Option<String>,
+}
+
+impl UsbHost {
+    pub fn builder() -> UsbHostBuilder {
+        UsbHostBuilder::default()
+    }
+
+    pub fn broker(&self) -> &Arc<DeviceBroker> {
+        &self.broker
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Starts configuring a new guest. `name` identifies it in events and logs, usually the path of its component.
+    pub fn guest(&self, name: impl Into<PathBuf>) -> GuestBuilder<'_> {
+        GuestBuilder {
+            host: self,
+            name: name.into(),
+            policy: Arc::new(Policy::default()),
+            limits: Limits::default(),
+            component_sha256: None,
+        }
+    }
+}
+
+impl UsbHostBuilder {
+    /// Serves the devices of `backend` instead of the devices attached through libusb.
+    pub fn backend(mut self, backend: Arc<dyn UsbBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    pub fn contention(mut self, contention: Contention) -> Self {
+        self.contention = contention;
+        self
+    }
+
+    /// Records the transfers of all guests to a pcapng file.
+    pub fn capture(mut self, path: impl AsRef<Path>) -> Self {
+        self.capture = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Appends the devices that guests open, the interfaces they claim, their control requests and resets,
+    /// and the bytes they transfer to a hash-chained audit log. See `audit::AuditLog`.
+    pub fn audit_log(mut self, path: impl AsRef<Path>) -> Self {
+        self.audit_log = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Asks the operator through `prompt` when a guest enumerates or opens devices that no rule of its policy covers.
+    /// The guest only sees the devices the operator grants, and may claim their interfaces but not reset or reconfigure them.
+    /// See `prompt::Chooser`.
+    pub fn prompt(mut self, prompt: impl Prompt + 'static) -> Self {
+        self.prompt = Some(Box::new(prompt));
+        self
+    }
+
+    /// Where devices that the operator grants for good are kept, per component. Only used with `prompt`.
+    pub fn permissions_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.permissions_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Calls `hook` for every connection event, transfer and exited guest.
+    pub fn on_event(mut self, hook: impl Fn(&HostEvent) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Creates the host. Must be called within a tokio runtime.
+    pub fn build(self) -> Result<UsbHost> {
+        let backend: Arc<dyn UsbBackend> = match self.backend {
+            Some(backend) => backend,
+            None => Arc::new(LibusbBackend::new()?),
+        };
+
+        let broker = DeviceBroker::new(backend, self.contention)?;
+
+        let registry = Arc::new(Registry::new());
+        for hook in self.hooks {
+            registry.add_hook(hook);
+        }
+
+        let mut updates = broker.subscribe()?;
+        let events = registry.clone();
+        tokio::spawn(async move {
+            while let Some(event) = updates.recv().await {
+                events.publish(match event {
+                    DeviceConnectionEvent::Connected(device) => HostEvent::Connected {
+                        bus_number: device.device.bus_number(),
+                        address: device.device.address(),
+                    },
+                    DeviceConnectionEvent::Disconnected(device) => HostEvent::Disconnected {
+                        bus_number: device.device.bus_number(),
+                        address: device.device.address(),
+                    },
+                });
+            }
+        });
+
+        let capture = self.capture
+            .map(|path| Capture::create(&path).map(Arc::new))
+            .transpose()?;
+
+        let audit = self.audit_log
+            .map(|path| AuditLog::open(&path).map(Arc::new))
+            .transpose()?;
+
+        let chooser = match self.prompt {
+            Some(prompt) => Some(Arc::new(Chooser::new(prompt, self.permissions_file)?)),
+            None => None,
+        };
+
+        Ok(UsbHost { broker, registry, capture, audit, chooser })
+    }
+}
+
+impl GuestBuilder<'_> {
+    /// Decides which devices the guest may see and what it may do with them.
+    pub fn policy(mut self, policy: impl Into<Arc<Policy>>) -> Self {
+        self.policy = policy.into();
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The hex SHA-256 of the guest's component, which identifies the guest in the audit log. See `audit::file_sha256`.
+    pub fn component_sha256(mut self, component_sha256: impl Into<String>) -> Self {
+        self.component_sha256 = Some(component_sha256.into());
+        self
+    }
+
+    /// Registers the guest with the host and creates its context.
+    pub fn build(self) -> Result<UsbCtx> {
+        let guest = self.host.registry.add(self.name, self.policy.clone());
+        UsbCtx::new(guest, self.policy, self.host, self.limits, self.component_sha256)
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use wasmtime::{ResourceLimiter, StoreLimits, StoreLimitsBuilder};
+
+use crate::bindings::component::usb::types::DeviceHandleError;
+use crate::registry::Guest;
+
+/// How often the engine's epoch is incremented. Guests yield to the host at every tick,
+/// so a guest that never calls the host can still be stopped when its run time is up.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// The limits that apply to every guest. A guest that exceeds one of them is trapped, except for `max_bytes_per_second`.
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct Limits {
+    /// The maximum size of the linear memory of a guest, in bytes. A K, M or G suffix can be used.
+    #[clap(long, value_name = "BYTES", value_parser = parse_size)]
+    pub max_memory: Option<usize>,
+
+    /// The maximum wall-clock time a guest may run, in seconds.
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub max_run_time: Option<Duration>,
+
+    /// The maximum number of usb-device resources a guest may hold at the same time.
+    #[clap(long, value_name = "COUNT")]
+    pub max_devices: Option<usize>,
+
+    /// The maximum number of device handles a guest may have open at the same time.
+    #[clap(long, value_name = "COUNT")]
+    pub max_handles: Option<usize>,
+
+    /// The maximum size of a single transfer, in bytes. A K, M or G suffix can be used.
+    #[clap(long, value_name = "BYTES", value_parser = parse_size, default_value = "16M")]
+    pub max_transfer_size: usize,
+
+    /// The maximum number of bytes a guest may transfer per second, over all of its devices. A K, M or G suffix can be used.
+    /// Transfers fail with `busy` once it is reached.
+    #[clap(long, value_name = "BYTES", value_parser = parse_size)]
+    pub max_bytes_per_second: Option<usize>,
+}
+
+/// The default of `--max-transfer-size`.
+pub const DEFAULT_MAX_TRANSFER_SIZE: usize = 16 << 20;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_memory: None,
+            max_run_time: None,
+            max_devices: None,
+            max_handles: None,
+            max_transfer_size: DEFAULT_MAX_TRANSFER_SIZE,
+            max_bytes_per_second: None,
+        }
+    }
+}
+
+/// The reason a guest was trapped for exceeding its limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Devices(usize),
+    Handles(usize),
+    TransferSize { requested: u64, max: usize },
+    RunTime(Duration),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Devices(max) => write!(f, "the guest holds more than {} devices", max),
+            Self::Handles(max) => write!(f, "the guest has more than {} device handles open", max),
+            Self::TransferSize { requested, max } => write!(f, "the guest requested a transfer of {} bytes, the maximum is {}", requested, max),
+            Self::RunTime(max) => write!(f, "the guest ran for more than {:?}", max),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Tracks the resources a guest uses against its limits.
+#[derive(Debug)]
+pub struct Usage {
+    limits: Limits,
+    devices: usize,
+    handles: usize,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+impl Usage {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            devices: 0,
+            handles: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Forgets all resources, when the guest starts over with a new resource table.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.limits);
+    }
+
+    /// Called before `count` usb-device resources are given to the guest.
+    pub fn add_devices(&mut self, count: usize) -> Result<()> {
+        if let Some(max) = self.limits.max_devices.filter(|&max| self.devices + count > max) {
+            return Err(LimitExceeded::Devices(max).into());
+        }
+
+        self.devices += count;
+        Ok(())
+    }
+
+    pub fn remove_device(&mut self) {
+        self.devices = self.devices.saturating_sub(1);
+    }
+
+    /// Called before a device is opened for the guest.
+    pub fn add_handle(&mut self) -> Result<()> {
+        if let Some(max) = self.limits.max_handles.filter(|&max| self.handles >= max) {
+            return Err(LimitExceeded::Handles(max).into());
+        }
+
+        self.handles += 1;
+        Ok(())
+    }
+
+    pub fn remove_handle(&mut self) {
+        self.handles = self.handles.saturating_sub(1);
+    }
+
+    /// Checks the size of a transfer before a buffer for it is allocated.
+    pub fn check_transfer_size(&self, requested: u64) -> Result<usize> {
+        let max = self.limits.max_transfer_size;
+        if requested > max as u64 {
+            return Err(LimitExceeded::TransferSize { requested, max }.into());
+        }
+
+        Ok(requested as usize)
+    }
+
+    /// Checks, before a transfer is sent to the device, that the current one-second window is not used up.
+    /// Fails with `busy` otherwise, so the guest can try again once the window has passed. The transfer itself
+    /// may go over the limit, since only the bytes it moves are counted, so a transfer with a large buffer still
+    /// makes progress.
+    pub fn check_bandwidth(&mut self) -> Result<(), DeviceHandleError> {
+        let Some(max) = self.limits.max_bytes_per_second else { return Ok(()) };
+
+        self.advance_window();
+        if self.window_bytes >= max {
+            return Err(DeviceHandleError::Busy);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the bytes of a completed transfer to the current one-second window.
+    pub fn transferred(&mut self, bytes: usize) {
+        if self.limits.max_bytes_per_second.is_none() {
+            return;
+        }
+
+        self.advance_window();
+        self.window_bytes += bytes;
+    }
+
+    fn advance_window(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+}
+
+/// The resource limiter of a guest's store. Enforces `max_memory`, and keeps track of the size of the guest's memories.
+pub struct GuestLimiter {
+    limits: StoreLimits,
+    guest: Arc<Guest>,
+}
+
+impl GuestLimiter {
+    pub fn new(limits: &Limits, guest: Arc<Guest>) -> Self {
+        let mut builder = StoreLimitsBuilder::new().trap_on_grow_failure(true);
+        if let Some(max_memory) = limits.max_memory {
+            builder = builder.memory_size(max_memory);
+        }
+
+        Self { limits: builder.build(), guest }
+    }
+}
+
+impl ResourceLimiter for GuestLimiter {
+    fn memory_growing(&mut self, current: usize, desired: usize, maximum: Option<usize>) -> Result<bool> {
+        let allowed = self.limits.memory_growing(current, desired, maximum)?;
+        if allowed {
+            self.guest.memory_grown(desired - current);
+        }
+
+        Ok(allowed)
+    }
+
+    fn memory_grow_failed(&mut self, error: anyhow::Error) -> Result<()> {
+        self.limits.memory_grow_failed(error)
+    }
+
+    fn table_growing(&mut self, current: u32, desired: u32, maximum: Option<u32>) -> Result<bool> {
+        self.limits.table_growing(current, desired, maximum)
+    }
+
+    fn table_grow_failed(&mut self, error: anyhow::Error) -> Result<()> {
+        self.limits.table_grow_failed(error)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size {}", s))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("Invalid number of seconds {}", s))
+}
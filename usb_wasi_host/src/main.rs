@@ -1,35 +1,47 @@
-use anyhow::Result;
-use clap::Parser;
-use usb_host_wasi_view::USBHostWasiView;
-use wasmtime_wasi::bindings::Command;
-use std::{path::PathBuf, process::exit, str::FromStr};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{fs, io::IsTerminal, net::SocketAddr, path::PathBuf, process::exit, sync::Arc, time::{Duration, SystemTime}};
+use usb_wasi_host::audit;
+use usb_wasi_host::backend::ReplayBackend;
+use usb_wasi_host::broker::Contention;
+use usb_wasi_host::limits::{GuestLimiter, LimitExceeded, Limits, EPOCH_TICK};
+use usb_wasi_host::metrics::Metrics;
+use usb_wasi_host::outcome::{GuestResult, Outcome, RunResult, EXIT_REPLAY_MISMATCH};
+use usb_wasi_host::policy::Policy;
+use usb_wasi_host::prompt::{self, TerminalPrompt};
+use usb_wasi_host::reactor;
+use usb_wasi_host::registry::Guest;
+use usb_wasi_host::{UsbCtx, UsbHost, UsbView, USBDeviceIdentifier};
+use tracing::{info_span, Instrument};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+use wasi_config::{EnvVar, Preopen, WasiConfig};
 use wasmtime::{component::*, Config, Engine, Store};
+use wasmtime_wasi::bindings::Command;
+use wasmtime_wasi::{ResourceTable, WasiCtx, WasiView};
 
-use crate::bindings::Imports;
-
-mod conversion;
-mod device;
-mod events;
-mod usb_host_wasi_view;
-
-pub mod bindings {
-    wasmtime::component::bindgen!({
-        world: "component:usb/imports",
-        async: true,
-        with: {
-            "component:usb/usb/usb-device": crate::device::usbdevice::USBDevice,
-            "component:usb/usb/device-handle": crate::device::devicehandle::DeviceHandle,
-        },
-        path: "../WIT/wit"
-    });
-}
+mod admin;
+mod compile;
+mod wasi_config;
+mod watch;
 
 #[derive(Parser)]
-#[clap(name = "usb", version = env!("CARGO_PKG_VERSION"))]
+#[clap(name = "usb", version = env!("CARGO_PKG_VERSION"), args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct UsbDemoAppParser {
-    /// The path to the guest component.
-    #[clap(value_name = "COMPONENT_PATH")]
-    component_path: PathBuf,
+    #[clap(subcommand)]
+    command: Option<HostCommand>,
+
+    /// The paths to the guest components, or to components precompiled with `compile`. All guests run concurrently and share the USB devices.
+    #[clap(value_name = "COMPONENT_PATH", required = true)]
+    component_paths: Vec<PathBuf>,
+
+    /// Where compiled components are cached. Defaults to $XDG_CACHE_HOME/usb-wasi-host or ~/.cache/usb-wasi-host.
+    #[clap(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Compile components on every launch instead of using the cache.
+    #[clap(long, conflicts_with = "cache_dir")]
+    no_cache: bool,
 
     /// Comma-separated list of USB devices to allow (in hex format: vendor_id:product_id, e.g. 12AB:34CD).
     #[clap(long, value_name = "USB_DEVICES", use_value_delimiter = true)]
@@ -38,96 +50,531 @@ struct UsbDemoAppParser {
     /// Use a denylist for USB devices instead of an allowlist.
     #[clap(long)]
     usb_use_denylist: bool,
+
+    /// Path to a TOML or JSON file with the device access policy. Replaces --usb-devices and --usb-use-denylist.
+    /// Pass it once for all guests, or once per guest in the order of the components.
+    #[clap(long, value_name = "POLICY", conflicts_with_all = ["usb_devices", "usb_use_denylist"])]
+    policy: Vec<PathBuf>,
+
+    /// Ask on the terminal when a guest enumerates or opens devices that no policy rule covers. The devices are listed with
+    /// their manufacturer and product, and can be granted once, for this session, or always. Guests get no stdin.
+    #[clap(long)]
+    prompt: bool,
+
+    /// Where devices granted always with --prompt are kept.
+    /// Defaults to $XDG_CONFIG_HOME/usb-wasi-host/permissions.toml or ~/.config/usb-wasi-host/permissions.toml.
+    #[clap(long, value_name = "FILE", requires = "prompt")]
+    permissions: Option<PathBuf>,
+
+    /// What happens when a guest opens a device that another guest has open.
+    #[clap(long, value_enum, default_value_t = Contention::Deny)]
+    contention: Contention,
+
+    /// Record all USB transfers of the guest to a pcapng file that can be opened with Wireshark.
+    #[clap(long, value_name = "FILE")]
+    capture: Option<PathBuf>,
+
+    /// Serve the guest from a capture made with --capture instead of the attached devices.
+    /// Transfers that differ from the capture are reported, and make the host exit with status 4.
+    #[clap(long, value_name = "TRACE")]
+    replay: Option<PathBuf>,
+
+    /// Append the devices guests open, the interfaces they claim, their control requests and resets, and the bytes they
+    /// transfer to this hash-chained JSON-lines file. Check the file with `verify-audit`.
+    #[clap(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Serve the HTTP management API on this address, e.g. 127.0.0.1:8080.
+    #[clap(long, value_name = "ADDRESS")]
+    admin_listen: Option<SocketAddr>,
+
+    /// Serve transfer counts, latency histograms and memory usage in the Prometheus text format at http://ADDRESS/metrics.
+    #[clap(long, value_name = "ADDRESS")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Print the transfer counts, latencies and errors per device and endpoint when the host exits.
+    #[clap(long)]
+    metrics_summary: bool,
+
+    /// Write a JSON summary of the run to this file, or to stdout for -: the exit status, duration,
+    /// opened devices and transferred bytes of each guest.
+    #[clap(long, value_name = "FILE")]
+    result_json: Option<PathBuf>,
+
+    /// Path to a TOML or JSON file with the directories, environment, arguments and output files of the guest.
+    /// Pass it once for all guests, or once per guest in the order of the components.
+    #[clap(long, value_name = "FILE")]
+    wasi_config: Vec<PathBuf>,
+
+    /// Make a host directory available to the guests: HOST[::GUEST][:ro|:rw]. Directories are read-only unless :rw is given.
+    #[clap(long = "dir", value_name = "DIR")]
+    dirs: Vec<Preopen>,
+
+    /// Set an environment variable in the guests: NAME=VALUE, or NAME to pass on the host's value.
+    #[clap(long, value_name = "NAME[=VALUE]")]
+    env: Vec<EnvVar>,
+
+    /// Append the stdout of the guests to this file instead of the host's stdout.
+    #[clap(long, value_name = "FILE")]
+    stdout: Option<PathBuf>,
+
+    /// Append the stderr of the guests to this file instead of the host's stderr.
+    #[clap(long, value_name = "FILE")]
+    stderr: Option<PathBuf>,
+
+    #[clap(flatten)]
+    limits: Limits,
+
+    /// Start a guest again when its component file changes. The new version takes over the devices the old one had open
+    /// through usb-device.inherited. A guest that exits waits for a new version instead of ending the host.
+    #[clap(long)]
+    watch: bool,
+
+    /// How often the on-tick handler of drivers is called, in milliseconds.
+    #[clap(long, value_name = "MILLISECONDS", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    tick_interval: u64,
+
+    /// Which host events and calls to log, in the syntax of RUST_LOG, e.g. `debug` or `info,usb_wasi_host::device=trace`.
+    /// Host calls are logged at the debug level, with their device, endpoint, sizes, result and duration.
+    /// Defaults to RUST_LOG, or `info` if it is not set.
+    #[clap(long, value_name = "FILTER")]
+    log: Option<String>,
+
+    /// Whether log lines are written as text or as JSON objects with the fields of the event and its spans.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Arguments passed to the guests after their name.
+    #[clap(last = true, value_name = "ARGS")]
+    args: Vec<String>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct USBDeviceIdentifier {
-    vendor_id: u16,
-    product_id: u16
+#[derive(Subcommand)]
+enum HostCommand {
+    /// Compile a component ahead of time into a .cwasm file, which can be passed to the host instead of the component.
+    /// The file only works with this version of the host.
+    Compile {
+        #[clap(value_name = "COMPONENT_PATH")]
+        component: PathBuf,
+
+        /// Where to write the compiled component. Defaults to the component path with the .cwasm extension.
+        #[clap(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Check that no entry of an audit log written with --audit-log was modified, removed or inserted.
+    /// Prints the hash of the last entry, which should be kept elsewhere to detect entries removed from the end.
+    VerifyAudit {
+        #[clap(value_name = "FILE")]
+        log: PathBuf,
+    },
 }
 
-impl FromStr for USBDeviceIdentifier {
-    type Err = &'static str;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() != 2 {
-            return Err("Invalid format. Expected vendor_id:product_id");
-        }
+/// Logs to stderr, so the output of the guest on stdout stays clean. The span of every host call is logged when it closes, with the time the call took.
+fn init_logging(filter: Option<&str>, format: LogFormat) -> Result<()> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
 
-        let vendor_id = u16::from_str_radix(parts[0], 16).map_err(|_| "Invalid vendor_id")?;
-        let product_id = u16::from_str_radix(parts[1], 16).map_err(|_| "Invalid product_id")?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
 
-        Ok(Self { vendor_id, product_id })
-    }
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+
+    result.map_err(|e| anyhow::anyhow!(e))
 }
 
-#[derive(Debug, Clone)]
-enum AllowedUSBDevices {
-    Allowed(Vec<USBDeviceIdentifier>),
-    Denied(Vec<USBDeviceIdentifier>)
+/// The data of a guest's store.
+struct GuestState {
+    table: ResourceTable,
+    wasi: WasiCtx,
+    usb: UsbCtx,
+    limiter: GuestLimiter,
 }
 
-impl AllowedUSBDevices {
-    fn is_allowed(&self, device: &USBDeviceIdentifier) -> bool {
-        match self {
-            Self::Allowed(devices) => devices.contains(device),
-            Self::Denied(devices) => !devices.contains(device)
-        }
+impl WasiView for GuestState {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.wasi
+    }
+}
+
+impl UsbView for GuestState {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn usb(&mut self) -> &mut UsbCtx {
+        &mut self.usb
     }
 }
 
 struct UsbDemoApp {
     engine: Engine,
-    linker: Linker<USBHostWasiView>,
-    component: Component
+    linker: Linker<GuestState>,
+    components: Vec<(PathBuf, Component)>,
+    limits: Limits,
+    tick_interval: Duration,
+    cache_dir: Option<PathBuf>,
+    watch: bool
+}
+
+/// Everything needed to run a guest, and to start it again with a new version of its component.
+struct GuestRunner {
+    engine: Engine,
+    linker: Linker<GuestState>,
+    path: PathBuf,
+    component: Component,
+    wasi_config: WasiConfig,
+    limits: Limits,
+    tick_interval: Duration,
+    cache_dir: Option<PathBuf>,
+}
+
+/// Creates the engine that components are compiled for. Precompiled components only load into an engine with the same configuration,
+/// so epoch interruption is always enabled, even without a run time limit.
+fn create_engine() -> Result<Engine> {
+    let mut config = Config::default();
+    config.wasm_component_model(true);
+    config.async_support(true);
+    config.epoch_interruption(true);
+
+    Engine::new(&config)
 }
 
 impl UsbDemoApp {
-    fn new(component: PathBuf) -> Result<Self> {
-        let mut config = Config::default();
-        config.wasm_component_model(true);
-        config.async_support(true);
+    fn new(components: &[PathBuf], limits: Limits, tick_interval: Duration, cache_dir: Option<PathBuf>, watch: bool) -> Result<Self> {
+        let engine = create_engine()?;
+
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker.increment_epoch();
+        });
 
-        let engine = Engine::new(&config)?;
         let mut linker = Linker::new(&engine);
 
         wasmtime_wasi::add_to_linker_async(&mut linker)?;
-        Imports::add_to_linker(&mut linker, |view| view)?;
+        usb_wasi_host::add_to_linker(&mut linker)?;
 
-        let component = Component::from_file(&engine, component)?;
+        let components = components
+            .iter()
+            .map(|path| Ok((path.clone(), compile::load(&engine, path, cache_dir.as_deref())?)))
+            .collect::<Result<_>>()?;
 
         Ok(Self {
             engine,
             linker,
-            component
+            components,
+            limits,
+            tick_interval,
+            cache_dir,
+            watch
         })
     }
 
-    async fn start(&mut self, allowed_devices: AllowedUSBDevices) -> anyhow::Result<Result<(), ()>> {
-        let data = USBHostWasiView::new(allowed_devices)?;
+    /// Runs every component in its own store and task, with the policy and WASI configuration at the same position.
+    /// Components that export the hotplug interface are run as drivers, all others as commands.
+    async fn start(&mut self, host: &UsbHost, policies: Vec<Arc<Policy>>, wasi_configs: Vec<WasiConfig>) -> anyhow::Result<Vec<(Arc<Guest>, Outcome)>> {
+        let mut tasks = Vec::new();
+
+        for (((path, component), policy), wasi_config) in self.components.iter().zip(policies).zip(wasi_configs) {
+            let usb = host
+                .guest(path)
+                .policy(policy)
+                .limits(self.limits)
+                .component_sha256(audit::file_sha256(path)?)
+                .build()?;
+            let guest = usb.guest().clone();
+
+            let runner = GuestRunner {
+                engine: self.engine.clone(),
+                linker: self.linker.clone(),
+                path: path.clone(),
+                component: component.clone(),
+                wasi_config,
+                limits: self.limits,
+                tick_interval: self.tick_interval,
+                cache_dir: self.cache_dir.clone(),
+            };
+            let mut store = runner.store(usb)?;
+
+            // Every host call of the guest is logged within this span.
+            let span = info_span!("guest", id = guest.id, component = %path.display());
+            let task = if self.watch {
+                tokio::spawn(runner.watch(store).instrument(span))
+            } else {
+                tokio::spawn(async move { runner.run(&mut store).await }.instrument(span))
+            };
+
+            guest.set_abort_handle(task.abort_handle());
+            tasks.push((guest, task));
+        }
+
+        let mut outcomes = Vec::new();
+        for (guest, task) in tasks {
+            // A trap ends only the guest that caused it.
+            let outcome = match task.await {
+                Ok(outcome) => outcome,
+                Err(e) if e.is_cancelled() => Outcome::Stopped,
+                Err(e) => return Err(e.into()),
+            };
+
+            outcome.report(guest.id);
+            outcomes.push((guest, outcome));
+        }
+
+        Ok(outcomes)
+    }
+}
+
+impl GuestRunner {
+    fn store(&self, usb: UsbCtx) -> Result<Store<GuestState>> {
+        let program = self.path.file_name().unwrap_or(self.path.as_os_str()).to_string_lossy();
+        let wasi = self.wasi_config.build(&program)?;
+
+        let limiter = GuestLimiter::new(&self.limits, usb.guest().clone());
+        let data = GuestState { table: ResourceTable::new(), wasi, usb, limiter };
         let mut store = Store::new(&self.engine, data);
+        store.limiter(|state| &mut state.limiter);
+
+        // Guests yield at every epoch tick, so the run time limit also applies to guests that never call the host.
+        store.epoch_deadline_async_yield_and_update(1);
+        Ok(store)
+    }
+
+    /// Runs the component as a driver if it exports the hotplug interface, and as a command otherwise.
+    async fn run(&self, store: &mut Store<GuestState>) -> Outcome {
+        let run = async {
+            if reactor::is_driver(&self.engine, &self.component) {
+                return reactor::run(&mut *store, &self.component, &self.linker, self.tick_interval).await;
+            }
+
+            let command = match Command::instantiate_async(&mut *store, &self.component, &self.linker).await {
+                Ok((command, _)) => command,
+                Err(e) => return Outcome::InstantiationFailed(e),
+            };
 
-        let (command, _) = Command::instantiate_async(&mut store, &self.component, &self.linker).await?;
+            Outcome::from_run(command.wasi_cli_run().call_run(&mut *store).await)
+        };
 
-        command.wasi_cli_run().call_run(store).await
+        match self.limits.max_run_time {
+            Some(limit) => tokio::time::timeout(limit, run)
+                .await
+                .unwrap_or_else(|_| Outcome::Trapped(LimitExceeded::RunTime(limit).into())),
+            None => run.await,
+        }
+    }
+
+    /// Runs the component, and replaces it with every new version of the component file that loads.
+    /// The `UsbCtx` moves to the store of the new version, so the guest keeps its devices.
+    /// Only ends when the guest is stopped through the admin API, or when it cannot be started again.
+    async fn watch(mut self, mut store: Store<GuestState>) -> Outcome {
+        let guest = store.data().usb.guest().id;
+        let mut modified = watch::modified(&self.path);
+
+        loop {
+            let reloaded = tokio::select! {
+                outcome = self.run(&mut store) => Err(outcome),
+                version = self.next_version(modified) => Ok(version),
+            };
+
+            let (changed, component) = match reloaded {
+                Ok(version) => version,
+                Err(outcome) => {
+                    outcome.report(guest);
+                    tracing::info!("Waiting for {} to change", self.path.display());
+                    self.next_version(modified).await
+                }
+            };
+
+            modified = changed;
+            self.component = component;
+
+            let GuestState { mut usb, .. } = store.into_data();
+            if let Err(e) = usb.reload() {
+                return Outcome::InstantiationFailed(e);
+            }
+
+            match audit::file_sha256(&self.path) {
+                Ok(sha256) => usb.set_component_sha256(sha256),
+                Err(e) => return Outcome::InstantiationFailed(e),
+            }
+
+            store = match self.store(usb) {
+                Ok(store) => store,
+                Err(e) => return Outcome::InstantiationFailed(e),
+            };
+
+            tracing::info!("Reloaded {}", self.path.display());
+        }
+    }
+
+    /// Waits for a change of the component file that loads. Versions that fail to load are reported and skipped,
+    /// so a broken build does not stop the running version.
+    async fn next_version(&self, mut modified: Option<SystemTime>) -> (Option<SystemTime>, Component) {
+        loop {
+            modified = watch::changed(&self.path, modified).await;
+
+            let (engine, path, cache_dir) = (self.engine.clone(), self.path.clone(), self.cache_dir.clone());
+            let loaded = tokio::task::spawn_blocking(move || compile::load(&engine, &path, cache_dir.as_deref()))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|loaded| loaded);
+
+            match loaded {
+                Ok(component) => return (modified, component),
+                Err(e) => tracing::warn!("Could not load the new version of {}: {:?}", self.path.display(), e),
+            }
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let parsed = UsbDemoAppParser::parse();
-    let mut app = UsbDemoApp::new(parsed.component_path)?;
+    init_logging(parsed.log.as_deref(), parsed.log_format)?;
 
-    let allowed_devices = if parsed.usb_use_denylist {
-        AllowedUSBDevices::Denied(parsed.usb_devices)
+    match parsed.command {
+        Some(HostCommand::Compile { component, output }) => {
+            let output = output.unwrap_or_else(|| component.with_extension("cwasm"));
+            compile::compile(&create_engine()?, &component, &output)?;
+            return Ok(());
+        }
+        Some(HostCommand::VerifyAudit { log }) => {
+            let verification = audit::verify(&log)?;
+            println!("{} entries, the chain is intact. Last hash: {}", verification.entries, verification.last_hash);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let cache_dir = if parsed.no_cache {
+        None
     } else {
-        AllowedUSBDevices::Allowed(parsed.usb_devices)
+        parsed.cache_dir.or_else(compile::default_cache_dir)
     };
 
-    let result = app.start(allowed_devices.to_owned()).await;
+    let mut app = UsbDemoApp::new(&parsed.component_paths, parsed.limits, Duration::from_millis(parsed.tick_interval), cache_dir, parsed.watch)?;
 
-    dbg!(result.unwrap().unwrap());
+    let guests = parsed.component_paths.len();
+    let policies = match parsed.policy.len() {
+        0 => vec![Arc::new(Policy::from_device_list(parsed.usb_devices, parsed.usb_use_denylist)); guests],
+        1 => vec![Arc::new(Policy::from_file(&parsed.policy[0])?); guests],
+        n if n == guests => parsed.policy
+            .iter()
+            .map(|path| Policy::from_file(path).map(Arc::new))
+            .collect::<Result<_>>()?,
+        n => bail!("Got {} policies for {} components, expected one policy or one per component", n, guests),
+    };
+
+    let mut wasi_configs = match parsed.wasi_config.len() {
+        0 => vec![WasiConfig::default(); guests],
+        1 => vec![WasiConfig::from_file(&parsed.wasi_config[0])?; guests],
+        n if n == guests => parsed.wasi_config
+            .iter()
+            .map(|path| WasiConfig::from_file(path))
+            .collect::<Result<_>>()?,
+        n => bail!("Got {} WASI configurations for {} components, expected one or one per component", n, guests),
+    };
+
+    for config in &mut wasi_configs {
+        config.merge(&parsed.dirs, &parsed.env, &parsed.args, parsed.stdout.as_deref(), parsed.stderr.as_deref());
+        // The chooser reads the answers of the operator from stdin.
+        config.no_stdin = parsed.prompt;
+    }
+
+    if parsed.prompt && !std::io::stdin().is_terminal() {
+        bail!("--prompt asks on the terminal, but stdin is not a terminal");
+    }
+
+    let replay = parsed.replay
+        .map(|path| ReplayBackend::open(&path).map(Arc::new))
+        .transpose()?;
+
+    let mut builder = UsbHost::builder().contention(parsed.contention);
+    if let Some(replay) = &replay {
+        builder = builder.backend(replay.clone());
+    }
+    if let Some(path) = &parsed.capture {
+        builder = builder.capture(path);
+    }
+    if let Some(path) = &parsed.audit_log {
+        builder = builder.audit_log(path);
+    }
+    if parsed.prompt {
+        builder = builder.prompt(TerminalPrompt::new());
+        if let Some(path) = parsed.permissions.or_else(prompt::default_permissions_file) {
+            builder = builder.permissions_file(path);
+        }
+    }
+
+    let metrics = (parsed.metrics_listen.is_some() || parsed.metrics_summary).then(|| Arc::new(Metrics::new()));
+    if let Some(metrics) = &metrics {
+        let metrics = metrics.clone();
+        builder = builder.on_event(move |event| metrics.record(event));
+    }
+    let host = builder.build()?;
+
+    if let Some(address) = parsed.admin_listen {
+        admin::serve(address, host.broker().clone(), host.registry().clone())?;
+    }
+
+    if let (Some(address), Some(metrics)) = (parsed.metrics_listen, &metrics) {
+        admin::serve_metrics(address, metrics.clone(), host.registry().clone())?;
+    }
+
+    let outcomes = app.start(&host, policies, wasi_configs).await?;
+
+    // The first guest that did not exit successfully decides the exit code of the host.
+    let mut exit_code = outcomes
+        .iter()
+        .map(|(_, outcome)| outcome.exit_code())
+        .find(|&code| code != 0)
+        .unwrap_or(0);
+
+    if let Some(replay) = replay {
+        let mismatches = replay.finish();
+        for mismatch in &mismatches {
+            println!("{}", serde_json::to_string(mismatch)?);
+        }
+
+        if !mismatches.is_empty() && exit_code == 0 {
+            exit_code = EXIT_REPLAY_MISMATCH;
+        }
+    }
+
+    if let (true, Some(metrics)) = (parsed.metrics_summary, &metrics) {
+        print!("{}", metrics.summary(host.registry()));
+    }
+
+    if let Some(path) = parsed.result_json {
+        let result = RunResult {
+            exit_code,
+            guests: outcomes.iter().map(|(guest, outcome)| GuestResult::new(guest, outcome)).collect(),
+        };
+
+        let json = serde_json::to_string_pretty(&result)?;
+        if path.as_os_str() == "-" {
+            println!("{}", json);
+        } else {
+            fs::write(&path, json)?;
+        }
+    }
 
-    exit(0);
+    exit(exit_code);
 }
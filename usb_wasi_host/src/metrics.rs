@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::broker::GuestId;
+use crate::registry::{HostEvent, Registry};
+
+/// The upper bounds of the transfer duration histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Counts the transfers of all guests per device, endpoint and transfer type.
+/// Add `record` as an event hook of the host, and read the metrics with `render` or `summary`.
+#[derive(Default)]
+pub struct Metrics {
+    transfers: Mutex<BTreeMap<TransferKey, TransferStats>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TransferKey {
+    guest: GuestId,
+    bus_number: u8,
+    address: u8,
+    endpoint: u8,
+    transfer: &'static str,
+}
+
+#[derive(Debug, Default)]
+struct TransferStats {
+    count: u64,
+    bytes: u64,
+    /// The number of failed transfers, by error.
+    errors: BTreeMap<&'static str, u64>,
+    /// The number of transfers in each bucket of `BUCKETS`. Transfers slower than the last bucket are only in `count`.
+    buckets: [u64; BUCKETS.len()],
+    seconds: f64,
+    max_seconds: f64,
+}
+
+impl TransferKey {
+    fn labels(&self) -> String {
+        format!(
+            "guest=\"{}\",device=\"{:03}:{:03}\",endpoint=\"0x{:02x}\",transfer=\"{}\"",
+            self.guest, self.bus_number, self.address, self.endpoint, self.transfer
+        )
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a transfer event to the metrics. Other events are ignored.
+    pub fn record(&self, event: &HostEvent) {
+        let &HostEvent::Transfer { guest, bus_number, address, transfer, endpoint, transferred, error, duration_seconds, .. } = event else { return };
+
+        let key = TransferKey { guest, bus_number, address, endpoint, transfer: transfer.name() };
+        let mut transfers = self.transfers.lock().unwrap();
+        let stats = transfers.entry(key).or_default();
+
+        stats.count += 1;
+        stats.bytes += transferred.unwrap_or(0) as u64;
+        if let Some(error) = error {
+            *stats.errors.entry(error).or_default() += 1;
+        }
+
+        if let Some(bucket) = BUCKETS.iter().position(|&le| duration_seconds <= le) {
+            stats.buckets[bucket] += 1;
+        }
+        stats.seconds += duration_seconds;
+        stats.max_seconds = stats.max_seconds.max(duration_seconds);
+    }
+
+    /// The metrics in the Prometheus text format, together with the memory of the guests and the host.
+    pub fn render(&self, registry: &Registry) -> String {
+        let transfers = self.transfers.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "usb_transfers_total", "counter", "USB transfers by guest, device, endpoint and transfer type.");
+        for (key, stats) in transfers.iter() {
+            let _ = writeln!(out, "usb_transfers_total{{{}}} {}", key.labels(), stats.count);
+        }
+
+        header(&mut out, "usb_transfer_bytes_total", "counter", "Bytes transferred, in either direction.");
+        for (key, stats) in transfers.iter() {
+            let _ = writeln!(out, "usb_transfer_bytes_total{{{}}} {}", key.labels(), stats.bytes);
+        }
+
+        header(&mut out, "usb_transfer_errors_total", "counter", "Failed transfers by error.");
+        for (key, stats) in transfers.iter() {
+            for (error, count) in &stats.errors {
+                let _ = writeln!(out, "usb_transfer_errors_total{{{},error=\"{}\"}} {}", key.labels(), error, count);
+            }
+        }
+
+        header(&mut out, "usb_transfer_duration_seconds", "histogram", "How long transfers took, including timeouts.");
+        for (key, stats) in transfers.iter() {
+            let labels = key.labels();
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "usb_transfer_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+            }
+            let _ = writeln!(out, "usb_transfer_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, stats.count);
+            let _ = writeln!(out, "usb_transfer_duration_seconds_sum{{{}}} {}", labels, stats.seconds);
+            let _ = writeln!(out, "usb_transfer_duration_seconds_count{{{}}} {}", labels, stats.count);
+        }
+
+        header(&mut out, "usb_guest_memory_bytes", "gauge", "The size of the linear memories of a guest.");
+        for guest in registry.guests().iter().filter(|guest| guest.is_running()) {
+            let component = escape(&guest.component.display().to_string());
+            let _ = writeln!(out, "usb_guest_memory_bytes{{guest=\"{}\",component=\"{}\"}} {}", guest.id, component, guest.memory_size());
+        }
+
+        if let Some(memory) = memory_stats::memory_stats() {
+            header(&mut out, "process_resident_memory_bytes", "gauge", "Resident memory of the host process.");
+            let _ = writeln!(out, "process_resident_memory_bytes {}", memory.physical_mem);
+            header(&mut out, "process_virtual_memory_bytes", "gauge", "Virtual memory of the host process.");
+            let _ = writeln!(out, "process_virtual_memory_bytes {}", memory.virtual_mem);
+        }
+
+        out
+    }
+
+    /// A table of the transfers per guest, device and endpoint, and the memory of the guests and the host.
+    pub fn summary(&self, registry: &Registry) -> String {
+        let transfers = self.transfers.lock().unwrap();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "{:>5}  {:<7}  {:<8}  {:<9}  {:>8}  {:>6}  {:>12}  {:>9}  {:>9}", "guest", "device", "endpoint", "type", "count", "errors", "bytes", "mean ms", "max ms");
+        for (key, stats) in transfers.iter() {
+            let errors: u64 = stats.errors.values().sum();
+            let mean = stats.seconds / stats.count as f64;
+            let _ = writeln!(
+                out,
+                "{:>5}  {:03}:{:03}  0x{:02x}      {:<9}  {:>8}  {:>6}  {:>12}  {:>9.3}  {:>9.3}",
+                key.guest, key.bus_number, key.address, key.endpoint, key.transfer,
+                stats.count, errors, stats.bytes, mean * 1000.0, stats.max_seconds * 1000.0
+            );
+
+            for (error, count) in &stats.errors {
+                let _ = writeln!(out, "{:>44}{}: {}", "", error, count);
+            }
+        }
+
+        for guest in registry.guests() {
+            let _ = writeln!(out, "Guest {} memory: {} bytes", guest.id, guest.memory_size());
+        }
+
+        if let Some(memory) = memory_stats::memory_stats() {
+            let _ = writeln!(out, "Host resident memory: {} bytes", memory.physical_mem);
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value of the text format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,147 @@
+use anyhow::{Error, Result};
+use serde::Serialize;
+use wasmtime::{Trap, WasmBacktrace};
+use wasmtime_wasi::I32Exit;
+
+use crate::broker::GuestId;
+use crate::registry::{DeviceUsage, Guest};
+
+/// The exit code of a guest whose `run` returned an error.
+pub const EXIT_FAILURE: i32 = 1;
+
+// The exit codes of the host. The code a guest exited with is only reported through `--result-json`,
+// so it cannot be mistaken for a failure of the host. The host exits with 1 when it fails itself,
+// and with 2 when its arguments are invalid.
+
+/// The host exit code when a guest exited with a code other than 0.
+pub const EXIT_GUEST_FAILED: i32 = 3;
+/// The host exit code when the guests succeeded, but their transfers differed from the replayed capture.
+pub const EXIT_REPLAY_MISMATCH: i32 = 4;
+/// The host exit code when a guest could not be instantiated.
+pub const EXIT_INSTANTIATION_FAILED: i32 = 126;
+/// The host exit code when a guest was stopped through the admin API.
+pub const EXIT_STOPPED: i32 = 130;
+/// The host exit code when a guest trapped, the same as an aborted process.
+pub const EXIT_TRAPPED: i32 = 134;
+
+/// How a guest ended.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The guest returned from `run` or called `wasi:cli/exit` with this exit code.
+    Exited(i32),
+    Trapped(Error),
+    InstantiationFailed(Error),
+    Stopped,
+}
+
+impl Outcome {
+    pub fn from_run(result: Result<Result<(), ()>>) -> Self {
+        match result {
+            Ok(Ok(())) => Self::Exited(0),
+            Ok(Err(())) => Self::Exited(EXIT_FAILURE),
+            Err(e) => match e.downcast_ref::<I32Exit>() {
+                Some(exit) => Self::Exited(exit.0),
+                None => Self::Trapped(e),
+            },
+        }
+    }
+
+    /// The exit code of the host for this outcome.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Exited(0) => 0,
+            Self::Exited(_) => EXIT_GUEST_FAILED,
+            Self::Trapped(_) => EXIT_TRAPPED,
+            Self::InstantiationFailed(_) => EXIT_INSTANTIATION_FAILED,
+            Self::Stopped => EXIT_STOPPED,
+        }
+    }
+
+    /// The code the guest exited with, if it exited rather than being stopped.
+    pub fn guest_exit_code(&self) -> Option<i32> {
+        match self {
+            Self::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self {
+            Self::Exited(_) => "exited",
+            Self::Trapped(_) => "trapped",
+            Self::InstantiationFailed(_) => "instantiation-failed",
+            Self::Stopped => "stopped",
+        }
+    }
+
+    fn error(&self) -> Option<&Error> {
+        match self {
+            Self::Trapped(e) | Self::InstantiationFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Prints how the guest ended. The error of a trap includes the wasm backtrace.
+    pub fn report(&self, guest: GuestId) {
+        match self {
+            Self::Exited(0) => tracing::info!(guest, "Guest exited"),
+            Self::Exited(code) => tracing::warn!(guest, code, "Guest exited with an error"),
+            Self::Trapped(e) => tracing::error!(guest, "Guest trapped: {:?}", e),
+            Self::InstantiationFailed(e) => tracing::error!(guest, "Guest could not be instantiated: {:?}", e),
+            Self::Stopped => tracing::info!(guest, "Guest was stopped"),
+        }
+    }
+}
+
+/// The summary written by `--result-json`.
+#[derive(Debug, Serialize)]
+pub struct RunResult {
+    /// The exit code of the host.
+    pub exit_code: i32,
+    pub guests: Vec<GuestResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GuestResult {
+    pub guest: GuestId,
+    pub component: String,
+    pub status: &'static str,
+    /// The code the guest exited with, if its status is `exited`.
+    pub exit_code: Option<i32>,
+    /// The trap code, such as `unreachable`, if the guest trapped on one.
+    pub trap: Option<String>,
+    pub error: Option<String>,
+    pub backtrace: Vec<String>,
+    pub duration_seconds: f64,
+    pub devices: Vec<DeviceUsage>,
+}
+
+impl GuestResult {
+    pub fn new(guest: &Guest, outcome: &Outcome) -> Self {
+        let error = outcome.error();
+
+        let backtrace = error
+            .and_then(|e| e.downcast_ref::<WasmBacktrace>())
+            .map(|backtrace| backtrace.to_string().lines().map(|line| line.trim().to_string()).collect())
+            .unwrap_or_default();
+
+        Self {
+            guest: guest.id,
+            component: guest.component.display().to_string(),
+            status: outcome.status(),
+            exit_code: outcome.guest_exit_code(),
+            trap: error.and_then(|e| e.downcast_ref::<Trap>()).map(|trap| trap.to_string()),
+            error: error.map(|e| {
+                // The backtrace has its own field.
+                e.chain()
+                    .filter(|cause| !cause.is::<WasmBacktrace>())
+                    .map(|cause| cause.to_string())
+                    .collect::<Vec<_>>()
+                    .join(": ")
+            }),
+            backtrace,
+            duration_seconds: guest.duration().as_secs_f64(),
+            devices: guest.device_usage(),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::BackendDevice;
+use crate::firewall::ControlRule;
+use crate::bindings::component::usb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use crate::bindings::component::usb::types::DeviceHandleError;
+use crate::USBDeviceIdentifier;
+
+/// Decides which devices a guest is allowed to see and open.
+/// Rules are evaluated in order and the first matching rule wins.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The action for devices that no rule matches.
+    #[serde(default)]
+    pub default: Action,
+
+    /// What the guest may do with devices that are allowed by `default`.
+    #[serde(default)]
+    pub grant: Grant,
+
+    #[serde(default, rename = "rule", alias = "rules")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// A single policy rule. Every field that is set must match for the rule to apply.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Shown in log messages when the rule rejects a device.
+    pub name: Option<String>,
+    pub action: Action,
+    pub vendor_id: Option<IdPattern>,
+    pub product_id: Option<IdPattern>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub protocol: Option<u8>,
+    /// Matches if any interface of any configuration has this class.
+    pub interface_class: Option<u8>,
+    pub serial_number: Option<String>,
+    pub bus: Option<u8>,
+    /// The chain of hub ports leading to the device, e.g. `[1, 4]`.
+    pub port_path: Option<Vec<u8>>,
+    /// What the guest may do with the device if the rule allows it.
+    #[serde(default)]
+    pub grant: Grant,
+}
+
+/// The operations a guest may perform on an allowed device.
+/// Nothing beyond opening the device is granted unless stated.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    /// The interfaces that may be claimed: a list of interface numbers or `"*"`.
+    #[serde(default)]
+    pub interfaces: Interfaces,
+    #[serde(default)]
+    pub reset: bool,
+    /// Whether the guest may select a different configuration.
+    #[serde(default)]
+    pub configure: bool,
+    /// Whether kernel drivers are detached from interfaces the guest claims.
+    #[serde(default)]
+    pub detach_kernel_driver: bool,
+    /// Control request rules, evaluated before the built-in firewall rules.
+    #[serde(default)]
+    pub control: Vec<ControlRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "InterfacesRepr", into = "InterfacesRepr")]
+pub enum Interfaces {
+    All,
+    Only(Vec<u8>),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum InterfacesRepr {
+    Wildcard(String),
+    List(Vec<u8>),
+}
+
+/// Matches a vendor or product id: `*`, an exact hex id such as `18d1`,
+/// or an inclusive hex range such as `9400-94ff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum IdPattern {
+    Any,
+    Exact(u16),
+    Range(u16, u16),
+}
+
+/// Why a device was rejected by the policy.
+#[derive(Debug, Clone)]
+pub enum Rejection {
+    DeniedByRule(String),
+    NoMatchingRule,
+    Unreadable(DeviceHandleError),
+}
+
+impl Policy {
+    /// Reads a policy from a JSON file if the extension is `.json`, and from TOML otherwise.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read policy file {}", path.display()))?;
+
+        let policy = if path.extension().is_some_and(|e| e == "json") {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+
+        Ok(policy)
+    }
+
+    /// Builds the policy equivalent to a flat allow- or denylist of devices.
+    pub fn from_device_list(devices: Vec<USBDeviceIdentifier>, denylist: bool) -> Self {
+        let (action, default) = if denylist {
+            (Action::Deny, Action::Allow)
+        } else {
+            (Action::Allow, Action::Deny)
+        };
+
+        let rules = devices
+            .into_iter()
+            .map(|device| Rule {
+                action,
+                vendor_id: Some(IdPattern::Exact(device.vendor_id)),
+                product_id: Some(IdPattern::Exact(device.product_id)),
+                grant: Grant::all(),
+                ..Default::default()
+            })
+            .collect();
+
+        Self { default, grant: Grant::all(), rules }
+    }
+
+    /// Returns what the guest may do with the device, or why it may not use the device at all.
+    pub fn evaluate(&self, device: &dyn BackendDevice) -> Result<Grant, Rejection> {
+        let mut facts = DeviceFacts::new(device).map_err(Rejection::Unreadable)?;
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(&mut facts).map_err(Rejection::Unreadable)? {
+                continue;
+            }
+
+            return match rule.action {
+                Action::Allow => Ok(rule.grant.clone()),
+                Action::Deny => Err(Rejection::DeniedByRule(rule.label(index))),
+            };
+        }
+
+        match self.default {
+            Action::Allow => Ok(self.grant.clone()),
+            Action::Deny => Err(Rejection::NoMatchingRule),
+        }
+    }
+}
+
+impl Rule {
+    fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("\"{}\"", name),
+            None => format!("#{}", index + 1),
+        }
+    }
+
+    fn matches(&self, facts: &mut DeviceFacts) -> Result<bool, DeviceHandleError> {
+        let descriptor = &facts.descriptor;
+
+        let matches_descriptor = self.vendor_id.map_or(true, |p| p.matches(descriptor.vendor_id))
+            && self.product_id.map_or(true, |p| p.matches(descriptor.product_id))
+            && self.class.map_or(true, |v| descriptor.device_class == v)
+            && self.subclass.map_or(true, |v| descriptor.device_subclass == v)
+            && self.protocol.map_or(true, |v| descriptor.device_protocol == v)
+            && self.bus.map_or(true, |v| facts.device.bus_number() == v);
+
+        if !matches_descriptor {
+            return Ok(false);
+        }
+
+        if let Some(class) = self.interface_class {
+            let has_interface = facts
+                .configurations()?
+                .iter()
+                .flat_map(|c| c.interfaces.iter())
+                .flat_map(|i| i.alternate_settings.iter())
+                .any(|i| i.class_code == class);
+
+            if !has_interface {
+                return Ok(false);
+            }
+        }
+
+        if let Some(port_path) = &self.port_path {
+            if facts.port_numbers()? != port_path.as_slice() {
+                return Ok(false);
+            }
+        }
+
+        if let Some(serial_number) = &self.serial_number {
+            if facts.serial_number()? != Some(serial_number.as_str()) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Grant {
+    /// Grants every operation, which is how devices passed with `--usb-devices` are treated.
+    pub fn all() -> Self {
+        Self {
+            interfaces: Interfaces::All,
+            reset: true,
+            configure: true,
+            detach_kernel_driver: true,
+            control: Vec::new(),
+        }
+    }
+
+    /// Grants what the operator allows when choosing a device in the chooser: its interfaces may be claimed,
+    /// but the device may not be reset or reconfigured, and kernel drivers are not detached.
+    pub fn chosen() -> Self {
+        Self {
+            interfaces: Interfaces::All,
+            ..Default::default()
+        }
+    }
+
+    pub fn allows_interface(&self, interface: u8) -> bool {
+        match &self.interfaces {
+            Interfaces::All => true,
+            Interfaces::Only(interfaces) => interfaces.contains(&interface),
+        }
+    }
+}
+
+impl Default for Interfaces {
+    fn default() -> Self {
+        Self::Only(Vec::new())
+    }
+}
+
+impl TryFrom<InterfacesRepr> for Interfaces {
+    type Error = String;
+
+    fn try_from(value: InterfacesRepr) -> Result<Self, Self::Error> {
+        match value {
+            InterfacesRepr::Wildcard(s) if s == "*" => Ok(Self::All),
+            InterfacesRepr::Wildcard(s) => Err(format!("Invalid interfaces \"{}\", expected a list or \"*\"", s)),
+            InterfacesRepr::List(interfaces) => Ok(Self::Only(interfaces)),
+        }
+    }
+}
+
+impl From<Interfaces> for InterfacesRepr {
+    fn from(value: Interfaces) -> Self {
+        match value {
+            Interfaces::All => Self::Wildcard("*".to_owned()),
+            Interfaces::Only(interfaces) => Self::List(interfaces),
+        }
+    }
+}
+
+impl IdPattern {
+    pub fn matches(&self, id: u16) -> bool {
+        match *self {
+            Self::Any => true,
+            Self::Exact(value) => id == value,
+            Self::Range(start, end) => (start..=end).contains(&id),
+        }
+    }
+}
+
+impl FromStr for IdPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| u16::from_str_radix(s.trim(), 16).map_err(|_| format!("Invalid id \"{}\"", s));
+
+        match s.trim() {
+            "*" => Ok(Self::Any),
+            s => match s.split_once('-') {
+                Some((start, end)) => Ok(Self::Range(parse(start)?, parse(end)?)),
+                None => Ok(Self::Exact(parse(s)?)),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for IdPattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for IdPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Any => write!(f, "*"),
+            Self::Exact(id) => write!(f, "{:04x}", id),
+            Self::Range(start, end) => write!(f, "{:04x}-{:04x}", start, end),
+        }
+    }
+}
+
+impl From<IdPattern> for String {
+    fn from(value: IdPattern) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeniedByRule(rule) => write!(f, "denied by rule {}", rule),
+            Self::NoMatchingRule => write!(f, "no rule allows this device"),
+            Self::Unreadable(error) => write!(f, "could not read device information ({:?})", error),
+        }
+    }
+}
+
+/// The properties of a device that rules match on.
+/// Anything that is expensive to read, such as the serial number, is only read when a rule needs it.
+struct DeviceFacts<'a> {
+    device: &'a dyn BackendDevice,
+    descriptor: DeviceDescriptor,
+    configurations: Option<Vec<ConfigurationDescriptor>>,
+    port_numbers: Option<Vec<u8>>,
+    serial_number: Option<Option<String>>,
+}
+
+impl<'a> DeviceFacts<'a> {
+    fn new(device: &'a dyn BackendDevice) -> Result<Self, DeviceHandleError> {
+        Ok(Self {
+            device,
+            descriptor: device.device_descriptor()?,
+            configurations: None,
+            port_numbers: None,
+            serial_number: None,
+        })
+    }
+
+    fn configurations(&mut self) -> Result<&[ConfigurationDescriptor], DeviceHandleError> {
+        if self.configurations.is_none() {
+            self.configurations = Some(self.device.configurations()?);
+        }
+
+        Ok(self.configurations.as_deref().unwrap_or_default())
+    }
+
+    fn port_numbers(&mut self) -> Result<&[u8], DeviceHandleError> {
+        if self.port_numbers.is_none() {
+            self.port_numbers = Some(self.device.port_numbers()?);
+        }
+
+        Ok(self.port_numbers.as_deref().unwrap_or_default())
+    }
+
+    fn serial_number(&mut self) -> Result<Option<&str>, DeviceHandleError> {
+        if self.serial_number.is_none() {
+            self.serial_number = Some(self.device.serial_number()?);
+        }
+
+        Ok(self.serial_number.as_ref().and_then(|s| s.as_deref()))
+    }
+}
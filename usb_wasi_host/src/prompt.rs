@@ -0,0 +1,304 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::backend::BackendDevice;
+use crate::policy::IdPattern;
+use crate::registry::Guest;
+use crate::USBDeviceIdentifier;
+
+/// What the operator decided about a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Deny,
+    /// The guest may open the device once.
+    Once,
+    /// The component may use the device until the host exits.
+    Session,
+    /// The component may use the device from now on. The permission is written to the permissions file.
+    Always,
+}
+
+/// A device that no policy rule covers, as shown to the operator.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub bus_number: u8,
+    pub address: u8,
+    pub identifier: Option<USBDeviceIdentifier>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// Asks the operator which devices a guest may use.
+/// Called on a blocking thread while the guest waits, so implementations may block until the operator answers.
+pub trait Prompt: Send + Sync {
+    /// Returns a decision for every candidate, in the same order.
+    fn choose(&self, guest: &Guest, candidates: &[Candidate]) -> Result<Vec<Decision>>;
+}
+
+/// Lists the devices on stderr and reads the answers from stdin. Guests that ask at the same time are asked one after the other.
+#[derive(Default)]
+pub struct TerminalPrompt {
+    lock: Mutex<()>,
+}
+
+/// A device the operator allowed a component to use, until the host exits or in the permissions file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Permission {
+    /// The canonical path of the component.
+    pub component: PathBuf,
+    pub vendor_id: IdPattern,
+    pub product_id: IdPattern,
+    /// Only the device with this serial number is allowed, if set.
+    pub serial_number: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PermissionFile {
+    #[serde(default, rename = "permission")]
+    permissions: Vec<Permission>,
+}
+
+/// Asks the operator about devices that no policy rule covers, and remembers the devices granted for the session or for good.
+pub struct Chooser {
+    prompt: Box<dyn Prompt>,
+    path: Option<PathBuf>,
+    session: Mutex<Vec<Permission>>,
+    permanent: Mutex<Vec<Permission>>,
+}
+
+impl Chooser {
+    /// Reads the permanent permissions from `path`, if it exists. Without a path, permanent permissions only last for the session.
+    pub fn new(prompt: Box<dyn Prompt>, path: Option<PathBuf>) -> Result<Self> {
+        let permanent = match &path {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Could not read permissions file {}", path.display()))?;
+                let file: PermissionFile = toml::from_str(&contents)
+                    .with_context(|| format!("Could not parse permissions file {}", path.display()))?;
+                file.permissions
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            prompt,
+            path,
+            session: Mutex::new(Vec::new()),
+            permanent: Mutex::new(permanent),
+        })
+    }
+
+    /// Whether the operator allowed the guest's component to use the device for the session or for good.
+    pub(crate) fn allows(&self, guest: &Guest, device: &dyn BackendDevice) -> bool {
+        let Ok(descriptor) = device.device_descriptor() else { return false };
+        let component = component_key(guest);
+
+        let session = self.session.lock().unwrap();
+        let permanent = self.permanent.lock().unwrap();
+        let mut serial_number = None;
+
+        session.iter().chain(permanent.iter()).any(|permission| {
+            if permission.component != component
+                || !permission.vendor_id.matches(descriptor.vendor_id)
+                || !permission.product_id.matches(descriptor.product_id)
+            {
+                return false;
+            }
+
+            let Some(expected) = &permission.serial_number else { return true };
+            let serial_number = serial_number.get_or_insert_with(|| device.serial_number().ok().flatten());
+            serial_number.as_ref() == Some(expected)
+        })
+    }
+
+    /// Shows the devices to the operator and returns the decision for each. Devices granted for the session or for good
+    /// are remembered. Blocks until the operator answers.
+    pub(crate) fn ask(&self, guest: &Guest, devices: &[Arc<dyn BackendDevice>]) -> Result<Vec<Decision>> {
+        let candidates: Vec<Candidate> = devices.iter().map(|device| Candidate::read(device.as_ref())).collect();
+        let decisions = self.prompt.choose(guest, &candidates)?;
+
+        let component = component_key(guest);
+        for (candidate, &decision) in candidates.iter().zip(&decisions) {
+            let Some(identifier) = &candidate.identifier else { continue };
+            let permission = Permission {
+                component: component.clone(),
+                vendor_id: IdPattern::Exact(identifier.vendor_id),
+                product_id: IdPattern::Exact(identifier.product_id),
+                serial_number: candidate.serial_number.clone(),
+            };
+
+            match decision {
+                Decision::Deny | Decision::Once => {}
+                Decision::Session => self.session.lock().unwrap().push(permission),
+                Decision::Always => self.remember(permission),
+            }
+
+            info!(guest = guest.id, device = %candidate, ?decision, "The operator decided on the device");
+        }
+
+        Ok(decisions)
+    }
+
+    /// Adds a permanent permission and rewrites the permissions file. If the file cannot be written,
+    /// the permission still lasts for the session.
+    fn remember(&self, permission: Permission) {
+        let mut permanent = self.permanent.lock().unwrap();
+        permanent.push(permission);
+
+        let Some(path) = &self.path else { return };
+        let file = PermissionFile { permissions: permanent.clone() };
+        if let Err(error) = write_permissions(path, &file) {
+            warn!(?error, "Could not write the permissions file {}", path.display());
+        }
+    }
+}
+
+impl Candidate {
+    /// Reads what is shown about the device. The strings are only shown if they can be read.
+    fn read(device: &dyn BackendDevice) -> Self {
+        let identifier = device.device_descriptor().ok().map(|descriptor| USBDeviceIdentifier {
+            vendor_id: descriptor.vendor_id,
+            product_id: descriptor.product_id,
+        });
+
+        Self {
+            bus_number: device.bus_number(),
+            address: device.address(),
+            identifier,
+            manufacturer: device.manufacturer().ok().flatten(),
+            product: device.product().ok().flatten(),
+            serial_number: device.serial_number().ok().flatten(),
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03}:{:03}", self.bus_number, self.address)?;
+        if let Some(identifier) = &self.identifier {
+            write!(f, " {}", identifier)?;
+        }
+
+        match (&self.manufacturer, &self.product) {
+            (Some(manufacturer), Some(product)) => write!(f, " {} {}", manufacturer, product)?,
+            (Some(name), None) | (None, Some(name)) => write!(f, " {}", name)?,
+            (None, None) => {}
+        }
+
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, " (serial number {})", serial_number)?;
+        }
+        Ok(())
+    }
+}
+
+impl TerminalPrompt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Prompt for TerminalPrompt {
+    fn choose(&self, guest: &Guest, candidates: &[Candidate]) -> Result<Vec<Decision>> {
+        let _asking = self.lock.lock().unwrap();
+        let denied = vec![Decision::Deny; candidates.len()];
+        let mut out = io::stderr().lock();
+
+        writeln!(out)?;
+        writeln!(out, "Guest {} ({}) asks for USB devices that no policy rule covers:", guest.id, guest.component.display())?;
+        for (i, candidate) in candidates.iter().enumerate() {
+            writeln!(out, "  [{}] {}", i + 1, candidate)?;
+        }
+
+        let chosen = loop {
+            write!(out, "Grant which devices? Enter their numbers separated by spaces, or nothing to deny all: ")?;
+            out.flush()?;
+
+            // Without a terminal to answer, nothing is granted.
+            let Some(answer) = read_answer()? else { return Ok(denied) };
+            match parse_selection(&answer, candidates.len()) {
+                Ok(chosen) => break chosen,
+                Err(message) => writeln!(out, "{}", message)?,
+            }
+        };
+
+        if chosen.is_empty() {
+            return Ok(denied);
+        }
+
+        let decision = loop {
+            write!(out, "Grant them [o]nce, for this [s]ession, or [a]lways? ")?;
+            out.flush()?;
+
+            let Some(answer) = read_answer()? else { return Ok(denied) };
+            match answer.as_str() {
+                "o" | "once" => break Decision::Once,
+                "s" | "session" => break Decision::Session,
+                "a" | "always" => break Decision::Always,
+                _ => writeln!(out, "Answer o, s or a.")?,
+            }
+        };
+
+        Ok((0..candidates.len())
+            .map(|i| if chosen.contains(&i) { decision } else { Decision::Deny })
+            .collect())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/usb-wasi-host/permissions.toml`, or `~/.config/usb-wasi-host/permissions.toml`.
+pub fn default_permissions_file() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config.join("usb-wasi-host").join("permissions.toml"))
+}
+
+/// Permissions are kept per component file, wherever the host was started from.
+fn component_key(guest: &Guest) -> PathBuf {
+    fs::canonicalize(&guest.component).unwrap_or_else(|_| guest.component.clone())
+}
+
+/// A trimmed line from stdin, or `None` at the end of the input.
+fn read_answer() -> Result<Option<String>> {
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim().to_lowercase()))
+}
+
+/// Parses the numbers of the chosen devices, counting from 1, into indices.
+fn parse_selection(answer: &str, count: usize) -> Result<Vec<usize>, String> {
+    answer
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|part| !part.is_empty())
+        .map(|part| match part.parse::<usize>() {
+            Ok(number) if (1..=count).contains(&number) => Ok(number - 1),
+            _ => Err(format!("\"{}\" is not a device number between 1 and {}.", part, count)),
+        })
+        .collect()
+}
+
+/// Writes to a temporary file first, so the file is never left partially written.
+fn write_permissions(path: &Path, file: &PermissionFile) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let partial = path.with_extension(format!("{}.partial", std::process::id()));
+    fs::write(&partial, toml::to_string(file)?)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
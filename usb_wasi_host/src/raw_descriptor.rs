@@ -0,0 +1,631 @@
+use crate::bindings::component::usb::descriptors::{
+    AudioControlHeader, BosDescriptor, CdcCallManagement, CdcUnion, ConfigurationDescriptor, DeviceCapability, DeviceDescriptor,
+    EndpointDescriptor, ExtraDescriptor, HidClassDescriptor, HidDescriptor, InterfaceAssociation, InterfaceDescriptor,
+    MicrosoftOs20DescriptorSet, PlatformCapability, RawDescriptor, SuperspeedCapability, SuperspeedEndpointCompanion, UnknownCapability,
+    Usb2ExtensionCapability, UsbInterface, VideoControlHeader, WebusbCapability,
+};
+use crate::bindings::component::usb::types::{Direction, SyncType, TransferType, UsageType, Version};
+use crate::conversion::hex;
+
+pub const DESCRIPTOR_DEVICE: u8 = 0x01;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+pub const DESCRIPTOR_STRING: u8 = 0x03;
+pub const DESCRIPTOR_INTERFACE: u8 = 0x04;
+pub const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+pub const DESCRIPTOR_OTHER_SPEED_CONFIGURATION: u8 = 0x07;
+pub const DESCRIPTOR_INTERFACE_ASSOCIATION: u8 = 0x0b;
+pub const DESCRIPTOR_BOS: u8 = 0x0f;
+pub const DESCRIPTOR_DEVICE_CAPABILITY: u8 = 0x10;
+pub const DESCRIPTOR_HID: u8 = 0x21;
+pub const DESCRIPTOR_CS_INTERFACE: u8 = 0x24;
+pub const DESCRIPTOR_SUPERSPEED_ENDPOINT_COMPANION: u8 = 0x30;
+
+const CLASS_AUDIO: u8 = 0x01;
+const CLASS_CDC: u8 = 0x02;
+const CLASS_VIDEO: u8 = 0x0e;
+/// The subclass of audio and video control interfaces.
+const SUBCLASS_CONTROL: u8 = 0x01;
+
+const CDC_HEADER: u8 = 0x00;
+const CDC_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_ACM: u8 = 0x02;
+const CDC_UNION: u8 = 0x06;
+/// The subtype of the header of audio and video control interfaces.
+const CONTROL_HEADER: u8 = 0x01;
+
+const CAPABILITY_USB2_EXTENSION: u8 = 0x02;
+const CAPABILITY_SUPERSPEED: u8 = 0x03;
+const CAPABILITY_CONTAINER_ID: u8 = 0x04;
+const CAPABILITY_PLATFORM: u8 = 0x05;
+
+/// {3408b638-09a9-47a0-8bfd-a0768815b665}, in the byte order of a descriptor.
+const PLATFORM_WEBUSB: [u8; 16] = [0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65];
+/// {d8dd60df-4589-4cc7-9cd2-659d9e648a9f}, in the byte order of a descriptor.
+const PLATFORM_MICROSOFT_OS20: [u8; 16] = [0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f];
+
+const DEVICE_DESCRIPTOR_LENGTH: u8 = 18;
+const CONFIGURATION_DESCRIPTOR_LENGTH: u8 = 9;
+const INTERFACE_DESCRIPTOR_LENGTH: u8 = 9;
+const ENDPOINT_DESCRIPTOR_LENGTH: u8 = 7;
+const INTERFACE_ASSOCIATION_DESCRIPTOR_LENGTH: u8 = 8;
+const AUDIO_ENDPOINT_DESCRIPTOR_LENGTH: u8 = 9;
+
+/// Encodes a device descriptor in its USB wire format.
+pub fn encode_device_descriptor(descriptor: &DeviceDescriptor) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(DEVICE_DESCRIPTOR_LENGTH.into());
+    bytes.push(DEVICE_DESCRIPTOR_LENGTH);
+    bytes.push(DESCRIPTOR_DEVICE);
+    bytes.extend_from_slice(&encode_version(&descriptor.usb_version).to_le_bytes());
+    bytes.push(descriptor.device_class);
+    bytes.push(descriptor.device_subclass);
+    bytes.push(descriptor.device_protocol);
+    bytes.push(descriptor.max_packet_size);
+    bytes.extend_from_slice(&descriptor.vendor_id.to_le_bytes());
+    bytes.extend_from_slice(&descriptor.product_id.to_le_bytes());
+    bytes.extend_from_slice(&encode_version(&descriptor.device_version).to_le_bytes());
+    bytes.push(descriptor.manufacturer_string_index.unwrap_or(0));
+    bytes.push(descriptor.product_string_index.unwrap_or(0));
+    bytes.push(descriptor.serial_number_string_index.unwrap_or(0));
+    bytes.push(descriptor.num_configurations);
+    bytes
+}
+
+/// Encodes a configuration descriptor together with its interface and endpoint descriptors,
+/// as returned by a GET_DESCRIPTOR request for the configuration.
+/// Each interface association is placed before the first interface it groups.
+pub fn encode_configuration_descriptor(configuration: &ConfigurationDescriptor) -> Vec<u8> {
+    let mut body = Vec::new();
+    for interface in &configuration.interfaces {
+        for association in configuration.interface_associations.iter().filter(|a| a.first_interface == interface.number) {
+            body.push(INTERFACE_ASSOCIATION_DESCRIPTOR_LENGTH);
+            body.push(DESCRIPTOR_INTERFACE_ASSOCIATION);
+            body.push(association.first_interface);
+            body.push(association.interface_count);
+            body.push(association.function_class);
+            body.push(association.function_subclass);
+            body.push(association.function_protocol);
+            body.push(association.function_string_index.unwrap_or(0));
+        }
+
+        for setting in &interface.alternate_settings {
+            body.push(INTERFACE_DESCRIPTOR_LENGTH);
+            body.push(DESCRIPTOR_INTERFACE);
+            body.push(setting.number);
+            body.push(setting.alternate_setting);
+            body.push(setting.endpoint_descriptors.len() as u8);
+            body.push(setting.class_code);
+            body.push(setting.subclass_code);
+            body.push(setting.protocol);
+            body.push(setting.interface_string_index.unwrap_or(0));
+            for extra in &setting.extra_descriptors {
+                body.extend_from_slice(&extra.data);
+            }
+
+            for endpoint in &setting.endpoint_descriptors {
+                let is_audio = endpoint.refresh != 0 || endpoint.synch_address != 0;
+                body.push(if is_audio { AUDIO_ENDPOINT_DESCRIPTOR_LENGTH } else { ENDPOINT_DESCRIPTOR_LENGTH });
+                body.push(DESCRIPTOR_ENDPOINT);
+                body.push(endpoint.address);
+                body.push(encode_endpoint_attributes(endpoint));
+                body.extend_from_slice(&endpoint.max_packet_size.to_le_bytes());
+                body.push(endpoint.interval);
+                if is_audio {
+                    body.push(endpoint.refresh);
+                    body.push(endpoint.synch_address);
+                }
+                for extra in &endpoint.extra_descriptors {
+                    body.extend_from_slice(&extra.data);
+                }
+            }
+        }
+    }
+
+    let total_length = (CONFIGURATION_DESCRIPTOR_LENGTH as usize + body.len()) as u16;
+
+    let mut bytes = Vec::with_capacity(total_length.into());
+    bytes.push(CONFIGURATION_DESCRIPTOR_LENGTH);
+    bytes.push(DESCRIPTOR_CONFIGURATION);
+    bytes.extend_from_slice(&total_length.to_le_bytes());
+    bytes.push(configuration.interfaces.len() as u8);
+    bytes.push(configuration.number);
+    // No configuration string, and the reserved bit that must always be set.
+    bytes.push(0);
+    bytes.push(0x80);
+    bytes.push((configuration.max_power / 2) as u8);
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+pub fn parse_device_descriptor(bytes: &[u8]) -> Option<DeviceDescriptor> {
+    if bytes.len() < DEVICE_DESCRIPTOR_LENGTH.into() || bytes[1] != DESCRIPTOR_DEVICE {
+        return None;
+    }
+
+    let string_index = |i: usize| Some(bytes[i]).filter(|&index| index != 0);
+
+    Some(DeviceDescriptor {
+        usb_version: parse_version(u16::from_le_bytes([bytes[2], bytes[3]])),
+        device_class: bytes[4],
+        device_subclass: bytes[5],
+        device_protocol: bytes[6],
+        max_packet_size: bytes[7],
+        vendor_id: u16::from_le_bytes([bytes[8], bytes[9]]),
+        product_id: u16::from_le_bytes([bytes[10], bytes[11]]),
+        device_version: parse_version(u16::from_le_bytes([bytes[12], bytes[13]])),
+        manufacturer_string_index: string_index(14),
+        product_string_index: string_index(15),
+        serial_number_string_index: string_index(16),
+        num_configurations: bytes[17],
+    })
+}
+
+/// Parses a configuration descriptor, which must include all of its interface and endpoint descriptors.
+/// Alternate settings are grouped under their interface. Other descriptors, except interface associations,
+/// are added to the extra descriptors of the interface or endpoint they follow, and those before the first interface are skipped.
+pub fn parse_configuration_descriptor(bytes: &[u8]) -> Option<ConfigurationDescriptor> {
+    if bytes.len() < CONFIGURATION_DESCRIPTOR_LENGTH.into() || bytes[1] != DESCRIPTOR_CONFIGURATION {
+        return None;
+    }
+
+    let total_length = usize::from(u16::from_le_bytes([bytes[2], bytes[3]]));
+    if total_length < CONFIGURATION_DESCRIPTOR_LENGTH.into() || total_length < usize::from(bytes[0]) {
+        return None;
+    }
+    let bytes = bytes.get(..total_length)?;
+
+    let mut configuration = ConfigurationDescriptor {
+        number: bytes[5],
+        max_power: u16::from(bytes[8]) * 2,
+        interfaces: Vec::new(),
+        interface_associations: Vec::new(),
+    };
+
+    // The interface of the last interface descriptor, which the descriptors after it belong to.
+    let mut current = None;
+
+    for descriptor in descriptors(bytes.get(usize::from(bytes[0])..)?) {
+        match descriptor[1] {
+            DESCRIPTOR_INTERFACE if descriptor.len() >= INTERFACE_DESCRIPTOR_LENGTH.into() => {
+                let setting = InterfaceDescriptor {
+                    number: descriptor[2],
+                    alternate_setting: descriptor[3],
+                    class_code: descriptor[5],
+                    subclass_code: descriptor[6],
+                    protocol: descriptor[7],
+                    interface_string_index: Some(descriptor[8]).filter(|&index| index != 0),
+                    endpoint_descriptors: Vec::new(),
+                    extra_descriptors: Vec::new(),
+                };
+
+                let interfaces = &mut configuration.interfaces;
+                let index = match interfaces.iter().position(|i| i.number == setting.number) {
+                    Some(index) => index,
+                    None => {
+                        interfaces.push(UsbInterface { number: setting.number, alternate_settings: Vec::new() });
+                        interfaces.len() - 1
+                    }
+                };
+                interfaces[index].alternate_settings.push(setting);
+                current = Some(index);
+            }
+            DESCRIPTOR_ENDPOINT if descriptor.len() >= ENDPOINT_DESCRIPTOR_LENGTH.into() => {
+                let interface = configuration.interfaces[current?].alternate_settings.last_mut()?;
+                interface.endpoint_descriptors.push(parse_endpoint_descriptor(descriptor));
+            }
+            DESCRIPTOR_INTERFACE_ASSOCIATION => {
+                configuration.interface_associations.extend(parse_interface_association(descriptor));
+            }
+            DESCRIPTOR_INTERFACE | DESCRIPTOR_ENDPOINT => {}
+            _ => {
+                let Some(interface) = current.and_then(|i| configuration.interfaces[i].alternate_settings.last_mut()) else { continue };
+                match interface.endpoint_descriptors.last_mut() {
+                    Some(endpoint) => {
+                        let extra = parse_endpoint_extra(descriptor, endpoint.transfer_type);
+                        endpoint.extra_descriptors.push(extra);
+                    }
+                    None => {
+                        let extra = parse_interface_extra(descriptor, interface.class_code, interface.subclass_code);
+                        interface.extra_descriptors.push(extra);
+                    }
+                }
+            }
+        }
+    }
+
+    Some(configuration)
+}
+
+/// Encodes a string descriptor, which holds the text as UTF-16LE. Text that does not fit in 255 bytes is cut off.
+pub fn encode_string_descriptor(string: &str) -> Vec<u8> {
+    let mut bytes = vec![0, DESCRIPTOR_STRING];
+    for unit in string.encode_utf16().take(126) {
+        bytes.extend_from_slice(&unit.to_le_bytes());
+    }
+    bytes[0] = bytes.len() as u8;
+    bytes
+}
+
+/// Encodes the string descriptor 0, which lists the language ids of the other string descriptors.
+pub fn encode_languages(languages: &[u16]) -> Vec<u8> {
+    let mut bytes = vec![0, DESCRIPTOR_STRING];
+    for language in languages.iter().take(126) {
+        bytes.extend_from_slice(&language.to_le_bytes());
+    }
+    bytes[0] = bytes.len() as u8;
+    bytes
+}
+
+pub fn parse_languages(bytes: &[u8]) -> Option<Vec<u16>> {
+    if bytes.len() < 2 || bytes[1] != DESCRIPTOR_STRING {
+        return None;
+    }
+
+    let length = usize::from(bytes[0]).clamp(2, bytes.len());
+    Some(bytes[2..length].chunks_exact(2).map(|id| u16::from_le_bytes([id[0], id[1]])).collect())
+}
+
+/// The total length of a descriptor that is followed by the descriptors it contains, such as a configuration.
+pub fn total_length(bytes: &[u8]) -> Option<usize> {
+    match *bytes.get(1)? {
+        DESCRIPTOR_CONFIGURATION | DESCRIPTOR_OTHER_SPEED_CONFIGURATION | DESCRIPTOR_BOS if bytes.len() >= 4 => {
+            Some(usize::from(u16::from_le_bytes([bytes[2], bytes[3]])))
+        }
+        _ => None,
+    }
+}
+
+/// Parses a BOS descriptor together with its device capability descriptors.
+/// Capabilities that the host does not know are returned with their raw data.
+pub fn parse_bos_descriptor(bytes: &[u8]) -> Option<BosDescriptor> {
+    if bytes.len() < 5 || bytes[1] != DESCRIPTOR_BOS {
+        return None;
+    }
+
+    // A total length shorter than the BOS descriptor itself would leave nothing to read its length from.
+    let total_length = usize::from(u16::from_le_bytes([bytes[2], bytes[3]]));
+    if total_length < 5 || total_length < usize::from(bytes[0]) {
+        return None;
+    }
+    let bytes = bytes.get(..total_length)?;
+
+    let capabilities = descriptors(bytes.get(usize::from(bytes[0])..)?)
+        .filter(|descriptor| descriptor[1] == DESCRIPTOR_DEVICE_CAPABILITY && descriptor.len() >= 3)
+        .map(parse_capability)
+        .collect();
+
+    Some(BosDescriptor { capabilities })
+}
+
+fn parse_capability(bytes: &[u8]) -> DeviceCapability {
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+    match bytes[2] {
+        CAPABILITY_USB2_EXTENSION if bytes.len() >= 7 => {
+            let attributes = u32_at(3);
+            DeviceCapability::Usb2Extension(Usb2ExtensionCapability { attributes, lpm_supported: attributes & 0x02 != 0 })
+        }
+        CAPABILITY_SUPERSPEED if bytes.len() >= 10 => DeviceCapability::Superspeed(SuperspeedCapability {
+            attributes: bytes[3],
+            speeds_supported: u16_at(4),
+            functionality_support: bytes[6],
+            u1_exit_latency: bytes[7],
+            u2_exit_latency: u16_at(8),
+        }),
+        CAPABILITY_CONTAINER_ID if bytes.len() >= 20 => DeviceCapability::ContainerId(format_uuid(&bytes[4..20])),
+        CAPABILITY_PLATFORM if bytes.len() >= 20 => {
+            let (uuid, data) = (&bytes[4..20], &bytes[20..]);
+
+            if uuid == PLATFORM_WEBUSB && data.len() >= 4 {
+                DeviceCapability::Webusb(WebusbCapability {
+                    version: parse_version(u16::from_le_bytes([data[0], data[1]])),
+                    vendor_code: data[2],
+                    landing_page: Some(data[3]).filter(|&index| index != 0),
+                })
+            } else if uuid == PLATFORM_MICROSOFT_OS20 {
+                let sets = data
+                    .chunks_exact(8)
+                    .map(|set| MicrosoftOs20DescriptorSet {
+                        windows_version: u32::from_le_bytes([set[0], set[1], set[2], set[3]]),
+                        total_length: u16::from_le_bytes([set[4], set[5]]),
+                        vendor_code: set[6],
+                        alt_enum_code: set[7],
+                    })
+                    .collect();
+                DeviceCapability::MicrosoftOs20(sets)
+            } else {
+                DeviceCapability::Platform(PlatformCapability { uuid: format_uuid(uuid), data: data.to_vec() })
+            }
+        }
+        capability_type => DeviceCapability::Other(UnknownCapability { capability_type, data: bytes[3..].to_vec() }),
+    }
+}
+
+/// Formats a UUID stored as in USB descriptors, where the first three fields are little-endian.
+fn format_uuid(bytes: &[u8]) -> String {
+    let reversed = |bytes: &[u8]| hex(&bytes.iter().rev().copied().collect::<Vec<u8>>());
+
+    format!("{}-{}-{}-{}-{}", reversed(&bytes[0..4]), reversed(&bytes[4..6]), reversed(&bytes[6..8]), hex(&bytes[8..10]), hex(&bytes[10..16]))
+}
+
+/// Decodes the UTF-16LE text of a string descriptor. Unpaired surrogates are replaced.
+pub fn parse_string_descriptor(bytes: &[u8]) -> Option<String> {
+    if bytes.len() < 2 || bytes[0] < 2 || bytes[1] != DESCRIPTOR_STRING {
+        return None;
+    }
+
+    let length = usize::from(bytes[0]).min(bytes.len());
+    let units: Vec<u16> = bytes[2..length]
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Splits a buffer of concatenated descriptors, stopping at the first malformed one.
+pub fn descriptors(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = bytes;
+    std::iter::from_fn(move || {
+        let length = usize::from(*rest.first()?);
+        if length < 2 || length > rest.len() {
+            return None;
+        }
+
+        let (descriptor, remaining) = rest.split_at(length);
+        rest = remaining;
+        Some(descriptor)
+    })
+}
+
+fn parse_endpoint_descriptor(bytes: &[u8]) -> EndpointDescriptor {
+    let attributes = bytes[3];
+
+    EndpointDescriptor {
+        address: bytes[2],
+        number: bytes[2] & 0x0f,
+        direction: if bytes[2] & 0x80 != 0 { Direction::In } else { Direction::Out },
+        transfer_type: match attributes & 0x03 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        },
+        sync_type: match (attributes >> 2) & 0x03 {
+            0 => SyncType::NoSync,
+            1 => SyncType::Asynchronous,
+            2 => SyncType::Adaptive,
+            _ => SyncType::Synchronous,
+        },
+        usage_type: match (attributes >> 4) & 0x03 {
+            0 => UsageType::Data,
+            1 => UsageType::Feedback,
+            2 => UsageType::FeedbackData,
+            _ => UsageType::Reserved,
+        },
+        max_packet_size: u16::from_le_bytes([bytes[4], bytes[5]]),
+        interval: bytes[6],
+        refresh: bytes.get(7).copied().unwrap_or(0),
+        synch_address: bytes.get(8).copied().unwrap_or(0),
+        extra_descriptors: Vec::new(),
+    }
+}
+
+pub fn parse_interface_association(bytes: &[u8]) -> Option<InterfaceAssociation> {
+    if bytes.len() < INTERFACE_ASSOCIATION_DESCRIPTOR_LENGTH.into() || bytes[1] != DESCRIPTOR_INTERFACE_ASSOCIATION {
+        return None;
+    }
+
+    Some(InterfaceAssociation {
+        first_interface: bytes[2],
+        interface_count: bytes[3],
+        function_class: bytes[4],
+        function_subclass: bytes[5],
+        function_protocol: bytes[6],
+        function_string_index: Some(bytes[7]).filter(|&index| index != 0),
+    })
+}
+
+/// Wraps a descriptor that follows an interface descriptor, parsing it if the host knows it.
+/// Class-specific interface descriptors are only understood for the class of the interface.
+pub fn parse_interface_extra(bytes: &[u8], class_code: u8, subclass_code: u8) -> RawDescriptor {
+    let parsed = match bytes[1] {
+        DESCRIPTOR_HID => parse_hid_descriptor(bytes),
+        DESCRIPTOR_CS_INTERFACE if bytes.len() >= 3 => match (class_code, subclass_code) {
+            (CLASS_CDC, _) => parse_cdc_descriptor(bytes),
+            (CLASS_AUDIO, SUBCLASS_CONTROL) if bytes[2] == CONTROL_HEADER => parse_audio_control_header(bytes),
+            (CLASS_VIDEO, SUBCLASS_CONTROL) if bytes[2] == CONTROL_HEADER => parse_video_control_header(bytes),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    RawDescriptor { descriptor_type: bytes[1], data: bytes.to_vec(), parsed }
+}
+
+/// Wraps a descriptor that follows an endpoint descriptor, parsing it if the host knows it.
+pub fn parse_endpoint_extra(bytes: &[u8], transfer_type: TransferType) -> RawDescriptor {
+    let parsed = match bytes[1] {
+        DESCRIPTOR_SUPERSPEED_ENDPOINT_COMPANION if bytes.len() >= 6 => {
+            let attributes = bytes[3];
+            let (max_streams, mult) = match transfer_type {
+                TransferType::Bulk if attributes & 0x1f != 0 => (1 << (attributes & 0x1f), 0),
+                TransferType::Isochronous => (0, attributes & 0x03),
+                _ => (0, 0),
+            };
+
+            Some(ExtraDescriptor::SuperspeedEndpointCompanion(SuperspeedEndpointCompanion {
+                max_burst: bytes[2],
+                attributes,
+                max_streams,
+                mult,
+                bytes_per_interval: u16::from_le_bytes([bytes[4], bytes[5]]),
+            }))
+        }
+        _ => None,
+    };
+
+    RawDescriptor { descriptor_type: bytes[1], data: bytes.to_vec(), parsed }
+}
+
+/// The HID descriptor lists the class descriptors of the interface, the first of which is the report descriptor.
+fn parse_hid_descriptor(bytes: &[u8]) -> Option<ExtraDescriptor> {
+    if bytes.len() < 6 {
+        return None;
+    }
+
+    let class_descriptors = bytes[6..]
+        .chunks_exact(3)
+        .take(usize::from(bytes[5]))
+        .map(|class| HidClassDescriptor { descriptor_type: class[0], length: u16::from_le_bytes([class[1], class[2]]) })
+        .collect();
+
+    Some(ExtraDescriptor::Hid(HidDescriptor {
+        hid_version: parse_version(u16::from_le_bytes([bytes[2], bytes[3]])),
+        country_code: bytes[4],
+        class_descriptors,
+    }))
+}
+
+/// The CDC functional descriptors that describe how the interfaces of a serial or modem function belong together.
+fn parse_cdc_descriptor(bytes: &[u8]) -> Option<ExtraDescriptor> {
+    match bytes[2] {
+        CDC_HEADER if bytes.len() >= 5 => Some(ExtraDescriptor::CdcHeader(parse_version(u16::from_le_bytes([bytes[3], bytes[4]])))),
+        CDC_CALL_MANAGEMENT if bytes.len() >= 5 => {
+            Some(ExtraDescriptor::CdcCallManagement(CdcCallManagement { capabilities: bytes[3], data_interface: bytes[4] }))
+        }
+        CDC_ACM if bytes.len() >= 4 => Some(ExtraDescriptor::CdcAcm(bytes[3])),
+        CDC_UNION if bytes.len() >= 4 => Some(ExtraDescriptor::CdcUnion(CdcUnion {
+            control_interface: bytes[3],
+            subordinate_interfaces: bytes[4..].to_vec(),
+        })),
+        _ => None,
+    }
+}
+
+/// UAC 1.0 headers list their streaming interfaces, UAC 2.0 headers have a category instead and a shorter total length field.
+fn parse_audio_control_header(bytes: &[u8]) -> Option<ExtraDescriptor> {
+    if bytes.len() < 8 {
+        return None;
+    }
+
+    let audio_version = parse_version(u16::from_le_bytes([bytes[3], bytes[4]]));
+    let header = if audio_version.major >= 2 {
+        AudioControlHeader { audio_version, total_length: u16::from_le_bytes([bytes[6], bytes[7]]), streaming_interfaces: Vec::new() }
+    } else {
+        AudioControlHeader {
+            audio_version,
+            total_length: u16::from_le_bytes([bytes[5], bytes[6]]),
+            streaming_interfaces: bytes[8..].iter().take(usize::from(bytes[7])).copied().collect(),
+        }
+    };
+
+    Some(ExtraDescriptor::AudioControlHeader(header))
+}
+
+fn parse_video_control_header(bytes: &[u8]) -> Option<ExtraDescriptor> {
+    if bytes.len() < 12 {
+        return None;
+    }
+
+    Some(ExtraDescriptor::VideoControlHeader(VideoControlHeader {
+        video_version: parse_version(u16::from_le_bytes([bytes[3], bytes[4]])),
+        total_length: u16::from_le_bytes([bytes[5], bytes[6]]),
+        clock_frequency: u32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]),
+        streaming_interfaces: bytes[12..].iter().take(usize::from(bytes[11])).copied().collect(),
+    }))
+}
+
+fn encode_endpoint_attributes(endpoint: &EndpointDescriptor) -> u8 {
+    let transfer_type = match endpoint.transfer_type {
+        TransferType::Control => 0,
+        TransferType::Isochronous => 1,
+        TransferType::Bulk => 2,
+        TransferType::Interrupt => 3,
+    };
+    let sync_type = match endpoint.sync_type {
+        SyncType::NoSync => 0,
+        SyncType::Asynchronous => 1,
+        SyncType::Adaptive => 2,
+        SyncType::Synchronous => 3,
+    };
+    let usage_type = match endpoint.usage_type {
+        UsageType::Data => 0,
+        UsageType::Feedback => 1,
+        UsageType::FeedbackData => 2,
+        UsageType::Reserved => 3,
+    };
+
+    transfer_type | (sync_type << 2) | (usage_type << 4)
+}
+
+/// Versions are stored as binary-coded decimal, 0xJJMN for version JJ.M.N.
+fn encode_version(version: &Version) -> u16 {
+    let major = u16::from(version.major % 100);
+    ((major / 10) << 12) | ((major % 10) << 8) | (u16::from(version.minor & 0x0f) << 4) | u16::from(version.subminor & 0x0f)
+}
+
+fn parse_version(bcd: u16) -> Version {
+    Version {
+        major: (((bcd & 0xf000) >> 12) * 10 + ((bcd & 0x0f00) >> 8)) as u8,
+        minor: ((bcd & 0x00f0) >> 4) as u8,
+        subminor: (bcd & 0x000f) as u8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configuration_descriptor_with_bogus_total_length_is_rejected() {
+        for total_length in [0u8, 2, 8] {
+            let bytes = [9, DESCRIPTOR_CONFIGURATION, total_length, 0, 1, 1, 0, 0x80, 50];
+            assert!(parse_configuration_descriptor(&bytes).is_none());
+        }
+    }
+
+    #[test]
+    fn configuration_descriptor_round_trips() {
+        let mut bytes = vec![9, DESCRIPTOR_CONFIGURATION, 0, 0, 1, 1, 0, 0x80, 50];
+        bytes.extend_from_slice(&[9, DESCRIPTOR_INTERFACE, 0, 0, 1, 0xff, 0, 0, 0]);
+        bytes.extend_from_slice(&[7, DESCRIPTOR_ENDPOINT, 0x81, 0x02, 64, 0, 0]);
+        bytes[2] = bytes.len() as u8;
+
+        let configuration = parse_configuration_descriptor(&bytes).unwrap();
+        assert_eq!(configuration.number, 1);
+        assert_eq!(configuration.max_power, 100);
+        assert_eq!(configuration.interfaces[0].alternate_settings[0].endpoint_descriptors[0].address, 0x81);
+        assert_eq!(encode_configuration_descriptor(&configuration), bytes);
+    }
+
+    #[test]
+    fn bos_descriptor_with_bogus_total_length_is_rejected() {
+        // A valid BOS header without capabilities, claiming a total length of 0, 3 and 4 bytes.
+        for total_length in [0u8, 3, 4] {
+            let bytes = [5, DESCRIPTOR_BOS, total_length, 0, 0];
+            assert!(parse_bos_descriptor(&bytes).is_none());
+        }
+    }
+
+    #[test]
+    fn truncated_bos_descriptor_is_rejected() {
+        // Claims a container id capability of 20 bytes, but the buffer ends after the header.
+        let bytes = [5, DESCRIPTOR_BOS, 25, 0, 1];
+        assert!(parse_bos_descriptor(&bytes).is_none());
+    }
+
+    #[test]
+    fn bos_descriptor_with_container_id() {
+        let mut bytes = vec![5, DESCRIPTOR_BOS, 25, 0, 1];
+        bytes.extend_from_slice(&[20, DESCRIPTOR_DEVICE_CAPABILITY, CAPABILITY_CONTAINER_ID, 0]);
+        bytes.extend_from_slice(&PLATFORM_WEBUSB);
+
+        let bos = parse_bos_descriptor(&bytes).unwrap();
+        assert_eq!(bos.capabilities.len(), 1);
+        assert!(matches!(
+            &bos.capabilities[0],
+            DeviceCapability::ContainerId(uuid) if uuid == "3408b638-09a9-47a0-8bfd-a0768815b665"
+        ));
+    }
+}
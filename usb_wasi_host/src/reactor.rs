@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+use tracing::{debug_span, Instrument};
+use anyhow::Result;
+use wasmtime::component::{Component, Linker, Resource};
+use wasmtime::{Engine, Store};
+
+use crate::bindings::component::usb::events::DeviceConnectionEvent;
+use crate::bindings::Driver;
+use crate::compat;
+use crate::ctx::{self, UsbView};
+use crate::device::usbdevice::USBDevice;
+use crate::events;
+use crate::outcome::Outcome;
+
+/// The interface that a component exports to be run as a driver instead of a command.
+pub const HOTPLUG_INTERFACE: &str = "component:usb/hotplug@0.3.0";
+
+/// Whether the component is a driver that exports the hotplug handlers, of either version of `component:usb`.
+pub fn is_driver(engine: &Engine, component: &Component) -> bool {
+    component
+        .component_type()
+        .exports(engine)
+        .any(|(name, _)| name == HOTPLUG_INTERFACE || name == compat::HOTPLUG_INTERFACE)
+}
+
+/// A driver instance, with the bindings of the version it was built against.
+enum Hotplug {
+    Current(Driver),
+    V0_2(compat::bindings::Driver),
+}
+
+impl Hotplug {
+    async fn instantiate<T: UsbView + 'static>(store: &mut Store<T>, component: &Component, linker: &Linker<T>) -> Result<Self> {
+        let current = component
+            .component_type()
+            .exports(store.engine())
+            .any(|(name, _)| name == HOTPLUG_INTERFACE);
+
+        if current {
+            let (driver, _) = Driver::instantiate_async(&mut *store, component, linker).await?;
+            Ok(Self::Current(driver))
+        } else {
+            let (driver, _) = compat::bindings::Driver::instantiate_async(&mut *store, component, linker).await?;
+            Ok(Self::V0_2(driver))
+        }
+    }
+
+    async fn on_connected<T: UsbView + 'static>(&self, store: &mut Store<T>, device: Resource<USBDevice>) -> Result<()> {
+        match self {
+            Self::Current(driver) => driver.component_usb_hotplug().call_on_connected(store, device).await,
+            Self::V0_2(driver) => driver.component_usb_hotplug().call_on_connected(store, device).await,
+        }
+    }
+
+    async fn on_disconnected<T: UsbView + 'static>(&self, store: &mut Store<T>, device: Resource<USBDevice>) -> Result<()> {
+        match self {
+            Self::Current(driver) => driver.component_usb_hotplug().call_on_disconnected(store, device).await,
+            Self::V0_2(driver) => driver.component_usb_hotplug().call_on_disconnected(store, device).await,
+        }
+    }
+
+    async fn on_tick<T: UsbView + 'static>(&self, store: &mut Store<T>) -> Result<()> {
+        match self {
+            Self::Current(driver) => driver.component_usb_hotplug().call_on_tick(store).await,
+            Self::V0_2(driver) => driver.component_usb_hotplug().call_on_tick(store).await,
+        }
+    }
+}
+
+enum Wakeup {
+    Event(Option<events::DeviceConnectionEvent>),
+    Tick,
+}
+
+/// Instantiates a driver once, and calls its handlers for every connection event and at every tick until the host stops.
+/// The host consumes the connection events, so `events.update` always returns `pending` in a driver.
+/// `tick` must not be zero.
+pub async fn run<T: UsbView + 'static>(store: &mut Store<T>, component: &Component, linker: &Linker<T>, tick: Duration) -> Outcome {
+    let hotplug = match Hotplug::instantiate(store, component, linker).await {
+        Ok(hotplug) => hotplug,
+        Err(e) => return Outcome::InstantiationFailed(e),
+    };
+
+    let mut ticks = tokio::time::interval(tick);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let wakeup = tokio::select! {
+            event = store.data_mut().usb().next_event() => Wakeup::Event(event),
+            _ = ticks.tick() => Wakeup::Tick,
+        };
+
+        let result = match wakeup {
+            Wakeup::Event(None) => return Outcome::Exited(0),
+            Wakeup::Event(Some(event)) => match ctx::accept(store.data_mut(), event) {
+                Ok(Some(DeviceConnectionEvent::Connected(device))) => hotplug
+                    .on_connected(store, device)
+                    .instrument(debug_span!("hotplug.on-connected"))
+                    .await,
+                Ok(Some(DeviceConnectionEvent::Disconnected(device))) => hotplug
+                    .on_disconnected(store, device)
+                    .instrument(debug_span!("hotplug.on-disconnected"))
+                    .await,
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Wakeup::Tick => hotplug.on_tick(store).instrument(debug_span!("hotplug.on-tick")).await,
+        };
+
+        if let Err(e) = result {
+            return Outcome::from_run(Err(e));
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+use crate::broker::{DeviceBroker, GuestId};
+use crate::capture::{CapturedTransfer, TransferKind};
+use crate::conversion::error_name;
+use crate::device::devicehandle::SharedHandle;
+use crate::policy::Policy;
+use crate::bindings::component::usb::types::DeviceHandleError;
+
+/// Keeps track of the guests running in the host, so they can be inspected and controlled,
+/// and distributes the events that happen in the host to hooks and subscribers.
+pub struct Registry {
+    guests: Mutex<BTreeMap<GuestId, Arc<Guest>>>,
+    events: Arc<EventSink>,
+}
+
+/// A callback for every host event. It is called on the task that caused the event, so it should return quickly.
+pub type EventHook = Box<dyn Fn(&HostEvent) + Send + Sync>;
+
+struct EventSink {
+    sender: broadcast::Sender<HostEvent>,
+    hooks: RwLock<Vec<EventHook>>,
+}
+
+impl EventSink {
+    fn publish(&self, event: HostEvent) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook(&event);
+        }
+
+        // Sending only fails when nobody is listening.
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Something that happened in the host.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum HostEvent {
+    Connected { bus_number: u8, address: u8 },
+    Disconnected { bus_number: u8, address: u8 },
+    Transfer {
+        guest: GuestId,
+        bus_number: u8,
+        address: u8,
+        transfer: TransferKind,
+        endpoint: u8,
+        /// The number of bytes requested.
+        length: usize,
+        transferred: Option<usize>,
+        error: Option<&'static str>,
+        /// How long the transfer took.
+        duration_seconds: f64,
+    },
+    /// The component of the guest changed and a new version of it was started, with the devices of the old version.
+    GuestReloaded { guest: GuestId },
+    GuestExited { guest: GuestId },
+}
+
+impl HostEvent {
+    /// The name of the event, as used for the server-sent event stream.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Connected { .. } => "connected",
+            Self::Disconnected { .. } => "disconnected",
+            Self::Transfer { .. } => "transfer",
+            Self::GuestReloaded { .. } => "guest-reloaded",
+            Self::GuestExited { .. } => "guest-exited",
+        }
+    }
+}
+
+/// A running or exited guest.
+pub struct Guest {
+    pub id: GuestId,
+    pub component: PathBuf,
+    pub policy: Arc<Policy>,
+    pub started: SystemTime,
+    finished: Mutex<Option<SystemTime>>,
+    running: AtomicBool,
+    /// The size of the guest's linear memories, in bytes.
+    memory: AtomicUsize,
+    handles: Mutex<BTreeMap<(u8, u8), OpenHandle>>,
+    usage: Mutex<BTreeMap<(u8, u8), DeviceUsage>>,
+    revoked: Mutex<HashSet<(u8, u8)>>,
+    abort: Mutex<Option<AbortHandle>>,
+    events: Arc<EventSink>,
+}
+
+/// A device the guest has open, by bus number and address.
+struct OpenHandle {
+    handle: SharedHandle,
+    claimed_interfaces: BTreeSet<u8>,
+}
+
+/// What a guest did with a device over its whole run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceUsage {
+    pub bus_number: u8,
+    pub address: u8,
+    /// The vendor and product id, as `vendor_id:product_id`.
+    pub device: Option<String>,
+    /// How often the guest opened the device.
+    pub opened: u32,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestSummary {
+    pub id: GuestId,
+    pub component: PathBuf,
+    pub running: bool,
+    /// Seconds since the Unix epoch.
+    pub started: u64,
+    pub memory_bytes: usize,
+    pub handles: Vec<HandleSummary>,
+    pub revoked: Vec<(u8, u8)>,
+    pub devices: Vec<DeviceUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HandleSummary {
+    pub bus_number: u8,
+    pub address: u8,
+    pub claimed_interfaces: Vec<u8>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(256);
+
+        Self {
+            guests: Mutex::new(BTreeMap::new()),
+            events: Arc::new(EventSink { sender, hooks: RwLock::new(Vec::new()) }),
+        }
+    }
+
+    /// Adds a guest, which gets the next id. `component` names the guest, usually the path of its component.
+    pub fn add(&self, component: PathBuf, policy: Arc<Policy>) -> Arc<Guest> {
+        let mut guests = self.guests.lock().unwrap();
+        let id = guests.keys().next_back().map_or(0, |id| id + 1);
+
+        let guest = Arc::new(Guest {
+            id,
+            component,
+            policy,
+            started: SystemTime::now(),
+            finished: Mutex::new(None),
+            running: AtomicBool::new(true),
+            memory: AtomicUsize::new(0),
+            handles: Mutex::new(BTreeMap::new()),
+            usage: Mutex::new(BTreeMap::new()),
+            revoked: Mutex::new(HashSet::new()),
+            abort: Mutex::new(None),
+            events: self.events.clone(),
+        });
+
+        guests.insert(id, guest.clone());
+        guest
+    }
+
+    pub fn guest(&self, id: GuestId) -> Option<Arc<Guest>> {
+        self.guests.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn guests(&self) -> Vec<Arc<Guest>> {
+        self.guests.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn publish(&self, event: HostEvent) {
+        self.events.publish(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<HostEvent> {
+        self.events.sender.subscribe()
+    }
+
+    /// Calls `hook` for every event from now on.
+    pub fn add_hook(&self, hook: EventHook) {
+        self.events.hooks.write().unwrap().push(hook);
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Guest {
+    pub fn set_abort_handle(&self, abort: AbortHandle) {
+        *self.abort.lock().unwrap() = Some(abort);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Stops the guest at its next await point. Its store is dropped, which closes all of its devices.
+    pub fn stop(&self) -> bool {
+        match &*self.abort.lock().unwrap() {
+            Some(abort) if self.is_running() => {
+                abort.abort();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Called when the guest's store is dropped.
+    pub fn exited(&self) {
+        *self.finished.lock().unwrap() = Some(SystemTime::now());
+        self.running.store(false, Ordering::Relaxed);
+        self.handles.lock().unwrap().clear();
+        self.events.publish(HostEvent::GuestExited { guest: self.id });
+    }
+
+    /// Called when a new version of the guest's component replaces the old one.
+    pub fn reloaded(&self) {
+        // The new version starts with new memories.
+        self.memory.store(0, Ordering::Relaxed);
+        self.events.publish(HostEvent::GuestReloaded { guest: self.id });
+    }
+
+    /// Called when a linear memory of the guest is created or grows by `bytes`.
+    pub fn memory_grown(&self, bytes: usize) {
+        self.memory.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The size of the guest's linear memories, in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+
+    /// How long the guest ran, or has been running.
+    pub fn duration(&self) -> Duration {
+        let end = self.finished.lock().unwrap().unwrap_or_else(SystemTime::now);
+        end.duration_since(self.started).unwrap_or_default()
+    }
+
+    pub fn opened(&self, bus_number: u8, address: u8, device: Option<String>, handle: SharedHandle) {
+        let open = OpenHandle { handle, claimed_interfaces: BTreeSet::new() };
+        self.handles.lock().unwrap().insert((bus_number, address), open);
+
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage
+            .entry((bus_number, address))
+            .or_insert_with(|| DeviceUsage { bus_number, address, ..Default::default() });
+        usage.device = device.or(usage.device.take());
+        usage.opened += 1;
+    }
+
+    pub fn closed(&self, bus_number: u8, address: u8) {
+        self.handles.lock().unwrap().remove(&(bus_number, address));
+    }
+
+    /// The devices the guest has open, with the backend handles that stay open as long as they are referenced.
+    pub fn open_handles(&self) -> Vec<(HandleSummary, SharedHandle)> {
+        self.handles
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(bus_number, address), open)| {
+                let summary = HandleSummary {
+                    bus_number,
+                    address,
+                    claimed_interfaces: open.claimed_interfaces.iter().copied().collect(),
+                };
+                (summary, open.handle.clone())
+            })
+            .collect()
+    }
+
+    pub fn claimed(&self, bus_number: u8, address: u8, interface: u8, claimed: bool) {
+        let mut handles = self.handles.lock().unwrap();
+        let Some(open) = handles.get_mut(&(bus_number, address)) else { return };
+
+        if claimed {
+            open.claimed_interfaces.insert(interface);
+        } else {
+            open.claimed_interfaces.remove(&interface);
+        }
+    }
+
+    pub fn is_revoked(&self, bus_number: u8, address: u8) -> bool {
+        self.revoked.lock().unwrap().contains(&(bus_number, address))
+    }
+
+    /// Takes a device away from the guest. An open handle is closed right away, after any transfer
+    /// in progress, and from then on fails with `no-device`. The guest cannot open the device again.
+    pub fn revoke(&self, bus_number: u8, address: u8, broker: &DeviceBroker) {
+        self.revoked.lock().unwrap().insert((bus_number, address));
+
+        let open = self.handles.lock().unwrap().remove(&(bus_number, address));
+        if let Some(open) = open {
+            open.handle.lock().unwrap().take();
+            broker.release(self.id, bus_number, address);
+        }
+    }
+
+    /// Counts a transfer and publishes it to the event hooks. A transfer that was not `sent` to the device was refused
+    /// by the host, and fails with `denied` if the grant or the firewall refused it.
+    pub fn transferred(&self, bus_number: u8, address: u8, transfer: &CapturedTransfer, sent: bool) {
+        let result = transfer.result;
+        if let (Ok(bytes), Some(usage)) = (result, self.usage.lock().unwrap().get_mut(&(bus_number, address))) {
+            if transfer.endpoint & 0x80 != 0 {
+                usage.bytes_in += bytes as u64;
+            } else {
+                usage.bytes_out += bytes as u64;
+            }
+        }
+
+        self.events.publish(HostEvent::Transfer {
+            guest: self.id,
+            bus_number,
+            address,
+            transfer: transfer.kind,
+            endpoint: transfer.endpoint,
+            length: transfer.length,
+            transferred: result.ok(),
+            error: result.err().map(|error| match error {
+                DeviceHandleError::Access if !sent => "denied",
+                error => error_name(error),
+            }),
+            duration_seconds: transfer.submitted.elapsed().unwrap_or_default().as_secs_f64(),
+        });
+    }
+
+    pub fn summary(&self) -> GuestSummary {
+        let handles = self
+            .handles
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(bus_number, address), open)| HandleSummary {
+                bus_number,
+                address,
+                claimed_interfaces: open.claimed_interfaces.iter().copied().collect(),
+            })
+            .collect();
+
+        let mut revoked: Vec<(u8, u8)> = self.revoked.lock().unwrap().iter().copied().collect();
+        revoked.sort();
+
+        GuestSummary {
+            id: self.id,
+            component: self.component.clone(),
+            running: self.is_running(),
+            started: self.started.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            memory_bytes: self.memory_size(),
+            handles,
+            revoked,
+            devices: self.device_usage(),
+        }
+    }
+
+    pub fn device_usage(&self) -> Vec<DeviceUsage> {
+        self.usage.lock().unwrap().values().cloned().collect()
+    }
+}
@@ -0,0 +1,30 @@
+use tracing::field::display;
+use tracing::Span;
+
+use crate::bindings::component::usb::types::DeviceHandleError;
+use crate::conversion::error_name;
+use crate::USBDeviceIdentifier;
+
+/// Records the device a host call works on in the span of the call, which declares the `bus`, `address` and `device` fields.
+pub(crate) fn record_device(bus_number: u8, address: u8, identifier: Option<&USBDeviceIdentifier>) {
+    let span = Span::current();
+    span.record("bus", bus_number);
+    span.record("address", address);
+    if let Some(identifier) = identifier {
+        span.record("device", display(identifier));
+    }
+}
+
+/// `ok`, or the name of the error.
+pub(crate) fn result_name<T>(result: &Result<T, DeviceHandleError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(e) => error_name(*e),
+    }
+}
+
+/// Records the result of a host call in its span, which declares the `result` field, and passes the result on.
+pub(crate) fn record_result<T>(result: Result<T, DeviceHandleError>) -> Result<T, DeviceHandleError> {
+    Span::current().record("result", result_name(&result));
+    result
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use wasmtime_wasi::pipe::AsyncWriteStream;
+use wasmtime_wasi::{AsyncStdoutStream, DirPerms, FilePerms, WasiCtx, WasiCtxBuilder};
+
+/// The WASI environment of a guest: the directories it can access, its environment variables,
+/// its arguments and where its output goes. By default a guest gets no directories, no
+/// environment variables and no arguments besides its name, and shares the host's stdio.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WasiConfig {
+    #[serde(default, rename = "dir")]
+    pub dirs: Vec<Preopen>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// The arguments after the program name.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// A file the guest's stdout is appended to.
+    pub stdout: Option<PathBuf>,
+    /// A file the guest's stderr is appended to.
+    pub stderr: Option<PathBuf>,
+    /// Gives the guest an empty stdin instead of the host's, when the host reads stdin itself.
+    #[serde(skip)]
+    pub no_stdin: bool,
+}
+
+/// A host directory that is made available to the guest.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preopen {
+    pub host: PathBuf,
+    /// The path under which the guest sees the directory. Defaults to the host path.
+    pub guest: Option<String>,
+    #[serde(default)]
+    pub mode: AccessMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AccessMode {
+    #[default]
+    #[serde(rename = "ro")]
+    ReadOnly,
+    #[serde(rename = "rw")]
+    ReadWrite,
+}
+
+impl FromStr for Preopen {
+    type Err = &'static str;
+
+    /// Parses `HOST[::GUEST][:ro|:rw]`. Directories are read-only unless `:rw` is given.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, mode) = match s.rsplit_once(':') {
+            Some((path, "ro")) if !path.ends_with(':') => (path, AccessMode::ReadOnly),
+            Some((path, "rw")) if !path.ends_with(':') => (path, AccessMode::ReadWrite),
+            _ => (s, AccessMode::ReadOnly),
+        };
+
+        let (host, guest) = match path.split_once("::") {
+            Some((host, guest)) => (host, Some(guest.to_string())),
+            None => (path, None),
+        };
+
+        if host.is_empty() || guest.as_deref() == Some("") {
+            return Err("Invalid directory. Expected HOST[::GUEST][:ro|:rw]");
+        }
+
+        Ok(Self { host: PathBuf::from(host), guest, mode })
+    }
+}
+
+/// An environment variable from the command line: `NAME=VALUE`, or `NAME` to pass on the host's value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl FromStr for EnvVar {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (s, None),
+        };
+
+        if name.is_empty() {
+            return Err("Invalid environment variable. Expected NAME=VALUE or NAME");
+        }
+
+        Ok(Self { name: name.to_string(), value })
+    }
+}
+
+impl WasiConfig {
+    /// Reads a WASI configuration from a TOML file, or a JSON file if the extension is `.json`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read WASI configuration {}", path.display()))?;
+
+        let config = if path.extension().is_some_and(|e| e == "json") {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+
+        Ok(config)
+    }
+
+    /// Adds the options given on the command line. They take precedence over the configuration file:
+    /// directories and arguments are appended, environment variables and output files are replaced.
+    pub fn merge(&mut self, dirs: &[Preopen], env: &[EnvVar], args: &[String], stdout: Option<&Path>, stderr: Option<&Path>) {
+        self.dirs.extend_from_slice(dirs);
+
+        for var in env {
+            match var.value.clone().or_else(|| std::env::var(&var.name).ok()) {
+                Some(value) => {
+                    self.env.insert(var.name.clone(), value);
+                }
+                None => tracing::warn!("Not passing environment variable {}: it is not set on the host", var.name),
+            }
+        }
+
+        self.args.extend_from_slice(args);
+
+        if let Some(stdout) = stdout {
+            self.stdout = Some(stdout.to_path_buf());
+        }
+        if let Some(stderr) = stderr {
+            self.stderr = Some(stderr.to_path_buf());
+        }
+    }
+
+    /// Builds the WASI context of a guest. `program` is passed as the first argument.
+    pub fn build(&self, program: &str) -> Result<WasiCtx> {
+        let mut builder = WasiCtxBuilder::new();
+        if self.no_stdin {
+            builder.inherit_stdout().inherit_stderr();
+        } else {
+            builder.inherit_stdio();
+        }
+
+        builder.arg(program);
+        builder.args(&self.args);
+
+        for (name, value) in &self.env {
+            builder.env(name, value);
+        }
+
+        for dir in &self.dirs {
+            let (dir_perms, file_perms) = match dir.mode {
+                AccessMode::ReadOnly => (DirPerms::READ, FilePerms::READ),
+                AccessMode::ReadWrite => (DirPerms::all(), FilePerms::all()),
+            };
+
+            let guest = dir.guest.clone().unwrap_or_else(|| dir.host.to_string_lossy().into_owned());
+            builder
+                .preopened_dir(&dir.host, guest, dir_perms, file_perms)
+                .with_context(|| format!("Could not open directory {}", dir.host.display()))?;
+        }
+
+        if let Some(path) = &self.stdout {
+            builder.stdout(output_file(path)?);
+        }
+        if let Some(path) = &self.stderr {
+            builder.stderr(output_file(path)?);
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// Opens a file for appending, so guests that share an output file do not overwrite each other.
+fn output_file(path: &Path) -> Result<AsyncStdoutStream> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open output file {}", path.display()))?;
+
+    let stream = AsyncWriteStream::new(1 << 16, tokio::fs::File::from_std(file));
+    Ok(AsyncStdoutStream::new(stream))
+}
@@ -0,0 +1,28 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// How often `--watch` checks whether a component changed.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The modification time of the file, or `None` while it does not exist.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Waits until the file was modified after `since` and then stayed the same for a poll interval,
+/// so a file that is still being written is not picked up. Returns the new modification time.
+/// Polling also notices files that a build replaces instead of writing to.
+pub async fn changed(path: &Path, since: Option<SystemTime>) -> Option<SystemTime> {
+    let mut last = since;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let current = modified(path);
+        if current.is_some() && current != since && current == last {
+            return current;
+        }
+
+        last = current;
+    }
+}